@@ -0,0 +1,316 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tauri::Manager;
+use tokio::sync::Mutex;
+
+use crate::{
+    chess::{EngineOptions, EngineProcess},
+    engine::{EngineOption, EngineSpawnConfig, GoMode},
+    error::Error,
+    progress::update_progress,
+    AppState,
+};
+
+const BENCHMARKS_FILE: &str = "benchmarks.json";
+/// Reports kept per engine, older ones are dropped.
+const MAX_REPORTS: usize = 20;
+
+/// Positions searched when the caller doesn't provide any, a mix of opening,
+/// middlegame and endgame positions.
+const DEFAULT_POSITIONS: [&str; 6] = [
+    "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+    "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 10",
+    "r1bq1rk1/pp2bppp/2n1pn2/2pp4/3P4/2PBPN2/PP1N1PPP/R2QK2R w KQ - 0 8",
+    "4rrk1/pp1n3p/3q2pQ/2p1pb2/2PP4/2P3N1/P2B2PP/4RRK1 b - - 7 19",
+    "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+    "6k1/6p1/6Pp/ppp5/3pn2P/1P3K2/1PP2P2/3N4 b - - 0 1",
+];
+
+#[derive(Deserialize, Debug, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct BenchmarkOptions {
+    /// FENs to search, defaults to a built-in set.
+    #[serde(default)]
+    pub positions: Vec<String>,
+    pub depth: u32,
+    /// Values tried for the `Threads` option, in increasing order.
+    pub threads: Vec<u32>,
+    /// Values tried for the `Hash` option (MB), in increasing order.
+    pub hash: Vec<u32>,
+    #[serde(flatten)]
+    pub spawn: EngineSpawnConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct BenchmarkPosition {
+    pub fen: String,
+    pub depth: u32,
+    pub nodes: u64,
+    /// Time to reach the requested depth.
+    pub time_ms: u64,
+    pub nps: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct BenchmarkRun {
+    pub threads: u32,
+    pub hash: u32,
+    pub positions: Vec<BenchmarkPosition>,
+    pub nodes: u64,
+    pub time_ms: u64,
+    pub nps: u64,
+    /// Time-to-depth speedup relative to the first run.
+    pub speedup: f64,
+}
+
+impl BenchmarkRun {
+    fn new(threads: u32, hash: u32, positions: Vec<BenchmarkPosition>) -> Self {
+        let nodes = positions.iter().map(|p| p.nodes).sum();
+        let time_ms = positions.iter().map(|p| p.time_ms).sum();
+        Self {
+            threads,
+            hash,
+            positions,
+            nodes,
+            time_ms,
+            nps: nodes_per_second(nodes, time_ms),
+            speedup: 1.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct BenchmarkReport {
+    pub engine: String,
+    /// RFC 3339 timestamp of the run.
+    pub date: String,
+    pub depth: u32,
+    pub runs: Vec<BenchmarkRun>,
+    /// Index of the run that reached the depth the fastest.
+    pub best: Option<u32>,
+}
+
+fn nodes_per_second(nodes: u64, time_ms: u64) -> u64 {
+    nodes * 1000 / time_ms.max(1)
+}
+
+impl BenchmarkReport {
+    fn new(engine: String, depth: u32, mut runs: Vec<BenchmarkRun>) -> Self {
+        if let Some(baseline) = runs.first().map(|r| r.time_ms.max(1)) {
+            for run in &mut runs {
+                run.speedup = baseline as f64 / run.time_ms.max(1) as f64;
+            }
+        }
+        let best = runs
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, r)| r.time_ms)
+            .map(|(i, _)| i as u32);
+        Self {
+            engine,
+            date: chrono::Utc::now().to_rfc3339(),
+            depth,
+            runs,
+            best,
+        }
+    }
+}
+
+fn benchmarks_path(app: &tauri::AppHandle) -> Result<PathBuf, Error> {
+    Ok(app
+        .path()
+        .resolve(BENCHMARKS_FILE, tauri::path::BaseDirectory::AppData)?)
+}
+
+/// Past reports, keyed by engine path.
+fn load_benchmarks(path: &Path) -> Result<HashMap<String, Vec<BenchmarkReport>>, Error> {
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+}
+
+fn record_benchmark(app: &tauri::AppHandle, report: &BenchmarkReport) -> Result<(), Error> {
+    let path = benchmarks_path(app)?;
+    let mut benchmarks = load_benchmarks(&path)?;
+    let reports = benchmarks.entry(report.engine.clone()).or_default();
+    reports.push(report.clone());
+    let excess = reports.len().saturating_sub(MAX_REPORTS);
+    reports.drain(..excess);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_string(&benchmarks)?)?;
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+pub fn get_benchmarks(
+    engine: String,
+    app: tauri::AppHandle,
+) -> Result<Vec<BenchmarkReport>, Error> {
+    let mut benchmarks = load_benchmarks(&benchmarks_path(&app)?)?;
+    Ok(benchmarks.remove(&engine).unwrap_or_default())
+}
+
+/// Searches every position to a fixed depth with each Threads/Hash
+/// combination and records the report for the engine.
+#[tauri::command]
+#[specta::specta]
+pub async fn run_benchmark(
+    id: String,
+    engine: String,
+    options: BenchmarkOptions,
+    uci_options: Vec<EngineOption>,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<BenchmarkReport, Error> {
+    let positions: Vec<String> = if options.positions.is_empty() {
+        DEFAULT_POSITIONS.iter().map(|p| p.to_string()).collect()
+    } else {
+        options.positions.clone()
+    };
+    let configs: Vec<(u32, u32)> = options
+        .threads
+        .iter()
+        .flat_map(|&threads| options.hash.iter().map(move |&hash| (threads, hash)))
+        .collect();
+    if configs.is_empty() {
+        return Err(Error::InvalidBenchmark(
+            "At least one Threads and one Hash value are needed".to_string(),
+        ));
+    }
+    // the benchmarked options take precedence over the engine's settings
+    let base_options: Vec<EngineOption> = uci_options
+        .into_iter()
+        .filter(|o| !o.name.eq_ignore_ascii_case("Threads") && !o.name.eq_ignore_ascii_case("Hash"))
+        .collect();
+
+    let cancel_flag = Arc::new(AtomicBool::new(false));
+    state
+        .analysis_cancel_flags
+        .insert(id.clone(), cancel_flag.clone());
+
+    // the flag is removed however the benchmark ends
+    let runs = async {
+        let total = configs.len() * positions.len();
+        let mut runs = Vec::new();
+        for (c, &(threads, hash)) in configs.iter().enumerate() {
+            let mut extra_options = base_options.clone();
+            extra_options.push(EngineOption {
+                name: "Threads".to_string(),
+                value: threads.to_string(),
+            });
+            extra_options.push(EngineOption {
+                name: "Hash".to_string(),
+                value: hash.to_string(),
+            });
+            options.spawn.limits.validate(&extra_options)?;
+
+            // a fresh engine for every configuration, so the hash is allocated once
+            let (proc, reader) = EngineProcess::new(PathBuf::from(&engine), &options.spawn).await?;
+            let process = Mutex::new(proc);
+            process.lock().await.attach_reader(reader);
+
+            let mut results = Vec::new();
+            for (p, fen) in positions.iter().enumerate() {
+                if cancel_flag.load(Ordering::SeqCst) {
+                    process.lock().await.kill().await?;
+                    return Err(Error::AnalysisCancelled);
+                }
+                update_progress(
+                    &state.progress_state,
+                    &app,
+                    id.clone(),
+                    ((c * positions.len() + p) as f32 / total as f32) * 100.0,
+                    false,
+                )?;
+
+                let start = {
+                    let mut proc = process.lock().await;
+                    proc.new_game().await?;
+                    proc.set_options(EngineOptions {
+                        fen: fen.clone(),
+                        moves: Vec::new(),
+                        extra_options: extra_options.clone(),
+                        spawn: options.spawn.clone(),
+                    })
+                    .await?;
+                    proc.go(&GoMode::Depth(options.depth)).await?;
+                    Instant::now()
+                };
+                let best = EngineProcess::search_until_bestmove(&process).await?;
+                let time_ms = start.elapsed().as_millis() as u64;
+                let (depth, nodes) = best.first().map_or((0, 0), |b| (b.depth, b.nodes));
+                results.push(BenchmarkPosition {
+                    fen: fen.clone(),
+                    depth,
+                    nodes,
+                    time_ms,
+                    nps: nodes_per_second(nodes, time_ms),
+                });
+            }
+            process.lock().await.kill().await?;
+            runs.push(BenchmarkRun::new(threads, hash, results));
+        }
+        Ok::<_, Error>(runs)
+    }
+    .await;
+    state.analysis_cancel_flags.remove(&id);
+
+    let report = BenchmarkReport::new(engine, options.depth, runs?);
+    record_benchmark(&app, &report)?;
+
+    update_progress(&state.progress_state, &app, id.clone(), 100.0, true)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(threads: u32, time_ms: u64, nodes: u64) -> BenchmarkRun {
+        BenchmarkRun::new(
+            threads,
+            16,
+            vec![BenchmarkPosition {
+                fen: DEFAULT_POSITIONS[0].to_string(),
+                depth: 20,
+                nodes,
+                time_ms,
+                nps: nodes_per_second(nodes, time_ms),
+            }],
+        )
+    }
+
+    #[test]
+    fn report_computes_speedup_and_best_run() {
+        let report = BenchmarkReport::new(
+            "stockfish".to_string(),
+            20,
+            vec![
+                run(1, 4000, 4_000_000),
+                run(2, 2500, 4_500_000),
+                run(4, 1000, 5_000_000),
+            ],
+        );
+        assert_eq!(report.best, Some(2));
+        assert_eq!(report.runs[0].nps, 1_000_000);
+        assert!((report.runs[1].speedup - 1.6).abs() < 1e-9);
+        assert!((report.runs[2].speedup - 4.0).abs() < 1e-9);
+    }
+}
@@ -1,5 +1,6 @@
 use std::{
     fmt::Display,
+    ops::Range,
     path::PathBuf,
     sync::{
         atomic::{AtomicBool, Ordering},
@@ -14,12 +15,12 @@ use log::{info, warn};
 use nonzero_ext::*;
 use serde::{Deserialize, Serialize};
 use shakmaty::{
-    fen::Fen, san::SanPlus, uci::UciMove, ByColor, CastlingMode, Chess, Color, EnPassantMode,
-    Position, Role,
+    fen::Fen, san::SanPlus, uci::UciMove, variant::VariantPosition, ByColor, CastlingMode, Chess,
+    Color, EnPassantMode, Move, Outcome, Position, Role,
 };
 use specta::Type;
 use tauri_specta::Event;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify};
 use vampirc_uci::{
     parse_one,
     uci::{Score, ScoreValue},
@@ -29,10 +30,15 @@ use vampirc_uci::{
 use crate::{
     db::{is_position_in_db, GameQuery, PositionQueryJs},
     engine::{
-        parse_fen_and_apply_moves, BaseEngine, EngineLog, EngineOption, EngineReader, GoMode,
+        expected_score, null_move_position, parse_fen_and_apply_moves,
+        parse_variant_fen_and_apply_moves, validate_options, wdl_from_score, wdl_material,
+        BaseEngine, EngineCommand, EngineCrashed, EngineLog, EngineLogFilter, EngineOption,
+        EngineOptionError, EngineReader, EngineSpawnConfig, GoMode,
     },
     error::Error,
+    motifs::{detect_motifs, parse_line, Motif},
     progress::update_progress,
+    variant::GameVariant,
     AppState,
 };
 
@@ -47,12 +53,19 @@ pub struct EngineProcess {
     running: bool,
     real_multipv: u16,
     start: Instant,
+    /// Notified when a search hands the reader back to `base`.
+    reader_returned: Arc<Notify>,
 }
 
 impl EngineProcess {
-    async fn new(path: PathBuf) -> Result<(Self, EngineReader), Error> {
-        let mut base = BaseEngine::spawn(path).await?;
-        base.init_uci().await?;
+    pub async fn new(
+        path: PathBuf,
+        spawn: &EngineSpawnConfig,
+    ) -> Result<(Self, EngineReader), Error> {
+        let mut base = BaseEngine::start(path, spawn).await?;
+        if base.supports_option("UCI_ShowWDL") {
+            base.set_option("UCI_ShowWDL", "true").await?;
+        }
         let reader = base.take_reader().ok_or(Error::EngineDisconnected)?;
 
         Ok((
@@ -67,6 +80,7 @@ impl EngineProcess {
                 go_mode: GoMode::Infinite,
                 running: false,
                 start: Instant::now(),
+                reader_returned: Arc::new(Notify::new()),
             },
             reader,
         ))
@@ -79,19 +93,20 @@ impl EngineProcess {
         self.base.set_option(name, value).await
     }
 
-    async fn set_options(&mut self, options: EngineOptions) -> Result<(), Error> {
-        let fen_changed = options.fen != self.options.fen;
+    pub async fn set_options(&mut self, options: EngineOptions) -> Result<(), Error> {
+        let variant_changed = options.spawn.variant != self.options.spawn.variant;
+        let fen_changed = options.fen != self.options.fen || variant_changed;
         let fen: Fen = options.fen.parse()?;
         let setup = fen.as_setup();
         let castling_mode = CastlingMode::detect(setup);
-        let pos = parse_fen_and_apply_moves(&options.fen, &options.moves)?;
+        let pos =
+            parse_variant_fen_and_apply_moves(&options.fen, &options.moves, options.spawn.variant)?;
 
+        if variant_changed {
+            self.base.set_variant(options.spawn.variant).await?;
+        }
         if fen_changed {
-            if castling_mode.is_chess960() {
-                self.set_option("UCI_Chess960", "true").await?;
-            } else {
-                self.set_option("UCI_Chess960", "false").await?;
-            }
+            self.base.set_chess960(castling_mode.is_chess960()).await?;
         }
 
         let multipv = options
@@ -102,11 +117,25 @@ impl EngineProcess {
             .unwrap_or(1);
 
         self.real_multipv = multipv.min(pos.legal_moves().len() as u16);
+        if !self.base.supports_multipv() {
+            self.real_multipv = self.real_multipv.min(1);
+        }
 
-        for option in &options.extra_options {
-            if !self.options.extra_options.contains(option) && option.name != "UCI_Chess960" {
-                self.set_option(&option.name, &option.value).await?;
-            }
+        let changed: Vec<_> = options
+            .extra_options
+            .iter()
+            .filter(|o| {
+                !self.options.extra_options.contains(o)
+                    && o.name != "UCI_Chess960"
+                    && o.name != "UCI_Variant"
+            })
+            // single-line engines analyze with real_multipv = 1 instead
+            .filter(|o| o.name != "MultiPV" || self.base.supports_multipv())
+            .cloned()
+            .collect();
+        self.base.check_options(&changed)?;
+        for option in &changed {
+            self.set_option(&option.name, &option.value).await?;
         }
 
         if fen_changed || options.moves != self.options.moves {
@@ -126,7 +155,7 @@ impl EngineProcess {
         Ok(())
     }
 
-    async fn go(&mut self, mode: &GoMode) -> Result<(), Error> {
+    pub async fn go(&mut self, mode: &GoMode) -> Result<(), Error> {
         self.go_mode = mode.clone();
         self.base.go(mode).await?;
         self.running = true;
@@ -140,7 +169,17 @@ impl EngineProcess {
         Ok(())
     }
 
-    async fn kill(&mut self) -> Result<(), Error> {
+    /// Hands back the reader returned by `new`, for callers that search with
+    /// [`EngineProcess::search_until_bestmove`].
+    pub fn attach_reader(&mut self, reader: EngineReader) {
+        self.base.reader = Some(reader);
+    }
+
+    pub async fn new_game(&mut self) -> Result<(), Error> {
+        self.base.new_game().await
+    }
+
+    pub async fn kill(&mut self) -> Result<(), Error> {
         self.base.quit().await?;
         self.running = false;
         Ok(())
@@ -149,21 +188,103 @@ impl EngineProcess {
     pub fn kill_sync(&mut self) {
         self.base.kill_sync();
     }
+
+    /// Processes engine output until `bestmove`, returning the deepest complete
+    /// set of lines. The reader is borrowed from the base engine, so the process
+    /// lock is only held while handling each line and the search can still be
+    /// stopped through [`stop_engine`].
+    pub async fn search_until_bestmove(
+        process: &Mutex<EngineProcess>,
+    ) -> Result<Vec<BestMoves>, Error> {
+        let mut reader = {
+            let mut proc = process.lock().await;
+            proc.base.take_reader().ok_or(Error::EngineDisconnected)?
+        };
+        let result = loop {
+            let line = match reader.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => break Err(Error::EngineDisconnected),
+                Err(e) => break Err(e.into()),
+            };
+            let mut proc = process.lock().await;
+            proc.base.log_engine(&line);
+            match parse_one(&line) {
+                UciMessage::Info(attrs) => {
+                    let fen: Fen = match proc.options.fen.parse() {
+                        Ok(fen) => fen,
+                        Err(e) => break Err(Error::from(e)),
+                    };
+                    let moves = proc.options.moves.clone();
+                    if let Ok(best_moves) =
+                        parse_uci_attrs(attrs, &fen, &moves, proc.options.spawn.variant)
+                    {
+                        if best_moves.score.lower_bound == Some(true)
+                            || best_moves.score.upper_bound == Some(true)
+                        {
+                            continue;
+                        }
+                        let multipv = best_moves.multipv;
+                        let cur_depth = best_moves.depth;
+                        if multipv as usize == proc.best_moves.len() + 1 {
+                            proc.best_moves.push(best_moves);
+                            if multipv == proc.real_multipv {
+                                if proc.best_moves.iter().all(|x| x.depth == cur_depth)
+                                    && cur_depth >= proc.last_depth
+                                {
+                                    proc.last_depth = cur_depth;
+                                    proc.last_best_moves = proc.best_moves.clone();
+                                }
+                                proc.best_moves.clear();
+                            }
+                        }
+                    }
+                }
+                UciMessage::BestMove { .. } => {
+                    proc.running = false;
+                    break Ok(proc.last_best_moves.clone());
+                }
+                _ => {}
+            }
+        };
+        let mut proc = process.lock().await;
+        proc.base.reader = Some(reader);
+        proc.reader_returned.notify_one();
+        result
+    }
+
+    /// Waits until no search holds the reader, e.g. after stopping one.
+    pub async fn wait_for_reader(process: &Mutex<EngineProcess>) {
+        loop {
+            let reader_returned = {
+                let proc = process.lock().await;
+                if proc.base.reader.is_some() {
+                    return;
+                }
+                proc.reader_returned.clone()
+            };
+            // a permit is stored if the reader came back in between
+            reader_returned.notified().await;
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Debug, Derivative, Type)]
 #[derivative(Default)]
 pub struct BestMoves {
-    nodes: u32,
-    depth: u32,
-    score: Score,
+    pub nodes: u64,
+    pub depth: u32,
+    pub score: Score,
     #[serde(rename = "uciMoves")]
-    uci_moves: Vec<String>,
+    pub uci_moves: Vec<String>,
     #[serde(rename = "sanMoves")]
-    san_moves: Vec<String>,
+    pub san_moves: Vec<String>,
     #[derivative(Default(value = "1"))]
-    multipv: u16,
-    nps: u32,
+    pub multipv: u16,
+    pub nps: u32,
+    /// Expected score from White's point of view, in `[0, 1]`.
+    #[serde(rename = "expectedScore")]
+    #[derivative(Default(value = "0.5"))]
+    pub expected_score: f64,
 }
 
 #[derive(Serialize, Debug, Clone, Type, Event)]
@@ -194,11 +315,14 @@ fn parse_uci_attrs(
     attrs: Vec<UciInfoAttribute>,
     fen: &Fen,
     moves: &[String],
+    variant: GameVariant,
 ) -> Result<BestMoves, Error> {
     let mut best_moves = BestMoves::default();
 
-    let mut pos = parse_fen_and_apply_moves(&fen.to_string(), moves)?;
+    let mut pos = parse_variant_fen_and_apply_moves(&fen.to_string(), moves, variant)?;
     let turn = pos.turn();
+    let ply = (pos.fullmoves().get() - 1) * 2 + u32::from(turn == Color::Black);
+    let material = wdl_material(pos.board());
 
     for a in attrs {
         match a {
@@ -215,7 +339,7 @@ fn parse_uci_attrs(
                 best_moves.nps = nps as u32;
             }
             UciInfoAttribute::Nodes(nodes) => {
-                best_moves.nodes = nodes as u32;
+                best_moves.nodes = nodes;
             }
             UciInfoAttribute::Depth(depth) => {
                 best_moves.depth = depth;
@@ -234,8 +358,17 @@ fn parse_uci_attrs(
         return Err(Error::NoMovesFound);
     }
 
+    if best_moves.score.wdl.is_none() {
+        let (w, d, l) = wdl_from_score(&best_moves.score.value, ply, Some(material));
+        best_moves.score.wdl = Some((w as _, d as _, l as _));
+    }
+    if let Some((w, d, l)) = best_moves.score.wdl {
+        best_moves.expected_score = expected_score(f64::from(w), f64::from(d), f64::from(l));
+    }
+
     if turn == Color::Black {
         best_moves.score = invert_score(best_moves.score);
+        best_moves.expected_score = 1.0 - best_moves.expected_score;
     }
 
     Ok(best_moves)
@@ -248,6 +381,8 @@ pub struct EngineOptions {
     pub fen: String,
     pub moves: Vec<String>,
     pub extra_options: Vec<EngineOption>,
+    #[serde(flatten)]
+    pub spawn: EngineSpawnConfig,
 }
 
 #[tauri::command]
@@ -305,17 +440,36 @@ pub async fn stop_engine(
 pub async fn get_engine_logs(
     engine: String,
     tab: String,
+    filter: Option<EngineLogFilter>,
     state: tauri::State<'_, AppState>,
 ) -> Result<Vec<EngineLog>, Error> {
     let key = (tab, engine);
     if let Some(process) = state.engine_processes.get(&key) {
         let process = process.lock().await;
-        Ok(process.base.get_logs())
+        process.base.get_logs(&filter.unwrap_or_default())
     } else {
         Ok(Vec::new())
     }
 }
 
+#[tauri::command]
+#[specta::specta]
+pub async fn export_engine_logs(
+    engine: String,
+    tab: String,
+    filter: Option<EngineLogFilter>,
+    file: PathBuf,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let key = (tab, engine.clone());
+    let process = state
+        .engine_processes
+        .get(&key)
+        .ok_or(Error::EngineNotFound(engine))?;
+    let process = process.lock().await;
+    process.base.export_logs(&file, &filter.unwrap_or_default())
+}
+
 #[tauri::command]
 #[specta::specta]
 pub async fn get_best_moves(
@@ -354,7 +508,8 @@ pub async fn get_best_moves(
         return Ok(None);
     }
 
-    let (mut process, mut reader) = EngineProcess::new(path).await?;
+    options.spawn.limits.validate(&options.extra_options)?;
+    let (mut process, mut reader) = EngineProcess::new(path, &options.spawn).await?;
     process.set_options(options.clone()).await?;
     process.go(&go_mode).await?;
 
@@ -363,93 +518,270 @@ pub async fn get_best_moves(
     state.engine_processes.insert(key.clone(), process.clone());
 
     let lim = RateLimiter::direct(Quota::per_second(nonzero!(5u32)));
-
-    while let Some(line) = reader.next_line().await? {
-        let mut proc = process.lock().await;
-        match parse_one(&line) {
-            UciMessage::Info(attrs) => {
-                match parse_uci_attrs(attrs, &proc.options.fen.parse()?, &proc.options.moves) {
-                    Ok(best_moves) => {
-                        if best_moves.score.lower_bound == Some(true)
-                            || best_moves.score.upper_bound == Some(true)
-                        {
-                            continue;
-                        }
-                        let multipv = best_moves.multipv;
-                        let cur_depth = best_moves.depth;
-                        let cur_nodes = best_moves.nodes;
-                        if multipv as usize == proc.best_moves.len() + 1 {
-                            proc.best_moves.push(best_moves);
-                            if multipv == proc.real_multipv {
-                                if proc.best_moves.iter().all(|x| x.depth == cur_depth)
-                                    && cur_depth >= proc.last_depth
-                                    && lim.check().is_ok()
-                                {
-                                    let progress = match proc.go_mode {
-                                        GoMode::Depth(depth) => {
-                                            (cur_depth as f64 / depth as f64) * 100.0
-                                        }
-                                        GoMode::Time(time) => {
-                                            (proc.start.elapsed().as_millis() as f64 / time as f64)
-                                                * 100.0
-                                        }
-                                        GoMode::Nodes(nodes) => {
-                                            (cur_nodes as f64 / nodes as f64) * 100.0
+    let mut crashes = 0;
+
+    loop {
+        loop {
+            let line = match reader.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(e) => {
+                    if process.lock().await.base.is_gone().await {
+                        break;
+                    }
+                    warn!("Failed to read engine output: {}", e);
+                    continue;
+                }
+            };
+            let mut proc = process.lock().await;
+            match parse_one(&line) {
+                UciMessage::Info(attrs) => {
+                    match parse_uci_attrs(
+                        attrs,
+                        &proc.options.fen.parse()?,
+                        &proc.options.moves,
+                        proc.options.spawn.variant,
+                    ) {
+                        Ok(best_moves) => {
+                            if best_moves.score.lower_bound == Some(true)
+                                || best_moves.score.upper_bound == Some(true)
+                            {
+                                continue;
+                            }
+                            let multipv = best_moves.multipv;
+                            let cur_depth = best_moves.depth;
+                            let cur_nodes = best_moves.nodes;
+                            if multipv as usize == proc.best_moves.len() + 1 {
+                                proc.best_moves.push(best_moves);
+                                if multipv == proc.real_multipv {
+                                    if proc.best_moves.iter().all(|x| x.depth == cur_depth)
+                                        && cur_depth >= proc.last_depth
+                                        && lim.check().is_ok()
+                                    {
+                                        let progress = match proc.go_mode {
+                                            GoMode::Depth(depth) => {
+                                                (cur_depth as f64 / depth as f64) * 100.0
+                                            }
+                                            GoMode::Time(time) => {
+                                                (proc.start.elapsed().as_millis() as f64
+                                                    / time as f64)
+                                                    * 100.0
+                                            }
+                                            GoMode::Nodes(nodes) => {
+                                                (cur_nodes as f64 / nodes as f64) * 100.0
+                                            }
+                                            GoMode::PlayersTime(_) => 99.99,
+                                            GoMode::Infinite => 99.99,
+                                        };
+                                        BestMovesPayload {
+                                            best_lines: proc.best_moves.clone(),
+                                            engine: id.clone(),
+                                            tab: tab.clone(),
+                                            fen: proc.options.fen.clone(),
+                                            moves: proc.options.moves.clone(),
+                                            progress,
                                         }
-                                        GoMode::PlayersTime(_) => 99.99,
-                                        GoMode::Infinite => 99.99,
-                                    };
-                                    BestMovesPayload {
-                                        best_lines: proc.best_moves.clone(),
-                                        engine: id.clone(),
-                                        tab: tab.clone(),
-                                        fen: proc.options.fen.clone(),
-                                        moves: proc.options.moves.clone(),
-                                        progress,
+                                        .emit(&app)?;
+                                        proc.last_depth = cur_depth;
+                                        proc.last_best_moves = proc.best_moves.clone();
+                                        proc.last_progress = progress as f32;
                                     }
-                                    .emit(&app)?;
-                                    proc.last_depth = cur_depth;
-                                    proc.last_best_moves = proc.best_moves.clone();
-                                    proc.last_progress = progress as f32;
+                                    proc.best_moves.clear();
                                 }
-                                proc.best_moves.clear();
                             }
                         }
+                        Err(e) => match e {
+                            Error::NoMovesFound => {}
+                            _ => {
+                                warn!("Failed to parse info line: {}, error: {:?}", line, e);
+                            }
+                        },
                     }
-                    Err(e) => match e {
-                        Error::NoMovesFound => {}
-                        _ => {
-                            warn!("Failed to parse info line: {}, error: {:?}", line, e);
-                        }
-                    },
                 }
-            }
-            UciMessage::BestMove { .. } => {
-                BestMovesPayload {
-                    best_lines: proc.last_best_moves.clone(),
-                    engine: id.clone(),
-                    tab: tab.clone(),
-                    fen: proc.options.fen.clone(),
-                    moves: proc.options.moves.clone(),
-                    progress: 100.0,
+                UciMessage::BestMove { .. } => {
+                    BestMovesPayload {
+                        best_lines: proc.last_best_moves.clone(),
+                        engine: id.clone(),
+                        tab: tab.clone(),
+                        fen: proc.options.fen.clone(),
+                        moves: proc.options.moves.clone(),
+                        progress: 100.0,
+                    }
+                    .emit(&app)?;
+                    proc.last_progress = 100.0;
+                    proc.running = false;
+                    // a healthy search earns back the restarts
+                    crashes = 0;
                 }
-                .emit(&app)?;
-                proc.last_progress = 100.0;
+                _ => {}
+            }
+            proc.base.log_engine(&line);
+        }
+
+        let mut proc = process.lock().await;
+        let Some(crash) = proc.base.crash_report().await else {
+            break;
+        };
+        crashes += 1;
+        let event = EngineCrashed::new(
+            id.clone(),
+            Some(tab.clone()),
+            engine.clone(),
+            crash,
+            crashes,
+        );
+        let restarting = event.restarting;
+        event.emit(&app)?;
+        if !restarting {
+            warn!(
+                "Engine crashed too many times: tab: {}, engine: {}",
+                tab, engine
+            );
+            break;
+        }
+        warn!(
+            "Engine crashed, restarting: tab: {}, engine: {}",
+            tab, engine
+        );
+        let restarted = async {
+            let (mut new_process, new_reader) =
+                EngineProcess::new(path.clone(), &proc.options.spawn).await?;
+            new_process.set_options(proc.options.clone()).await?;
+            if proc.running {
+                new_process.go(&proc.go_mode).await?;
+            }
+            Ok::<_, Error>((new_process, new_reader))
+        }
+        .await;
+        match restarted {
+            Ok((new_process, new_reader)) => {
+                *proc = new_process;
+                reader = new_reader;
+            }
+            Err(e) => {
+                drop(proc);
+                state.engine_processes.remove(&key);
+                return Err(e);
             }
-            _ => {}
         }
-        proc.base.log_engine(&line);
     }
     info!("Engine process finished: tab: {}, engine: {}", tab, engine);
     state.engine_processes.remove(&key);
     Ok(None)
 }
 
+/// Default depth for threat searches when the requested mode has no natural end.
+const THREAT_DEPTH: u32 = 18;
+
+#[derive(Serialize, Debug, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct ThreatAnalysis {
+    /// Position after the null move, with the opponent to move.
+    pub fen: String,
+    /// The opponent's best line. Scores are from White's point of view.
+    pub threat: BestMoves,
+}
+
+/// Analyzes what the opponent would play if the side to move passed.
+///
+/// Returns `None` when passing is illegal (the side to move is in check), the
+/// opponent has no legal moves or the game is a variant. A separate engine
+/// instance keyed by `"{id}-threat"` is kept alive per tab so it can be reused
+/// and killed with the other engines of the tab.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_threat(
+    id: String,
+    engine: String,
+    tab: String,
+    go_mode: GoMode,
+    options: EngineOptions,
+    state: tauri::State<'_, AppState>,
+) -> Result<Option<ThreatAnalysis>, Error> {
+    if !options.spawn.variant.is_standard() {
+        return Ok(None);
+    }
+    let pos = parse_fen_and_apply_moves(&options.fen, &options.moves)?;
+    let Some(null_pos) = null_move_position(&pos) else {
+        return Ok(None);
+    };
+    if null_pos.legal_moves().is_empty() {
+        return Ok(None);
+    }
+    let fen = Fen::from_position(null_pos, EnPassantMode::Legal).to_string();
+
+    let mut extra_options: Vec<_> = options
+        .extra_options
+        .into_iter()
+        .filter(|x| x.name != "MultiPV")
+        .collect();
+    extra_options.push(EngineOption {
+        name: "MultiPV".to_string(),
+        value: "1".to_string(),
+    });
+    let go_mode = match go_mode {
+        GoMode::Infinite | GoMode::PlayersTime(_) => GoMode::Depth(THREAT_DEPTH),
+        mode => mode,
+    };
+
+    let key = (tab, format!("{}-threat", id));
+    let process = match state.engine_processes.get(&key) {
+        Some(process) => process.clone(),
+        None => {
+            options.spawn.limits.validate(&extra_options)?;
+            let (process, reader) =
+                EngineProcess::new(PathBuf::from(&engine), &options.spawn).await?;
+            let process = Arc::new(Mutex::new(process));
+            process.lock().await.base.reader = Some(reader);
+            state.engine_processes.insert(key.clone(), process.clone());
+            process
+        }
+    };
+
+    {
+        let mut proc = process.lock().await;
+        if proc.running {
+            proc.stop().await?;
+        }
+    }
+    EngineProcess::wait_for_reader(&process).await;
+    {
+        let mut proc = process.lock().await;
+        proc.set_options(EngineOptions {
+            fen: fen.clone(),
+            moves: Vec::new(),
+            extra_options,
+            spawn: EngineSpawnConfig {
+                variant: GameVariant::Standard,
+                ..options.spawn
+            },
+        })
+        .await?;
+        proc.go(&go_mode).await?;
+    }
+
+    let lines = match EngineProcess::search_until_bestmove(&process).await {
+        Ok(lines) => lines,
+        Err(e) => {
+            state.engine_processes.remove(&key);
+            return Err(e);
+        }
+    };
+
+    Ok(lines
+        .into_iter()
+        .next()
+        .map(|threat| ThreatAnalysis { fen, threat }))
+}
+
 #[derive(Serialize, Debug, Default, Type)]
 pub struct MoveAnalysis {
-    best: Vec<BestMoves>,
-    novelty: bool,
-    is_sacrifice: bool,
+    pub best: Vec<BestMoves>,
+    pub novelty: bool,
+    pub is_sacrifice: bool,
+    pub motifs: Vec<Motif>,
+    /// Expected score of the best line from White's point of view.
+    pub expected_score: f64,
 }
 
 #[derive(Deserialize, Debug, Default, Type)]
@@ -460,6 +792,8 @@ pub struct AnalysisOptions {
     pub annotate_novelties: bool,
     pub reference_db: Option<PathBuf>,
     pub reversed: bool,
+    #[serde(flatten)]
+    pub spawn: EngineSpawnConfig,
 }
 
 #[tauri::command]
@@ -482,22 +816,58 @@ pub async fn analyze_game(
     state: tauri::State<'_, AppState>,
     app: tauri::AppHandle,
 ) -> Result<Vec<MoveAnalysis>, Error> {
+    options.spawn.limits.validate(&uci_options)?;
+
     let cancel_flag = Arc::new(AtomicBool::new(false));
     state
         .analysis_cancel_flags
         .insert(id.clone(), cancel_flag.clone());
 
-    let path = PathBuf::from(&engine);
+    let analysis = analyze_game_positions(
+        &id,
+        &engine,
+        &go_mode,
+        &options,
+        &uci_options,
+        &cancel_flag,
+        &state,
+        &app,
+        0.0..100.0,
+    )
+    .await?;
+    update_progress(&state.progress_state, &app, id.clone(), 100.0, true)?;
+    state.analysis_cancel_flags.remove(&id);
+    Ok(analysis)
+}
+
+/// Analyzes every position of the game, reporting progress within `progress`.
+/// A final position ending the game is scored from its outcome, without any
+/// line. The caller owns the cancel flag registered under `id`.
+#[allow(clippy::too_many_arguments)]
+pub async fn analyze_game_positions(
+    id: &str,
+    engine: &str,
+    go_mode: &GoMode,
+    options: &AnalysisOptions,
+    uci_options: &[EngineOption],
+    cancel_flag: &AtomicBool,
+    state: &tauri::State<'_, AppState>,
+    app: &tauri::AppHandle,
+    progress: Range<f32>,
+) -> Result<Vec<MoveAnalysis>, Error> {
+    let path = PathBuf::from(engine);
     let mut analysis: Vec<MoveAnalysis> = Vec::new();
 
-    let (mut proc, mut reader) = EngineProcess::new(path).await?;
+    let (mut proc, mut reader) = EngineProcess::new(path, &options.spawn).await?;
 
     let fen = Fen::from_ascii(options.fen.as_bytes())?;
     let setup = fen.as_setup().clone();
     let castling_mode = CastlingMode::detect(&setup);
 
-    let mut chess: Chess = setup.position(castling_mode)?;
-    let mut fens: Vec<(Fen, Vec<String>, bool)> = vec![(fen, vec![], false)];
+    let mut chess = options.spawn.variant.position(setup, castling_mode)?;
+    // each position with the move that led to it
+    let mut fens: Vec<(Fen, Vec<String>, Option<(VariantPosition, Move)>)> =
+        vec![(fen, vec![], None)];
 
     options
         .moves
@@ -508,18 +878,21 @@ pub async fn analyze_game(
             let m = uci.to_move(&chess)?;
             let previous_pos = chess.clone();
             chess.play_unchecked(&m);
-            let current_pos = chess.clone();
             if !chess.is_game_over() {
-                let prev_eval = naive_eval(&previous_pos);
-                let cur_eval = -naive_eval(&current_pos);
                 fens.push((
-                    Fen::from_position(current_pos, EnPassantMode::Legal),
+                    Fen::from_position(chess.clone(), EnPassantMode::Legal),
                     options.moves.clone().into_iter().take(i + 1).collect(),
-                    prev_eval > cur_eval + 100,
+                    Some((previous_pos, m)),
                 ));
             }
             Ok(())
         })?;
+    // a game ending in mate or a draw has a known final score, without search
+    let final_outcome = if options.moves.is_empty() {
+        None
+    } else {
+        chess.outcome()
+    };
 
     if options.reversed {
         fens.reverse();
@@ -530,19 +903,19 @@ pub async fn analyze_game(
     for (i, (_, moves, _)) in fens.iter().enumerate() {
         if cancel_flag.load(Ordering::SeqCst) {
             proc.kill().await?;
-            state.analysis_cancel_flags.remove(&id);
+            state.analysis_cancel_flags.remove(id);
             return Err(Error::AnalysisCancelled);
         }
 
         update_progress(
             &state.progress_state,
-            &app,
-            id.clone(),
-            (i as f32 / fens.len() as f32) * 100.0,
+            app,
+            id.to_string(),
+            progress.start + (i as f32 / fens.len() as f32) * (progress.end - progress.start),
             false,
         )?;
 
-        let mut extra_options = uci_options.clone();
+        let mut extra_options = uci_options.to_vec();
         if !extra_options.iter().any(|x| x.name == "MultiPV") {
             extra_options.push(EngineOption {
                 name: "MultiPV".to_string(),
@@ -560,18 +933,22 @@ pub async fn analyze_game(
             fen: options.fen.clone(),
             moves: moves.clone(),
             extra_options,
+            spawn: options.spawn.clone(),
         })
         .await?;
 
-        proc.go(&go_mode).await?;
+        proc.go(go_mode).await?;
 
         let mut current_analysis = MoveAnalysis::default();
-        while let Ok(Some(line)) = reader.next_line().await {
+        while let Some(line) = reader.next_line().await? {
             match parse_one(&line) {
                 UciMessage::Info(attrs) => {
-                    if let Ok(best_moves) =
-                        parse_uci_attrs(attrs, &proc.options.fen.parse()?, moves)
-                    {
+                    if let Ok(best_moves) = parse_uci_attrs(
+                        attrs,
+                        &proc.options.fen.parse()?,
+                        moves,
+                        options.spawn.variant,
+                    ) {
                         let multipv = best_moves.multipv;
                         let cur_depth = best_moves.depth;
                         if multipv as usize == proc.best_moves.len() + 1 {
@@ -609,14 +986,41 @@ pub async fn analyze_game(
         let query = PositionQueryJs {
             fen: fen.to_string(),
             type_: "exact".to_string(),
+            pattern: None,
+            material: None,
+            pawn_structure: None,
+            variations: false,
+            flip: false,
+            min_positions: 0,
         };
 
-        analysis.is_sacrifice = fens[i].2;
+        // motifs rely on standard piece values and rules
+        if let Some((VariantPosition::Chess(previous_pos), m)) = &fens[i].2 {
+            let mut current_pos = previous_pos.clone();
+            current_pos.play_unchecked(m);
+            let pv = analysis
+                .best
+                .first()
+                .map(|b| parse_line(&current_pos, &b.uci_moves))
+                .unwrap_or_default();
+            analysis.motifs = detect_motifs(previous_pos, m, &pv);
+            analysis.is_sacrifice = analysis
+                .motifs
+                .iter()
+                .any(|m| matches!(m, Motif::Sacrifice | Motif::PseudoSacrifice));
+        }
+        analysis.expected_score = analysis
+            .best
+            .first()
+            .map(|b| b.expected_score)
+            .unwrap_or(0.5);
         if options.annotate_novelties && !novelty_found {
             if let Some(reference) = options.reference_db.clone() {
                 analysis.novelty = !is_position_in_db(
                     reference,
-                    GameQuery::new().position(query.clone()).clone(),
+                    GameQuery::new()
+                        .position(query.clone())
+                        .variant(options.spawn.variant),
                     state.clone(),
                 )
                 .await?;
@@ -628,12 +1032,24 @@ pub async fn analyze_game(
             }
         }
     }
-    update_progress(&state.progress_state, &app, id.clone(), 100.0, true)?;
-    state.analysis_cancel_flags.remove(&id);
+    if let Some(outcome) = final_outcome {
+        analysis.push(MoveAnalysis {
+            expected_score: match outcome {
+                Outcome::Decisive {
+                    winner: Color::White,
+                } => 1.0,
+                Outcome::Decisive {
+                    winner: Color::Black,
+                } => 0.0,
+                Outcome::Draw => 0.5,
+            },
+            ..Default::default()
+        });
+    }
     Ok(analysis)
 }
 
-fn count_material(position: &Chess) -> i32 {
+pub fn count_material(position: &Chess) -> i32 {
     if position.is_checkmate() {
         return -10000;
     }
@@ -651,7 +1067,7 @@ fn count_material(position: &Chess) -> i32 {
     }
 }
 
-fn piece_value(role: Role) -> i32 {
+pub fn piece_value(role: Role) -> i32 {
     match role {
         Role::Pawn => 90,
         Role::Knight => 300,
@@ -662,7 +1078,7 @@ fn piece_value(role: Role) -> i32 {
     }
 }
 
-fn qsearch(position: &Chess, mut alpha: i32, beta: i32) -> i32 {
+pub fn qsearch(position: &Chess, mut alpha: i32, beta: i32) -> i32 {
     let stand_pat = count_material(position);
 
     if stand_pat >= beta {
@@ -695,7 +1111,7 @@ fn qsearch(position: &Chess, mut alpha: i32, beta: i32) -> i32 {
     alpha
 }
 
-fn naive_eval(pos: &Chess) -> i32 {
+pub fn naive_eval(pos: &Chess) -> i32 {
     pos.legal_moves()
         .iter()
         .map(|mv| {
@@ -770,18 +1186,83 @@ mod tests {
         let position = pos("4kb1r/p2rqppp/5n2/1B2p1B1/4P3/1Q6/PPP2PPP/2KR4 b k - 1 14");
         assert_eq!(naive_eval(&position), 0);
     }
+
+    #[test]
+    fn null_move_flips_turn() {
+        let position = pos("rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq e6 0 2");
+        let null = null_move_position(&position).unwrap();
+        assert_eq!(null.turn(), Color::Black);
+        assert_eq!(null.fullmoves().get(), 2);
+        assert_eq!(null.board(), position.board());
+    }
+
+    #[test]
+    fn null_move_in_check() {
+        let position = pos("rnb1kbnr/pppp1ppp/8/4p3/6Pq/5P2/PPPPP2P/RNBQKBNR w KQkq - 1 3");
+        assert!(null_move_position(&position).is_none());
+    }
 }
 
-#[derive(Type, Default, Serialize, Debug)]
+#[derive(Type, Default, Serialize, Debug, Clone)]
 pub struct EngineConfig {
     pub name: String,
     pub options: Vec<UciOptionConfig>,
 }
 
+/// Returns the engine's config, querying the engine only when it isn't cached,
+/// the binary changed since or it was started differently. TCP engines have
+/// no binary to check, so they are queried every time.
+async fn cached_engine_config(
+    path: PathBuf,
+    command: Option<EngineCommand>,
+    state: &AppState,
+) -> Result<EngineConfig, Error> {
+    let command = command.unwrap_or_default();
+    let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+    if let Some(cached) = state.engine_configs.get(&path) {
+        if Some(cached.0) == modified && cached.1 == command {
+            return Ok(cached.2.clone());
+        }
+    }
+    let config = load_engine_config(path.clone(), command.clone()).await?;
+    if let Some(modified) = modified {
+        state
+            .engine_configs
+            .insert(path, (modified, command, config.clone()));
+    }
+    Ok(config)
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn get_engine_config(
+    path: PathBuf,
+    command: Option<EngineCommand>,
+    state: tauri::State<'_, AppState>,
+) -> Result<EngineConfig, Error> {
+    cached_engine_config(path, command, &state).await
+}
+
+/// Checks options against the ones the engine declares, returning every
+/// problem found.
 #[tauri::command]
 #[specta::specta]
-pub async fn get_engine_config(path: PathBuf) -> Result<EngineConfig, Error> {
-    let mut base = BaseEngine::spawn(path).await?;
+pub async fn validate_engine_options(
+    path: PathBuf,
+    command: Option<EngineCommand>,
+    options: Vec<EngineOption>,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<EngineOptionError>, Error> {
+    let config = cached_engine_config(path, command, &state).await?;
+    Ok(validate_options(&config.options, &options))
+}
+
+async fn load_engine_config(path: PathBuf, command: EngineCommand) -> Result<EngineConfig, Error> {
+    let config = EngineSpawnConfig {
+        command,
+        ..Default::default()
+    };
+    let mut base = BaseEngine::spawn_with(path, &config).await?;
 
     base.send("uci").await?;
 
@@ -0,0 +1,323 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+use shakmaty::{Color, Position};
+use specta::Type;
+use tokio::sync::Mutex;
+
+use crate::{
+    chess::{
+        analyze_game_positions, AnalysisOptions, BestMoves, EngineOptions, EngineProcess,
+        MoveAnalysis,
+    },
+    engine::{parse_variant_fen_and_apply_moves, EngineOption, GoMode},
+    error::Error,
+    progress::update_progress,
+    AppState,
+};
+
+/// Change in expected score that makes a move a turning point.
+const SWING_THRESHOLD: f64 = 0.15;
+/// Expected score the second best move has to lose for the best one to be
+/// the only move.
+const ONLY_MOVE_GAP: f64 = 0.2;
+/// Expected scores at which a side is still holding or already losing.
+const HOLDING: f64 = 0.35;
+const LOSING: f64 = 0.2;
+
+#[derive(Serialize, Debug, Clone, Copy, Type, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum CriticalKind {
+    /// The win probability swung by the move played.
+    Swing,
+    /// Only one move keeps the evaluation.
+    OnlyMove,
+    /// The move after which the side that played it never recovered.
+    DecisiveMistake,
+}
+
+#[derive(Serialize, Debug, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct CriticalMoment {
+    /// Number of moves played before the position.
+    pub ply: u32,
+    /// Moves leading to the position.
+    pub moves: Vec<String>,
+    pub played: String,
+    pub kinds: Vec<CriticalKind>,
+    /// Change of White's expected score caused by the move played.
+    pub swing: f64,
+    pub importance: f64,
+    /// Best lines in the position.
+    pub best: Vec<BestMoves>,
+    /// Best lines after the move played.
+    pub reply: Vec<BestMoves>,
+}
+
+#[derive(Deserialize, Debug, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct CriticalMomentsOptions {
+    /// Search used on every position of the game.
+    pub quick: GoMode,
+    /// Search used again on the critical positions, skipped if missing.
+    pub deep: Option<GoMode>,
+    pub limit: u32,
+}
+
+/// Expected score from `color`'s point of view.
+fn score_for(score: f64, color: Color) -> f64 {
+    match color {
+        Color::White => score,
+        Color::Black => 1.0 - score,
+    }
+}
+
+/// Finds the critical moments of an analyzed game, most important first.
+/// `analysis[i]` is the analysis of the position after `moves[..i]` and
+/// `turn` the side to move in the starting position.
+pub fn critical_moments(
+    analysis: &[MoveAnalysis],
+    moves: &[String],
+    turn: Color,
+) -> Vec<CriticalMoment> {
+    let mut moments = Vec::new();
+    for (ply, pair) in analysis.windows(2).enumerate().take(moves.len()) {
+        let (before, after) = (&pair[0], &pair[1]);
+        let mover = if ply % 2 == 0 { turn } else { !turn };
+        let swing = after.expected_score - before.expected_score;
+
+        let mut kinds = Vec::new();
+        let mut importance = swing.abs();
+        if swing.abs() >= SWING_THRESHOLD {
+            kinds.push(CriticalKind::Swing);
+        }
+        if let [first, second, ..] = before.best.as_slice() {
+            let gap =
+                score_for(first.expected_score, mover) - score_for(second.expected_score, mover);
+            if gap >= ONLY_MOVE_GAP {
+                kinds.push(CriticalKind::OnlyMove);
+                importance += gap;
+            }
+        }
+        if score_for(before.expected_score, mover) >= HOLDING
+            && score_for(after.expected_score, mover) < LOSING
+            && analysis[ply + 1..]
+                .iter()
+                .all(|a| score_for(a.expected_score, mover) < HOLDING)
+        {
+            kinds.push(CriticalKind::DecisiveMistake);
+            importance += 1.0;
+        }
+        if kinds.is_empty() {
+            continue;
+        }
+
+        moments.push(CriticalMoment {
+            ply: ply as u32,
+            moves: moves[..ply].to_vec(),
+            played: moves[ply].clone(),
+            kinds,
+            swing,
+            importance,
+            best: before.best.clone(),
+            reply: after.best.clone(),
+        });
+    }
+    moments.sort_by(|a, b| b.importance.total_cmp(&a.importance));
+    moments
+}
+
+fn with_multipv(uci_options: &[EngineOption], multipv: u16) -> Vec<EngineOption> {
+    let mut options: Vec<EngineOption> = uci_options
+        .iter()
+        .filter(|o| o.name != "MultiPV")
+        .cloned()
+        .collect();
+    options.push(EngineOption {
+        name: "MultiPV".to_string(),
+        value: multipv.to_string(),
+    });
+    options
+}
+
+/// Analyzes the game with the quick search, then runs the deep search on the
+/// positions before and after the critical moments found and ranks them again.
+/// Only moments evaluated on both sides by the deep search are returned, so
+/// quick and deep scores are never compared.
+#[tauri::command]
+#[specta::specta]
+pub async fn find_critical_moments(
+    id: String,
+    engine: String,
+    search: CriticalMomentsOptions,
+    options: AnalysisOptions,
+    uci_options: Vec<EngineOption>,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<Vec<CriticalMoment>, Error> {
+    options.spawn.limits.validate(&uci_options)?;
+    let variant = options.spawn.variant;
+    let turn = parse_variant_fen_and_apply_moves(&options.fen, &[], variant)?.turn();
+    let game_over =
+        parse_variant_fen_and_apply_moves(&options.fen, &options.moves, variant)?.is_game_over();
+    let limit = search.limit as usize;
+
+    let cancel_flag = Arc::new(AtomicBool::new(false));
+    state
+        .analysis_cancel_flags
+        .insert(id.clone(), cancel_flag.clone());
+
+    // the flag is removed however the search ends
+    let moments = async {
+        let quick_progress = if search.deep.is_some() {
+            0.0..50.0
+        } else {
+            0.0..100.0
+        };
+        let mut analysis = analyze_game_positions(
+            &id,
+            &engine,
+            &search.quick,
+            &options,
+            &uci_options,
+            &cancel_flag,
+            &state,
+            &app,
+            quick_progress,
+        )
+        .await?;
+
+        let Some(deep) = &search.deep else {
+            let mut moments = critical_moments(&analysis, &options.moves, turn);
+            moments.truncate(limit);
+            return Ok(moments);
+        };
+
+        let flagged: Vec<u32> = critical_moments(&analysis, &options.moves, turn)
+            .iter()
+            .take(limit)
+            .map(|m| m.ply)
+            .collect();
+        let mut plies: Vec<usize> = flagged
+            .iter()
+            .flat_map(|&ply| [ply as usize, ply as usize + 1])
+            // the position ending the game is already scored from its outcome
+            .filter(|&ply| ply < analysis.len() && !(game_over && ply == options.moves.len()))
+            .collect();
+        plies.sort_unstable();
+        plies.dedup();
+
+        let (proc, reader) = EngineProcess::new(PathBuf::from(&engine), &options.spawn).await?;
+        let process = Mutex::new(proc);
+        process.lock().await.attach_reader(reader);
+        let extra_options = with_multipv(&uci_options, 2);
+
+        let searched = async {
+            for (n, &ply) in plies.iter().enumerate() {
+                if cancel_flag.load(Ordering::SeqCst) {
+                    return Err(Error::AnalysisCancelled);
+                }
+                update_progress(
+                    &state.progress_state,
+                    &app,
+                    id.clone(),
+                    50.0 + (n as f32 / plies.len() as f32) * 50.0,
+                    false,
+                )?;
+
+                {
+                    let mut proc = process.lock().await;
+                    proc.set_options(EngineOptions {
+                        fen: options.fen.clone(),
+                        moves: options.moves[..ply].to_vec(),
+                        extra_options: extra_options.clone(),
+                        spawn: options.spawn.clone(),
+                    })
+                    .await?;
+                    proc.go(deep).await?;
+                }
+                let best = EngineProcess::search_until_bestmove(&process).await?;
+                if let Some(first) = best.first() {
+                    analysis[ply].expected_score = first.expected_score;
+                    analysis[ply].best = best;
+                }
+            }
+            Ok(())
+        }
+        .await;
+        process.lock().await.kill().await?;
+        searched?;
+
+        let mut moments = critical_moments(&analysis, &options.moves, turn);
+        moments.retain(|m| flagged.contains(&m.ply));
+        moments.truncate(limit);
+        Ok::<_, Error>(moments)
+    }
+    .await;
+    state.analysis_cancel_flags.remove(&id);
+    let moments = moments?;
+
+    update_progress(&state.progress_state, &app, id.clone(), 100.0, true)?;
+    Ok(moments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(lines: &[f64]) -> MoveAnalysis {
+        MoveAnalysis {
+            best: lines
+                .iter()
+                .map(|&expected_score| BestMoves {
+                    expected_score,
+                    ..Default::default()
+                })
+                .collect(),
+            expected_score: lines.first().copied().unwrap_or(0.5),
+            ..Default::default()
+        }
+    }
+
+    fn moves(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("m{}", i)).collect()
+    }
+
+    #[test]
+    fn ranks_decisive_mistake_first() {
+        // Black moves first, so White plays the odd plies
+        let analysis: Vec<_> = [0.5, 0.5, 0.75, 0.7, 0.1, 0.08]
+            .iter()
+            .map(|&s| position(&[s]))
+            .collect();
+        let moments = critical_moments(&analysis, &moves(5), Color::Black);
+
+        assert_eq!(moments.len(), 2);
+        assert_eq!(moments[0].ply, 3);
+        assert_eq!(moments[0].played, "m3");
+        assert_eq!(
+            moments[0].kinds,
+            vec![CriticalKind::Swing, CriticalKind::DecisiveMistake]
+        );
+        assert_eq!(moments[1].ply, 1);
+        assert_eq!(moments[1].kinds, vec![CriticalKind::Swing]);
+    }
+
+    #[test]
+    fn finds_only_moves_for_black() {
+        // Black to move can only keep the balance with the first line
+        let analysis = vec![position(&[0.5, 0.8]), position(&[0.5])];
+        let moments = critical_moments(&analysis, &moves(1), Color::Black);
+        assert_eq!(moments.len(), 1);
+        assert_eq!(moments[0].kinds, vec![CriticalKind::OnlyMove]);
+
+        let moments = critical_moments(&analysis, &moves(1), Color::White);
+        assert!(moments.is_empty());
+    }
+}
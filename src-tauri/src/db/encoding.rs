@@ -1,6 +1,6 @@
-use crate::error::Error;
+use crate::{error::Error, variant::GameVariant};
 use shakmaty::{
-    fen::Fen, san::SanPlus, CastlingMode, Chess, FromSetup, Move, Position, PositionError,
+    fen::Fen, san::SanPlus, variant::VariantPosition, CastlingMode, Move, Position, PositionError,
 };
 use std::io::{self, ErrorKind};
 
@@ -9,16 +9,35 @@ pub const VARIATION_END_MARKER: u8 = 254;
 pub const COMMENT_MARKER: u8 = 253;
 pub const NAG_MARKER: u8 = 252;
 
-pub fn encode_move(m: &Move, chess: &Chess) -> Result<u8, Error> {
+/// Encodes a move as its index among the legal moves. Positions with drops can
+/// have more legal moves than fit below the markers.
+pub fn encode_move<P: Position>(m: &Move, chess: &P) -> Result<u8, Error> {
     let moves = chess.legal_moves();
-    Ok(moves.iter().position(|x| x == m).unwrap() as u8)
+    match moves.iter().position(|x| x == m) {
+        Some(index) if index < NAG_MARKER as usize => Ok(index as u8),
+        _ => Err(invalid_data("Move can't be encoded in this position")),
+    }
 }
 
-pub fn decode_move(byte: u8, chess: &Chess) -> Option<Move> {
+pub fn decode_move<P: Position>(byte: u8, chess: &P) -> Option<Move> {
     let legal_moves = chess.legal_moves();
     legal_moves.get(byte as usize).cloned()
 }
 
+/// Plays the moves, in SAN, from the position and returns them encoded.
+#[cfg(test)]
+pub fn encode_sans<P: Position>(position: &mut P, sans: &[&str]) -> Vec<u8> {
+    use shakmaty::san::San;
+
+    let mut moves = Vec::new();
+    for san in sans {
+        let m = san.parse::<San>().unwrap().to_move(position).unwrap();
+        moves.push(encode_move(&m, position).unwrap());
+        position.play_unchecked(&m);
+    }
+    moves
+}
+
 pub fn encode_comment(comment: &str, output: &mut Vec<u8>) {
     for chunk in comment.as_bytes().chunks(u16::MAX as usize) {
         output.push(COMMENT_MARKER);
@@ -99,6 +118,46 @@ pub fn iter_mainline_move_bytes(bytes: &[u8]) -> MainlineMoveBytesIter<'_> {
     MainlineMoveBytesIter::new(bytes)
 }
 
+/// Move bytes of every variation of a game, each one preceded by the moves
+/// leading to it, in the order the variations end. Comments and NAGs are left
+/// out, so the lines can be read with `iter_mainline_move_bytes`.
+pub fn variation_lines(bytes: &[u8]) -> Vec<Vec<u8>> {
+    let mut stack: Vec<Vec<u8>> = vec![Vec::new()];
+    let mut lines = Vec::new();
+    let mut cursor = 0usize;
+    while cursor < bytes.len() {
+        let byte = bytes[cursor];
+        cursor += 1;
+
+        match byte {
+            VARIATION_START_MARKER => {
+                // a variation replaces the last move of its parent line
+                let parent = stack.last().map(Vec::as_slice).unwrap_or_default();
+                let line = parent[..parent.len().saturating_sub(1)].to_vec();
+                stack.push(line);
+            }
+            VARIATION_END_MARKER => {
+                if stack.len() > 1 {
+                    lines.extend(stack.pop());
+                }
+            }
+            COMMENT_MARKER | NAG_MARKER => {
+                if cursor + 2 > bytes.len() {
+                    break;
+                }
+                let len = u16::from_le_bytes([bytes[cursor], bytes[cursor + 1]]) as usize;
+                cursor = (cursor + 2).saturating_add(len);
+            }
+            move_idx => {
+                if let Some(line) = stack.last_mut() {
+                    line.push(move_idx);
+                }
+            }
+        }
+    }
+    lines
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DecodedGameNode {
     Move(String),
@@ -114,20 +173,24 @@ pub struct DecodedGame {
 
 struct DecodeFrame {
     nodes: Vec<DecodedGameNode>,
-    chess: Chess,
-    pre_move_positions: Vec<Chess>,
+    chess: VariantPosition,
+    pre_move_positions: Vec<VariantPosition>,
 }
 
 fn invalid_data(message: &str) -> Error {
     Error::from(io::Error::new(ErrorKind::InvalidData, message.to_string()))
 }
 
-pub fn decode_game(moves_bytes: &[u8], initial_fen: Fen) -> Result<DecodedGame, Error> {
+pub fn decode_game(
+    moves_bytes: &[u8],
+    initial_fen: Fen,
+    variant: GameVariant,
+) -> Result<DecodedGame, Error> {
     let setup = initial_fen.into_setup();
     let castling_mode = CastlingMode::detect(&setup);
-    let root_position = Chess::from_setup(setup, castling_mode)
-        .or_else(PositionError::ignore_too_much_material)
-        .unwrap();
+    let root_position = variant
+        .position(setup, castling_mode)
+        .or_else(PositionError::ignore_too_much_material)?;
 
     let mut stack = vec![DecodeFrame {
         nodes: Vec::new(),
@@ -338,9 +401,19 @@ fn render_nodes(nodes: &[DecodedGameNode], state: &mut RenderState) -> String {
     out
 }
 
-pub fn decode_game_to_movetext(moves_bytes: &[u8], initial_fen: Fen) -> Result<String, Error> {
+/// Renders a decoded game tree as PGN movetext.
+pub fn render_movetext(game: &DecodedGame, initial_fen: &Fen) -> String {
+    let mut state = parse_initial_render_state(initial_fen);
+    render_nodes(&game.nodes, &mut state)
+}
+
+pub fn decode_game_to_movetext(
+    moves_bytes: &[u8],
+    initial_fen: Fen,
+    variant: GameVariant,
+) -> Result<String, Error> {
     let render_state = parse_initial_render_state(&initial_fen);
-    let decoded = decode_game(moves_bytes, initial_fen)?;
+    let decoded = decode_game(moves_bytes, initial_fen, variant)?;
     let mut state = render_state;
     Ok(render_nodes(&decoded.nodes, &mut state))
 }
@@ -349,7 +422,7 @@ pub fn decode_game_to_movetext(moves_bytes: &[u8], initial_fen: Fen) -> Result<S
 mod tests {
     use super::*;
 
-    use shakmaty::{Role, Square};
+    use shakmaty::{Chess, FromSetup, Role, Square};
 
     #[test]
     fn test_encoding() {
@@ -413,6 +486,8 @@ mod tests {
 
         let result: Vec<u8> = iter_mainline_move_bytes(&bytes).collect();
         assert_eq!(result, vec![1, 5]);
+
+        assert_eq!(variation_lines(&bytes), vec![vec![3], vec![2, 4]]);
     }
 
     #[test]
@@ -437,7 +512,8 @@ mod tests {
         bytes.push(encode_move(&m2, &chess).unwrap());
         encode_nag("$1", &mut bytes);
 
-        let movetext = decode_game_to_movetext(&bytes, Fen::default()).unwrap();
+        let movetext =
+            decode_game_to_movetext(&bytes, Fen::default(), GameVariant::Standard).unwrap();
         assert_eq!(movetext, "1. e4! (1. e4?) 1... e5!");
     }
 
@@ -485,7 +561,8 @@ mod tests {
         };
         bytes.push(encode_move(&fourth, &chess).unwrap());
 
-        let movetext = decode_game_to_movetext(&bytes, Fen::default()).unwrap();
+        let movetext =
+            decode_game_to_movetext(&bytes, Fen::default(), GameVariant::Standard).unwrap();
         assert_eq!(movetext, "1. e4 e5 2. Nf3 Nc6");
     }
 
@@ -515,7 +592,8 @@ mod tests {
         };
         bytes.push(encode_move(&black_move, &chess).unwrap());
 
-        let movetext = decode_game_to_movetext(&bytes, Fen::default()).unwrap();
+        let movetext =
+            decode_game_to_movetext(&bytes, Fen::default(), GameVariant::Standard).unwrap();
         assert_eq!(movetext, "1. e4 {mainline} e5");
     }
 
@@ -537,10 +615,30 @@ mod tests {
         };
         let bytes = vec![encode_move(&black_pawn_push, &chess).unwrap()];
 
-        let movetext = decode_game_to_movetext(&bytes, initial_fen).unwrap();
+        let movetext = decode_game_to_movetext(&bytes, initial_fen, GameVariant::Standard).unwrap();
         assert_eq!(movetext, "1... e5");
     }
 
+    #[test]
+    fn test_decode_crazyhouse_drop() {
+        let initial_fen: Fen = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR[N] w KQkq - 0 1"
+            .parse()
+            .unwrap();
+        let position = GameVariant::Crazyhouse
+            .position(initial_fen.clone().into_setup(), CastlingMode::Standard)
+            .unwrap();
+
+        let drop = Move::Put {
+            role: Role::Knight,
+            to: Square::E4,
+        };
+        let bytes = vec![encode_move(&drop, &position).unwrap()];
+
+        let movetext =
+            decode_game_to_movetext(&bytes, initial_fen, GameVariant::Crazyhouse).unwrap();
+        assert_eq!(movetext, "1. N@e4");
+    }
+
     #[test]
     fn test_decode_game_nested_variations_and_comments() {
         let mut bytes = Vec::new();
@@ -571,7 +669,7 @@ mod tests {
         let m_mainline_second = decode_move(12, &root).unwrap();
         bytes.push(encode_move(&m_mainline_second, &root).unwrap());
 
-        let decoded = decode_game(&bytes, Fen::default()).unwrap();
+        let decoded = decode_game(&bytes, Fen::default(), GameVariant::Standard).unwrap();
 
         let expected_first = SanPlus::from_move(Chess::default(), &m_e4).to_string();
         let expected_var_first = SanPlus::from_move(root.clone(), &m_var_first).to_string();
@@ -0,0 +1,238 @@
+use serde::Deserialize;
+use shakmaty::{Bitboard, Board, ByColor, ByRole, Color};
+use specta::Type;
+
+use crate::{db::MaterialCount, error::Error};
+
+/// How the pawns of a material signature are compared.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Type, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum PawnCondition {
+    /// As many pawns as in the signature.
+    #[default]
+    Exact,
+    /// Both sides have the same number of pawns.
+    Equal,
+    Any,
+}
+
+/// Where a game has to reach the material.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Type, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum MaterialPhase {
+    #[default]
+    Any,
+    End,
+}
+
+#[derive(Debug, Clone, Deserialize, Type, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct MaterialQueryJs {
+    /// Pieces of White and Black separated by "v", e.g. "KRPvKR". `m` stands
+    /// for a bishop or a knight, so "KRvKmm" is a rook against two minor
+    /// pieces.
+    pub signature: String,
+    #[serde(default)]
+    #[specta(optional)]
+    pub pawns: PawnCondition,
+    /// Both sides have a single bishop, on squares of opposite colours.
+    #[serde(default)]
+    #[specta(optional)]
+    pub opposite_bishops: bool,
+    #[serde(default)]
+    #[specta(optional)]
+    pub phase: MaterialPhase,
+}
+
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq)]
+struct SideSignature {
+    queens: u8,
+    rooks: u8,
+    bishops: u8,
+    knights: u8,
+    /// Bishops or knights.
+    minors: u8,
+    pawns: u8,
+}
+
+impl SideSignature {
+    fn parse(side: &str) -> Result<Self, Error> {
+        let mut signature = Self::default();
+        for c in side.chars() {
+            match c.to_ascii_uppercase() {
+                'K' => {}
+                'Q' => signature.queens += 1,
+                'R' => signature.rooks += 1,
+                'B' => signature.bishops += 1,
+                'N' => signature.knights += 1,
+                'M' => signature.minors += 1,
+                'P' => signature.pawns += 1,
+                _ => {
+                    return Err(Error::InvalidPattern(format!(
+                        "unknown piece {c} in material signature"
+                    )))
+                }
+            }
+        }
+        Ok(signature)
+    }
+
+    fn matches(&self, material: &ByRole<u8>, count_pawns: bool) -> bool {
+        material.queen == self.queens
+            && material.rook == self.rooks
+            && material.bishop >= self.bishops
+            && material.knight >= self.knights
+            && material.bishop + material.knight == self.bishops + self.knights + self.minors
+            && (!count_pawns || material.pawn == self.pawns)
+    }
+
+    /// Same scale as `get_material_count`.
+    fn value(&self) -> u8 {
+        self.pawns
+            + (self.bishops + self.knights + self.minors) * 3
+            + self.rooks * 5
+            + self.queens * 9
+    }
+}
+
+fn has_opposite_bishops(board: &Board) -> bool {
+    let white = board.bishops() & board.by_color(Color::White);
+    let black = board.bishops() & board.by_color(Color::Black);
+    white.count() == 1
+        && black.count() == 1
+        && (white & Bitboard::LIGHT_SQUARES).any() != (black & Bitboard::LIGHT_SQUARES).any()
+}
+
+/// Material balance a position has to match, see `MaterialQueryJs`.
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+pub struct MaterialData {
+    sides: ByColor<SideSignature>,
+    pawns: PawnCondition,
+    opposite_bishops: bool,
+    /// Also match the signature with the colours swapped.
+    flip: bool,
+    phase: MaterialPhase,
+}
+
+impl MaterialData {
+    pub fn matches(&self, board: &Board) -> bool {
+        let material = board.material();
+        let count_pawns = self.pawns == PawnCondition::Exact;
+        let matches_as = |white: &SideSignature, black: &SideSignature| {
+            white.matches(&material.white, count_pawns)
+                && black.matches(&material.black, count_pawns)
+        };
+
+        (self.pawns != PawnCondition::Equal || material.white.pawn == material.black.pawn)
+            && (!self.opposite_bishops || has_opposite_bishops(board))
+            && (matches_as(&self.sides.white, &self.sides.black)
+                || (self.flip && matches_as(&self.sides.black, &self.sides.white)))
+    }
+
+    /// Also matches the signature with the colours swapped.
+    pub fn also_flipped(self) -> Self {
+        Self { flip: true, ..self }
+    }
+
+    pub fn at_end_only(&self) -> bool {
+        self.phase == MaterialPhase::End
+    }
+
+    /// Material counts a matching position can have, one per orientation of
+    /// the signature. Unknown if pawns are not compared to the signature.
+    pub fn material_counts(&self) -> Option<Vec<MaterialCount>> {
+        if self.pawns != PawnCondition::Exact {
+            return None;
+        }
+        let white = self.sides.white.value();
+        let black = self.sides.black.value();
+        let mut counts = vec![ByColor { white, black }];
+        if self.flip {
+            counts.push(ByColor {
+                white: black,
+                black: white,
+            });
+        }
+        Some(counts)
+    }
+}
+
+impl TryFrom<&MaterialQueryJs> for MaterialData {
+    type Error = Error;
+
+    fn try_from(query: &MaterialQueryJs) -> Result<Self, Error> {
+        let Some((white, black)) = query.signature.split_once(['v', 'V']) else {
+            return Err(Error::InvalidPattern(format!(
+                "material signature {} has no \"v\"",
+                query.signature
+            )));
+        };
+        Ok(Self {
+            sides: ByColor {
+                white: SideSignature::parse(white)?,
+                black: SideSignature::parse(black)?,
+            },
+            pawns: query.pawns,
+            opposite_bishops: query.opposite_bishops,
+            flip: false,
+            phase: query.phase,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::search::board_from_fen;
+
+    fn query(signature: &str) -> MaterialQueryJs {
+        MaterialQueryJs {
+            signature: signature.to_string(),
+            pawns: PawnCondition::Exact,
+            opposite_bishops: false,
+            phase: MaterialPhase::Any,
+        }
+    }
+
+    #[test]
+    fn matches_rook_endings() {
+        let data = MaterialData::try_from(&query("KRPvKR")).unwrap();
+        assert!(data.matches(&board_from_fen("8/8/4k3/8/3P4/8/r7/R3K3 w - - 0 1")));
+        assert!(!data.matches(&board_from_fen("8/8/4k3/8/3P4/3P4/r7/R3K3 w - - 0 1")));
+        assert!(!data.matches(&board_from_fen("8/8/4k3/8/3p4/8/r7/R3K3 w - - 0 1")));
+        assert_eq!(
+            data.material_counts(),
+            Some(vec![ByColor { white: 6, black: 5 }])
+        );
+    }
+
+    #[test]
+    fn matches_minor_pieces_either_way() {
+        let data = MaterialData::try_from(&MaterialQueryJs {
+            pawns: PawnCondition::Any,
+            ..query("KRvKmm")
+        })
+        .unwrap()
+        .also_flipped();
+        assert!(data.matches(&board_from_fen("8/8/2bnk3/8/8/5P2/8/R3K3 w - - 0 1")));
+        assert!(data.matches(&board_from_fen("8/8/2r1k3/8/8/8/8/B1B1K3 w - - 0 1")));
+        assert!(!data.matches(&board_from_fen("8/8/2n1k3/8/8/8/8/R3K3 w - - 0 1")));
+        assert_eq!(data.material_counts(), None);
+    }
+
+    #[test]
+    fn matches_opposite_bishops_with_equal_pawns() {
+        let data = MaterialData::try_from(&MaterialQueryJs {
+            pawns: PawnCondition::Equal,
+            opposite_bishops: true,
+            phase: MaterialPhase::End,
+            ..query("KBvKB")
+        })
+        .unwrap();
+        assert!(data.at_end_only());
+        assert!(data.matches(&board_from_fen("8/5p2/4kb2/8/2B5/8/5P2/4K3 w - - 0 1")));
+        assert!(!data.matches(&board_from_fen("8/5p2/4kb2/8/8/2B5/5P2/4K3 w - - 0 1")));
+        assert!(!data.matches(&board_from_fen("8/5p2/4kb2/8/2B5/8/4PP2/4K3 w - - 0 1")));
+        assert!(MaterialData::try_from(&query("KRPKR")).is_err());
+    }
+}
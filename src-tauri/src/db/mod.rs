@@ -1,6 +1,12 @@
 mod encoding;
+mod material;
 mod models;
+mod move_pattern;
+mod opening_tree;
 mod ops;
+mod pattern;
+mod pawn_structure;
+mod position_index;
 mod schema;
 mod search;
 mod search_index;
@@ -9,15 +15,22 @@ use crate::{
     db::{
         encoding::{decode_game_to_movetext, decode_move, iter_mainline_move_bytes},
         models::*,
+        move_pattern::MovePatternQueryJs,
+        opening_tree::{
+            add_game_to_tree, create_opening_tree, opening_tree_depth, remove_game_from_tree,
+            OpeningTreeBuilder, TreeGame, DEFAULT_OPENING_TREE_DEPTH,
+        },
         ops::*,
         schema::*,
     },
     error::Error,
     opening::get_opening_from_setup,
+    variant::GameVariant,
     AppState,
 };
 use chrono::{NaiveDate, NaiveTime};
 use dashmap::DashMap;
+use derivative::Derivative;
 use diesel::{
     connection::{DefaultLoadingMode, SimpleConnection},
     insert_into,
@@ -26,15 +39,19 @@ use diesel::{
     sql_query,
     sql_types::Text,
 };
+use futures_util::future::try_join_all;
 use pgn_reader::{BufferedReader, Nag, RawHeader, SanPlus, Skip, Visitor};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use shakmaty::{
-    fen::Fen, Board, ByColor, CastlingMode, Chess, EnPassantMode, FromSetup, Piece, Position,
-    PositionError,
+    fen::Fen,
+    variant::{Variant, VariantPosition},
+    Board, ByColor, CastlingMode, Chess, EnPassantMode, Piece, Position, PositionError,
 };
 use specta::Type;
 use std::{
+    cmp,
+    collections::HashSet,
     fs::{remove_file, File, OpenOptions},
     path::{Path, PathBuf},
     sync::atomic::{AtomicUsize, Ordering},
@@ -49,9 +66,15 @@ use tauri::{Emitter, State};
 use log::info;
 use tauri_specta::Event as _;
 
+pub use self::encoding::{decode_game, render_movetext, DecodedGame, DecodedGameNode};
 use self::encoding::{
     encode_comment, encode_move, encode_nag, VARIATION_END_MARKER, VARIATION_START_MARKER,
 };
+pub use self::opening_tree::{build_opening_tree, get_opening_tree};
+pub use self::position_index::{
+    get_position_index_path, position_index_enabled, set_position_index_enabled,
+    write_position_index, MmapPositionIndex,
+};
 pub use self::search_index::{get_index_path, MmapSearchIndex, SearchGameEntry, SearchIndex};
 
 pub use self::models::NormalizedGame;
@@ -59,7 +82,11 @@ pub use self::models::Puzzle;
 pub use self::schema::puzzle_themes;
 pub use self::schema::puzzles;
 pub use self::schema::themes;
-pub use self::search::{is_position_in_db, search_position, PositionQueryJs, PositionStats};
+pub use self::search::{
+    cancel_search, forget_searches, get_search_matches, get_search_totals, is_position_in_db,
+    search_position, search_position_multi, stream_search_position, PositionQueryJs, PositionStats,
+    SearchResults,
+};
 
 const DATABASE_VERSION: &str = "1.0.0";
 
@@ -160,6 +187,7 @@ fn get_db_or_create(
                 .max_size(16)
                 .connection_customizer(Box::new(options))
                 .build(ConnectionManager::<SqliteConnection>::new(db_path))?;
+            add_variant_column(&mut pool.get()?)?;
             state
                 .connection_pool
                 .insert(db_path.to_string(), pool.clone());
@@ -170,6 +198,23 @@ fn get_db_or_create(
     Ok(pool.get()?)
 }
 
+#[derive(QueryableByName)]
+struct ColumnName {
+    #[diesel(sql_type = Text)]
+    name: String,
+}
+
+/// Databases created before variants were supported lack the `Variant`
+/// column, all of their games are standard.
+fn add_variant_column(db: &mut SqliteConnection) -> Result<(), Error> {
+    let columns: Vec<ColumnName> =
+        sql_query("SELECT name FROM pragma_table_info('Games')").load(db)?;
+    if !columns.is_empty() && !columns.iter().any(|c| c.name == "Variant") {
+        db.batch_execute("ALTER TABLE Games ADD COLUMN Variant TEXT;")?;
+    }
+    Ok(())
+}
+
 fn update_info_count(
     db: &mut SqliteConnection,
     name: &str,
@@ -199,7 +244,8 @@ impl Default for MaterialColor {
     }
 }
 
-#[derive(Default, Debug)]
+#[derive(Derivative, Debug)]
+#[derivative(Default)]
 pub struct TempGame {
     pub event_name: Option<String>,
     pub site_name: Option<String>,
@@ -214,8 +260,10 @@ pub struct TempGame {
     pub time_control: Option<String>,
     pub eco: Option<String>,
     pub fen: Option<String>,
+    pub variant: GameVariant,
     pub moves: Vec<u8>,
-    pub position: Chess,
+    #[derivative(Default(value = "VariantPosition::new(Variant::Chess)"))]
+    pub position: VariantPosition,
     pub material_count: MaterialColor,
 }
 
@@ -271,11 +319,23 @@ impl TempGame {
             result: self.result.as_deref(),
             moves: self.moves.as_slice(),
             pawn_home: pawn_home as i32,
+            variant: self.variant.db_name(),
         };
 
         create_game(db, new_game)?;
         Ok(())
     }
+
+    fn tree_game(&self) -> TreeGame<'_> {
+        TreeGame {
+            fen: self.fen.as_deref(),
+            moves: &self.moves,
+            result: self.result.as_deref(),
+            white_elo: self.white_elo,
+            black_elo: self.black_elo,
+            date: self.date.as_deref(),
+        }
+    }
 }
 
 struct Importer {
@@ -286,12 +346,12 @@ struct Importer {
 }
 
 struct ImportFrame {
-    position: Chess,
-    pre_move_positions: Vec<Chess>,
+    position: VariantPosition,
+    pre_move_positions: Vec<VariantPosition>,
 }
 
 impl ImportFrame {
-    fn new(position: Chess) -> Self {
+    fn new(position: VariantPosition) -> Self {
         Self {
             position,
             pre_move_positions: Vec::new(),
@@ -353,24 +413,12 @@ impl Visitor for Importer {
         } else if key == b"Result" {
             self.game.result = Some(String::from_utf8_lossy(value.as_bytes()).to_string());
         } else if key == b"FEN" {
-            if value.as_bytes() == b"rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1" {
-                self.game.fen = None;
-            } else {
-                let fen = Fen::from_ascii(value.as_bytes());
-                if let Ok(fen) = fen {
-                    self.game.fen = Some(value.decode_utf8_lossy().into_owned());
-                    let setup = fen.into_setup();
-                    let castling_mode = CastlingMode::detect(&setup);
-                    if let Ok(setup) = Chess::from_setup(setup, castling_mode)
-                        .or_else(PositionError::ignore_too_much_material)
-                    {
-                        self.game.position = setup;
-                    } else {
-                        self.skip = true;
-                    }
-                } else {
-                    self.skip = true;
-                }
+            // the position is set up once the variant is known
+            self.game.fen = Some(value.decode_utf8_lossy().into_owned());
+        } else if key == b"Variant" {
+            match GameVariant::from_pgn(&value.decode_utf8_lossy()) {
+                Some(variant) => self.game.variant = variant,
+                None => self.skip = true,
             }
         }
     }
@@ -396,6 +444,30 @@ impl Visitor for Importer {
         // Skip games without ELO
         // self.skip |= self.current.white_elo.is_none() || self.current.black_elo.is_none();
 
+        let variant = self.game.variant;
+        self.game.position = variant.start_position();
+        if let Some(fen) = &self.game.fen {
+            let position = Fen::from_ascii(fen.as_bytes()).ok().and_then(|fen| {
+                let setup = fen.into_setup();
+                let castling_mode = CastlingMode::detect(&setup);
+                variant
+                    .position(setup, castling_mode)
+                    .or_else(PositionError::ignore_too_much_material)
+                    .ok()
+            });
+            match position {
+                Some(position) => {
+                    if Fen::from_position(position.clone(), EnPassantMode::Legal)
+                        == variant.start_fen()
+                    {
+                        self.game.fen = None;
+                    }
+                    self.game.position = position;
+                }
+                None => self.skip = true,
+            }
+        }
+
         self.frames.clear();
         self.frames
             .push(ImportFrame::new(self.game.position.clone()));
@@ -424,9 +496,13 @@ impl Visitor for Importer {
                     self.game.material_count.black = cur_material.black;
                 }
             }
-            self.game
-                .moves
-                .push(encode_move(&m, &frame.position).unwrap());
+            match encode_move(&m, &frame.position) {
+                Ok(byte) => self.game.moves.push(byte),
+                Err(_) => {
+                    self.skip = true;
+                    return;
+                }
+            }
             frame.pre_move_positions.push(pre_move_position);
             frame.position.play_unchecked(&m);
 
@@ -498,6 +574,7 @@ pub async fn convert_pgn(
     app: tauri::AppHandle,
     title: String,
     description: Option<String>,
+    position_index: Option<bool>,
     state: tauri::State<'_, AppState>,
 ) -> Result<(), Error> {
     if files.is_empty() {
@@ -529,8 +606,11 @@ pub async fn convert_pgn(
             )
             .as_str(),
         )?;
+        create_opening_tree(db, DEFAULT_OPENING_TREE_DEPTH)?;
     }
 
+    let mut opening_tree = opening_tree_depth(db)?.map(OpeningTreeBuilder::new);
+
     // start counting time
     let start = Instant::now();
 
@@ -573,8 +653,17 @@ pub async fn convert_pgn(
                     .unwrap();
                 }
                 game.insert_to_db(db)?;
+                if let Some(tree) = &mut opening_tree {
+                    if game.variant.is_standard() {
+                        tree.add_game(game.tree_game());
+                    }
+                    tree.flush_if_full(db)?;
+                }
                 file_imported_games += 1;
             }
+            if let Some(tree) = &mut opening_tree {
+                tree.flush(db)?;
+            }
             Ok(())
         })?;
 
@@ -608,6 +697,12 @@ pub async fn convert_pgn(
             .execute(db)?;
     }
 
+    if let Some(enabled) = position_index {
+        set_position_index_enabled(db, enabled)?;
+    }
+
+    generate_search_index(&db_path, &state)?;
+
     Ok(())
 }
 
@@ -638,6 +733,7 @@ pub fn generate_search_index(
         i32,
         Option<i32>,
         Option<i32>,
+        Option<String>,
     )> = games::table
         .select((
             games::id,
@@ -652,6 +748,7 @@ pub fn generate_search_index(
             games::black_material,
             games::white_elo,
             games::black_elo,
+            games::variant,
         ))
         .load(db)?;
 
@@ -669,6 +766,7 @@ pub fn generate_search_index(
         black_material,
         white_elo,
         black_elo,
+        variant,
     ) in games
     {
         let entry = SearchGameEntry::from_game_data(
@@ -684,12 +782,31 @@ pub fn generate_search_index(
             black_material,
             white_elo,
             black_elo,
+            variant,
         );
         writer.push(entry);
     }
-    writer.write_to(&index_path)?;
+    // the indexes are rewritten, they can't stay mapped
+    state.position_indexes.remove(db_path);
+    state.db_cache.remove(db_path);
+    forget_searches(db_path, state);
 
+    let checksum = writer.write_to(&index_path)?;
     info!("Search index generated in {:?}", start.elapsed());
+
+    let position_index_path = get_position_index_path(db_path);
+    if position_index_enabled(db)? {
+        write_position_index(&writer.entries, checksum, &position_index_path)?;
+        info!(
+            "Position index generated at {:?} in {:?}",
+            position_index_path,
+            start.elapsed()
+        );
+    } else if position_index_path.exists() {
+        // position searches scan the games instead
+        remove_file(&position_index_path)?;
+    }
+
     Ok(())
 }
 
@@ -887,8 +1004,13 @@ pub struct GameQuery {
     pub outcome: Option<String>,
     #[specta(optional)]
     pub position: Option<PositionQueryJs>,
+    /// Moves the games have to contain, checked by the position search.
+    #[specta(optional)]
+    pub move_pattern: Option<MovePatternQueryJs>,
     #[specta(optional)]
     pub wanted_result: Option<String>,
+    #[specta(optional)]
+    pub variant: Option<GameVariant>,
 }
 
 impl GameQuery {
@@ -899,6 +1021,10 @@ impl GameQuery {
         self.position = Some(position);
         self
     }
+    pub fn variant(mut self, variant: GameVariant) -> Self {
+        self.variant = Some(variant);
+        self
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Type)]
@@ -907,6 +1033,16 @@ pub struct QueryResponse<T> {
     pub count: Option<i32>,
 }
 
+/// A game found by a search over several databases, with the database it is
+/// from. Its ids, and those of its players and event, are those of that
+/// database.
+#[derive(Serialize, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct SourcedGame {
+    pub file: PathBuf,
+    pub game: NormalizedGame,
+}
+
 #[tauri::command]
 #[specta::specta]
 pub async fn get_games(
@@ -953,6 +1089,19 @@ pub async fn get_games(
         count_query = count_query.filter(games::event_id.eq(tournament_id));
     }
 
+    if let Some(variant) = query.variant {
+        match variant.db_name() {
+            Some(name) => {
+                sql_query = sql_query.filter(games::variant.eq(name));
+                count_query = count_query.filter(games::variant.eq(name));
+            }
+            None => {
+                sql_query = sql_query.filter(games::variant.is_null());
+                count_query = count_query.filter(games::variant.is_null());
+            }
+        }
+    }
+
     if let Some(limit) = query_options.page_size {
         sql_query = sql_query.limit(limit as i64);
     }
@@ -1109,14 +1258,113 @@ pub async fn get_games(
     })
 }
 
+fn compare_games(a: &NormalizedGame, b: &NormalizedGame, sort: &GameSort) -> cmp::Ordering {
+    match sort {
+        GameSort::Id => a.id.cmp(&b.id),
+        GameSort::Date => (&a.date, &a.time).cmp(&(&b.date, &b.time)),
+        GameSort::WhiteElo => a.white_elo.cmp(&b.white_elo),
+        GameSort::BlackElo => a.black_elo.cmp(&b.black_elo),
+        GameSort::PlyCount => a.ply_count.cmp(&b.ply_count),
+    }
+}
+
+/// Games of the merged order of several databases that can be paged through.
+/// Every database has to load the games up to the requested page.
+const MAX_MERGED_GAMES: i32 = 10_000;
+
+/// Removes the databases given twice, and rejects the filters by player or
+/// tournament when there are several databases, as their ids are those of a
+/// single database.
+fn check_multi_query(files: &mut Vec<PathBuf>, query: &GameQuery) -> Result<(), Error> {
+    let mut seen = HashSet::new();
+    files.retain(|file| seen.insert(file.clone()));
+    if files.len() > 1
+        && (query.player1.is_some() || query.player2.is_some() || query.tournament_id.is_some())
+    {
+        return Err(Error::InvalidMultiQuery(
+            "players and tournaments can only be filtered in a single database".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Same as `get_games` over several databases, the games of all of them being
+/// sorted together.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_games_multi(
+    mut files: Vec<PathBuf>,
+    query: GameQuery,
+    state: tauri::State<'_, AppState>,
+) -> Result<QueryResponse<Vec<SourcedGame>>, Error> {
+    check_multi_query(&mut files, &query)?;
+    let options = query.options.clone().unwrap_or_default();
+    if files.len() > 1 && options.sort == GameSort::Id {
+        return Err(Error::InvalidMultiQuery(
+            "ids of different databases can't be sorted together".to_string(),
+        ));
+    }
+    let page = options.page.unwrap_or(1).max(1);
+    let merged = options.page_size.map(|size| size.saturating_mul(page));
+    if files.len() > 1 && merged.map_or(true, |merged| merged > MAX_MERGED_GAMES) {
+        return Err(Error::InvalidMultiQuery(format!(
+            "only the first {MAX_MERGED_GAMES} games can be paged through"
+        )));
+    }
+    // every database has to return the games up to the requested page
+    let file_query = GameQuery {
+        options: Some(QueryOptions {
+            page: Some(1),
+            page_size: merged,
+            ..options.clone()
+        }),
+        ..query
+    };
+
+    let responses = try_join_all(
+        files
+            .iter()
+            .map(|file| get_games(file.clone(), file_query.clone(), state.clone())),
+    )
+    .await?;
+
+    let mut games: Vec<SourcedGame> = Vec::new();
+    let mut count: Option<i32> = None;
+    for (file, response) in files.iter().zip(responses) {
+        count = response.count.map(|c| c + count.unwrap_or(0)).or(count);
+        games.extend(response.data.into_iter().map(|game| SourcedGame {
+            file: file.clone(),
+            game,
+        }));
+    }
+
+    games.sort_by(|a, b| {
+        let ordering = compare_games(&a.game, &b.game, &options.sort);
+        match options.direction {
+            SortDirection::Asc => ordering,
+            SortDirection::Desc => ordering.reverse(),
+        }
+    });
+    if let Some(page_size) = options.page_size {
+        games = games
+            .into_iter()
+            .skip(((page - 1) * page_size) as usize)
+            .take(page_size as usize)
+            .collect();
+    }
+
+    Ok(QueryResponse { data: games, count })
+}
+
 fn normalize_games(games: Vec<(Game, Player, Player, Event, Site)>) -> Vec<NormalizedGame> {
     games
         .into_iter()
         .map(|(game, white, black, event, site)| {
+            let variant = GameVariant::from_db_name(game.variant.as_deref());
             let fen: Fen = game
                 .fen
                 .map(|f| Fen::from_ascii(f.as_bytes()).unwrap())
-                .unwrap_or_default();
+                .unwrap_or_else(|| variant.start_fen());
             let game_result = game.result.clone().unwrap_or_default();
             let result_token = if game_result.is_empty() {
                 "*".to_string()
@@ -1145,13 +1393,15 @@ fn normalize_games(games: Vec<(Game, Player, Player, Event, Site)>) -> Vec<Norma
                 ply_count: game.ply_count,
                 fen: fen.to_string(),
                 moves: {
-                    let movetext = decode_game_to_movetext(&game.moves, fen).unwrap_or_default();
+                    let movetext =
+                        decode_game_to_movetext(&game.moves, fen, variant).unwrap_or_default();
                     if movetext.is_empty() {
                         result_token
                     } else {
                         format!("{} {}", movetext, result_token)
                     }
                 },
+                variant,
             }
         })
         .collect()
@@ -1399,7 +1649,8 @@ pub async fn get_players_game_info(
             players::name,
         ))
         .filter(games::white_id.eq(id).or(games::black_id.eq(id)))
-        .filter(games::fen.is_null());
+        .filter(games::fen.is_null())
+        .filter(games::variant.is_null());
 
     type GameInfo = (
         i32,
@@ -1538,6 +1789,13 @@ pub async fn delete_database(
     // delete file
     remove_file(path_str)?;
     remove_file(get_index_path(&PathBuf::from(path_str)))?;
+    let position_index_path = get_position_index_path(&file);
+    if position_index_path.exists() {
+        remove_file(position_index_path)?;
+    }
+    state.position_indexes.remove(&file);
+    state.db_cache.remove(&file);
+    forget_searches(&file, &state);
     Ok(())
 }
 
@@ -1594,6 +1852,7 @@ pub async fn delete_duplicated_games(
     let game_count: i64 = games::table.count().get_result(db)?;
     update_info_count(db, "GameCount", game_count)?;
     delete_orphaned_data(db)?;
+    forget_searches(&file, &state);
 
     Ok(())
 }
@@ -1611,6 +1870,7 @@ pub async fn delete_empty_games(
     let game_count: i64 = games::table.count().get_result(db)?;
     update_info_count(db, "GameCount", game_count)?;
     delete_orphaned_data(db)?;
+    forget_searches(&file, &state);
 
     Ok(())
 }
@@ -1628,6 +1888,7 @@ struct PgnGame {
     white_elo: Option<String>,
     black_elo: Option<String>,
     ply_count: Option<String>,
+    variant: GameVariant,
     fen: Option<String>,
     moves: Option<String>,
 }
@@ -1684,6 +1945,9 @@ impl PgnGame {
         if let Some(ply_count) = self.ply_count.as_deref() {
             writeln!(writer, "[PlyCount \"{}\"]", ply_count)?;
         }
+        if !self.variant.is_standard() {
+            writeln!(writer, "[Variant \"{}\"]", self.variant.pgn_name())?;
+        }
         if let Some(fen) = self.fen.as_deref() {
             writeln!(writer, "[SetUp \"1\"]")?;
             writeln!(writer, "[FEN \"{}\"]", fen)?;
@@ -1731,6 +1995,7 @@ pub async fn export_to_pgn(
         .load_iter::<(Game, Player, Player, Event, Site), DefaultLoadingMode>(db)?
         .flatten()
         .map(|(game, white, black, event, site)| {
+            let variant = GameVariant::from_db_name(game.variant.as_deref());
             let pgn = PgnGame {
                 event: event.name,
                 site: site.name,
@@ -1744,14 +2009,16 @@ pub async fn export_to_pgn(
                 white_elo: game.white_elo.map(|e| e.to_string()),
                 black_elo: game.black_elo.map(|e| e.to_string()),
                 ply_count: game.ply_count.map(|e| e.to_string()),
+                variant,
                 fen: game.fen.clone(),
                 moves: decode_game_to_movetext(
                     &game.moves,
                     if let Some(fen) = game.fen {
-                        Fen::from_ascii(fen.as_bytes()).unwrap_or_default()
+                        Fen::from_ascii(fen.as_bytes()).unwrap_or_else(|_| variant.start_fen())
                     } else {
-                        Fen::default()
+                        variant.start_fen()
                     },
+                    variant,
                 )
                 .ok(),
             };
@@ -1764,6 +2031,31 @@ pub async fn export_to_pgn(
     Ok(())
 }
 
+/// Loads the full move tree of a game along with its starting position. Only
+/// standard games can be loaded.
+pub fn load_game_tree(
+    state: &State<AppState>,
+    file: &Path,
+    game_id: i32,
+) -> Result<(DecodedGame, Fen), Error> {
+    let db = &mut get_db_or_create(state, file.to_str().unwrap(), ConnectionOptions::default())?;
+
+    let game: Game = games::table
+        .filter(games::id.eq(game_id))
+        .first(db)
+        .optional()?
+        .ok_or_else(|| Error::GameNotFound(game_id.to_string()))?;
+    if let Some(variant) = game.variant {
+        return Err(Error::UnsupportedVariant(variant));
+    }
+    let fen = match game.fen {
+        Some(fen) => Fen::from_ascii(fen.as_bytes())?,
+        None => Fen::default(),
+    };
+    let tree = decode_game(&game.moves, fen.clone(), GameVariant::Standard)?;
+    Ok((tree, fen))
+}
+
 #[tauri::command]
 #[specta::specta]
 pub async fn delete_db_game(
@@ -1773,11 +2065,16 @@ pub async fn delete_db_game(
 ) -> Result<(), Error> {
     let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
 
-    diesel::delete(games::table.filter(games::id.eq(game_id))).execute(db)?;
+    db.transaction::<_, diesel::result::Error, _>(|db| {
+        remove_game_from_tree(db, game_id)?;
+        diesel::delete(games::table.filter(games::id.eq(game_id))).execute(db)?;
+        Ok(())
+    })?;
 
     let game_count: i64 = games::table.count().get_result(db)?;
     update_info_count(db, "GameCount", game_count)?;
     delete_orphaned_data(db)?;
+    forget_searches(&file, &state);
 
     Ok(())
 }
@@ -1826,32 +2123,39 @@ pub async fn write_db_game(
     let pawn_home = get_pawn_home(temp_game.position.board()) as i32;
     let ply_count = iter_mainline_move_bytes(&temp_game.moves).count() as i32;
 
-    let updated_rows = diesel::update(games::table.filter(games::id.eq(game_id)))
-        .set((
-            games::event_id.eq(event_id),
-            games::site_id.eq(site_id),
-            games::date.eq(temp_game.date),
-            games::time.eq(temp_game.time),
-            games::round.eq(temp_game.round),
-            games::white_id.eq(white_id),
-            games::white_elo.eq(temp_game.white_elo),
-            games::black_id.eq(black_id),
-            games::black_elo.eq(temp_game.black_elo),
-            games::white_material.eq(minimal_white_material),
-            games::black_material.eq(minimal_black_material),
-            games::result.eq(temp_game.result),
-            games::time_control.eq(temp_game.time_control),
-            games::eco.eq(temp_game.eco),
-            games::ply_count.eq(ply_count),
-            games::fen.eq(temp_game.fen),
-            games::moves.eq(temp_game.moves),
-            games::pawn_home.eq(pawn_home),
-        ))
-        .execute(db)?;
+    let updated_rows = db.transaction::<_, diesel::result::Error, _>(|db| {
+        remove_game_from_tree(db, game_id)?;
+        let updated_rows = diesel::update(games::table.filter(games::id.eq(game_id)))
+            .set((
+                games::event_id.eq(event_id),
+                games::site_id.eq(site_id),
+                games::date.eq(temp_game.date),
+                games::time.eq(temp_game.time),
+                games::round.eq(temp_game.round),
+                games::white_id.eq(white_id),
+                games::white_elo.eq(temp_game.white_elo),
+                games::black_id.eq(black_id),
+                games::black_elo.eq(temp_game.black_elo),
+                games::white_material.eq(minimal_white_material),
+                games::black_material.eq(minimal_black_material),
+                games::result.eq(temp_game.result),
+                games::time_control.eq(temp_game.time_control),
+                games::eco.eq(temp_game.eco),
+                games::ply_count.eq(ply_count),
+                games::fen.eq(temp_game.fen),
+                games::moves.eq(temp_game.moves),
+                games::pawn_home.eq(pawn_home),
+                games::variant.eq(temp_game.variant.db_name()),
+            ))
+            .execute(db)?;
+        add_game_to_tree(db, game_id)?;
+        Ok(updated_rows)
+    })?;
 
     if updated_rows == 0 {
         return Err(Error::GameNotFound(game_id.to_string()));
     }
+    forget_searches(&file, &state);
 
     Ok(())
 }
@@ -1889,6 +2193,7 @@ pub async fn merge_players(
 
     let player_count: i64 = players::table.count().get_result(db)?;
     update_info_count(db, "PlayerCount", player_count)?;
+    forget_searches(&file, &state);
 
     Ok(())
 }
@@ -1896,8 +2201,8 @@ pub async fn merge_players(
 #[tauri::command]
 #[specta::specta]
 pub fn clear_games(state: tauri::State<'_, AppState>) {
-    let mut state = state.db_cache.lock().unwrap();
-    *state = None;
+    state.db_cache.clear();
+    state.position_indexes.clear();
 }
 
 #[tauri::command]
@@ -1913,13 +2218,12 @@ pub async fn preload_reference_db(
         generate_search_index(&file, &state)?;
     }
 
-    let mut cache = state.db_cache.lock().unwrap();
-    if cache.is_none() {
+    if !state.db_cache.contains_key(&file) {
         info!("Preloading reference database from {:?}", index_path);
         match MmapSearchIndex::open(&index_path) {
             Ok(index) => {
                 info!("Preloaded reference database with {} games", index.len());
-                *cache = Some(index);
+                state.db_cache.insert(file, index);
             }
             Err(e) => {
                 return Err(Error::from(e));
@@ -1935,6 +2239,25 @@ mod tests {
     use super::*;
     use pgn_reader::BufferedReader;
 
+    #[test]
+    fn multi_queries_drop_duplicated_databases() {
+        let mut files = vec![PathBuf::from("a.db3"), PathBuf::from("a.db3")];
+        let query = GameQuery {
+            player1: Some(1),
+            ..Default::default()
+        };
+        // the player ids are those of the only database left
+        assert!(check_multi_query(&mut files, &query).is_ok());
+        assert_eq!(files, vec![PathBuf::from("a.db3")]);
+
+        files.push(PathBuf::from("b.db3"));
+        assert!(matches!(
+            check_multi_query(&mut files, &query),
+            Err(Error::InvalidMultiQuery(_))
+        ));
+        assert!(check_multi_query(&mut files, &GameQuery::default()).is_ok());
+    }
+
     #[test]
     fn home_row() {
         use shakmaty::Board;
@@ -1972,7 +2295,9 @@ mod tests {
             .collect();
 
         assert_eq!(games.len(), 1);
-        let movetext = decode_game_to_movetext(&games[0].moves, Fen::default()).unwrap();
+        let movetext =
+            decode_game_to_movetext(&games[0].moves, Fen::default(), GameVariant::Standard)
+                .unwrap();
 
         assert_eq!(movetext, "1. e4 (1. d4 d5 (1... Nf6) {inner}) 1... e5");
     }
@@ -1998,10 +2323,44 @@ mod tests {
             .collect();
 
         assert_eq!(games.len(), 1);
-        let movetext = decode_game_to_movetext(&games[0].moves, Fen::default()).unwrap();
+        let movetext =
+            decode_game_to_movetext(&games[0].moves, Fen::default(), GameVariant::Standard)
+                .unwrap();
         assert_eq!(movetext, "1. e4! (1. d4?) 1... e5!");
     }
 
+    #[test]
+    fn importer_stores_the_variant_header() {
+        let pgn = r#"[Event "T"]
+[Site "S"]
+[White "W"]
+[Black "B"]
+[Result "*"]
+[Variant "Atomic"]
+
+1. e4 d5 2. exd5 Qd5 *
+"#;
+
+        let mut importer = Importer::new(None);
+        let games: Vec<TempGame> = BufferedReader::new(pgn.as_bytes())
+            .into_iter(&mut importer)
+            .flatten()
+            .flatten()
+            .collect();
+        assert_eq!(games.len(), 1);
+        assert_eq!(games[0].variant, GameVariant::Atomic);
+
+        let db = &mut setup_test_db();
+        games[0].insert_to_db(db).unwrap();
+        let game: Game = games::table.first(db).unwrap();
+        assert_eq!(game.variant.as_deref(), GameVariant::Atomic.db_name());
+
+        let variant = GameVariant::from_db_name(game.variant.as_deref());
+        assert_eq!(variant, GameVariant::Atomic);
+        let movetext = decode_game_to_movetext(&game.moves, variant.start_fen(), variant).unwrap();
+        assert_eq!(movetext, "1. e4 d5 2. exd5 Qd5");
+    }
+
     fn setup_test_db() -> SqliteConnection {
         let mut conn = SqliteConnection::establish(":memory:").unwrap();
         conn.batch_execute("PRAGMA foreign_keys = ON;").unwrap();
@@ -2041,6 +2400,7 @@ mod tests {
                 fen: None,
                 moves: &[],
                 pawn_home: 0,
+                variant: None,
             },
         )
         .unwrap();
@@ -2140,6 +2500,7 @@ mod tests {
                     fen: None,
                     moves: &[],
                     pawn_home: 0,
+                    variant: None,
                 },
             )
             .unwrap()
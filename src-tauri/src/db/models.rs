@@ -4,7 +4,7 @@ use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
 use specta::Type;
 
-use crate::db::schema::*;
+use crate::{db::schema::*, variant::GameVariant};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Queryable, Identifiable, Type)]
 #[diesel(table_name = puzzles)]
@@ -62,6 +62,7 @@ pub struct Game {
     pub fen: Option<String>,
     pub moves: Vec<u8>,
     pub pawn_home: i32,
+    pub variant: Option<String>,
 }
 
 #[derive(Insertable, Debug)]
@@ -85,6 +86,7 @@ pub struct NewGame<'a> {
     pub fen: Option<&'a str>,
     pub moves: &'a [u8],
     pub pawn_home: i32,
+    pub variant: Option<&'a str>,
 }
 
 #[derive(Default, Debug, Queryable, Serialize, Deserialize, Identifiable, Clone)]
@@ -174,4 +176,6 @@ pub struct NormalizedGame {
     #[specta(optional)]
     pub ply_count: Option<i32>,
     pub moves: String,
+    #[serde(default)]
+    pub variant: GameVariant,
 }
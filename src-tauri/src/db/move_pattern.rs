@@ -0,0 +1,435 @@
+use serde::Deserialize;
+use shakmaty::{san::SanPlus, Color, Move, Position, Role, Square};
+use specta::Type;
+
+use crate::{
+    chess::piece_value,
+    db::{
+        encoding::{decode_move, iter_mainline_move_bytes},
+        search::{game_start_position, ColorJs},
+    },
+    error::Error,
+    variant::GameVariant,
+};
+
+#[derive(Debug, Clone, Default, Deserialize, Type, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct MoveConstraintJs {
+    /// Piece that moves: "K", "Q", "R", "B", "N" or "P".
+    #[serde(default)]
+    #[specta(optional)]
+    pub piece: Option<String>,
+    #[serde(default)]
+    #[specta(optional)]
+    pub color: Option<ColorJs>,
+    /// Target square, e.g. "h7". Castling moves go to the king's square.
+    #[serde(default)]
+    #[specta(optional)]
+    pub to: Option<String>,
+    #[serde(default)]
+    #[specta(optional)]
+    pub capture: Option<bool>,
+    #[serde(default)]
+    #[specta(optional)]
+    pub check: Option<bool>,
+    /// Piece promoted to, "Q", "R", "B" or "N", or "any".
+    #[serde(default)]
+    #[specta(optional)]
+    pub promotion: Option<String>,
+    /// The piece is captured right away and was worth more than what it
+    /// took, e.g. Qxf7+ Kxf7.
+    #[serde(default)]
+    #[specta(optional)]
+    pub sacrifice: bool,
+}
+
+/// How the moves of a pattern follow each other.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Type, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum MoveOrder {
+    /// One move after the other.
+    #[default]
+    Consecutive,
+    /// In this order, with other moves in between.
+    InOrder,
+    /// In any order.
+    AnyOrder,
+}
+
+#[derive(Debug, Clone, Deserialize, Type, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct MovePatternQueryJs {
+    pub moves: Vec<MoveConstraintJs>,
+    #[serde(default)]
+    #[specta(optional)]
+    pub order: MoveOrder,
+}
+
+/// A move of a game, as seen by the constraints.
+#[derive(Debug, Clone, Copy)]
+struct PlayedMove {
+    role: Role,
+    color: Color,
+    to: Square,
+    capture: bool,
+    check: bool,
+    promotion: Option<Role>,
+    captured: Option<Role>,
+    sacrificed: bool,
+}
+
+impl PlayedMove {
+    /// Whether `reply` takes the piece that just moved, giving up more than
+    /// it won.
+    fn is_sacrificed_by(&self, reply: &Move) -> bool {
+        let given = piece_value(self.promotion.unwrap_or(self.role));
+        let won = self.captured.map_or(0, piece_value);
+        reply.is_capture() && reply.to() == self.to && given > won
+    }
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+struct MoveConstraint {
+    role: Option<Role>,
+    color: Option<Color>,
+    to: Option<Square>,
+    capture: Option<bool>,
+    check: Option<bool>,
+    /// `Some(None)` for any promotion.
+    promotion: Option<Option<Role>>,
+    sacrifice: bool,
+}
+
+impl MoveConstraint {
+    fn holds(&self, m: &PlayedMove) -> bool {
+        let promotion = match self.promotion {
+            None => true,
+            Some(None) => m.promotion.is_some(),
+            Some(wanted) => wanted == m.promotion,
+        };
+        self.role.is_none_or(|role| role == m.role)
+            && self.color.is_none_or(|color| color == m.color)
+            && self.to.is_none_or(|to| to == m.to)
+            && self.capture.is_none_or(|capture| capture == m.capture)
+            && self.check.is_none_or(|check| check == m.check)
+            && promotion
+            && (!self.sacrifice || m.sacrificed)
+    }
+}
+
+fn parse_role(role: &str) -> Result<Role, Error> {
+    let mut chars = role.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Role::from_char(c.to_ascii_lowercase()),
+        _ => None,
+    }
+    .ok_or_else(|| Error::InvalidPattern(format!("unknown piece {role}")))
+}
+
+impl TryFrom<&MoveConstraintJs> for MoveConstraint {
+    type Error = Error;
+
+    fn try_from(constraint: &MoveConstraintJs) -> Result<Self, Error> {
+        let to = constraint
+            .to
+            .as_ref()
+            .map(|square| {
+                Square::from_ascii(square.as_bytes())
+                    .map_err(|_| Error::InvalidPattern(format!("unknown square {square}")))
+            })
+            .transpose()?;
+        let promotion = match constraint.promotion.as_deref() {
+            None => None,
+            Some("any") => Some(None),
+            Some(role) => Some(Some(parse_role(role)?)),
+        };
+        Ok(Self {
+            role: constraint.piece.as_deref().map(parse_role).transpose()?,
+            color: constraint.color.map(Color::from),
+            to,
+            capture: constraint.capture,
+            check: constraint.check,
+            promotion,
+            sacrifice: constraint.sacrifice,
+        })
+    }
+}
+
+/// Moves of a game matching a `MovePatternData`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MovePatternMatch {
+    /// Plies of the matching moves, the first move of the game being ply 1.
+    pub plies: Vec<u16>,
+    /// First matching move and the side that played it.
+    pub san: String,
+    pub turn: Color,
+}
+
+/// Moves a game has to contain, see `MovePatternQueryJs`.
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+pub struct MovePatternData {
+    constraints: Vec<MoveConstraint>,
+    order: MoveOrder,
+    /// Whether playing the moves has to look for checks, which is slow.
+    needs_checks: bool,
+}
+
+/// Gives `constraint` a move, moving the constraints holding its candidates
+/// to other moves if needed.
+fn augment(
+    constraint: usize,
+    candidates: &[Vec<usize>],
+    owners: &mut [Option<usize>],
+    visited: &mut [bool],
+) -> bool {
+    for &i in &candidates[constraint] {
+        if visited[i] {
+            continue;
+        }
+        visited[i] = true;
+        if owners[i].is_none_or(|owner| augment(owner, candidates, owners, visited)) {
+            owners[i] = Some(constraint);
+            return true;
+        }
+    }
+    false
+}
+
+/// Finds distinct moves for every constraint, as a bipartite matching of the
+/// constraints to the moves they hold for.
+fn assign_moves(constraints: &[MoveConstraint], moves: &[PlayedMove]) -> Option<Vec<usize>> {
+    let candidates: Vec<Vec<usize>> = constraints
+        .iter()
+        .map(|constraint| {
+            moves
+                .iter()
+                .enumerate()
+                .filter(|(_, m)| constraint.holds(m))
+                .map(|(i, _)| i)
+                .collect()
+        })
+        .collect();
+    let mut owners = vec![None; moves.len()];
+    for constraint in 0..constraints.len() {
+        let mut visited = vec![false; moves.len()];
+        if !augment(constraint, &candidates, &mut owners, &mut visited) {
+            return None;
+        }
+    }
+    Some(
+        owners
+            .iter()
+            .enumerate()
+            .filter(|(_, owner)| owner.is_some())
+            .map(|(i, _)| i)
+            .collect(),
+    )
+}
+
+impl MovePatternData {
+    /// Indices of the moves matching the constraints, in game order.
+    fn find(&self, moves: &[PlayedMove]) -> Option<Vec<usize>> {
+        let n = self.constraints.len();
+        match self.order {
+            MoveOrder::Consecutive => moves
+                .windows(n)
+                .position(|window| {
+                    window
+                        .iter()
+                        .zip(&self.constraints)
+                        .all(|(m, constraint)| constraint.holds(m))
+                })
+                .map(|start| (start..start + n).collect()),
+            MoveOrder::InOrder => {
+                let mut found = Vec::with_capacity(n);
+                let mut next = 0;
+                for constraint in &self.constraints {
+                    let i = next + moves[next..].iter().position(|m| constraint.holds(m))?;
+                    found.push(i);
+                    next = i + 1;
+                }
+                Some(found)
+            }
+            MoveOrder::AnyOrder => assign_moves(&self.constraints, moves),
+        }
+    }
+
+    pub fn find_in_game(
+        &self,
+        move_blob: &[u8],
+        fen: &Option<&str>,
+        variant: GameVariant,
+    ) -> Result<Option<MovePatternMatch>, Error> {
+        let start = game_start_position(fen, variant)?;
+        let mut position = start.clone();
+        let mut moves: Vec<Move> = Vec::new();
+        let mut played: Vec<PlayedMove> = Vec::new();
+        for byte in iter_mainline_move_bytes(move_blob) {
+            let Some(m) = decode_move(byte, &position) else {
+                break;
+            };
+            let color = position.turn();
+            let to = match m.castling_side() {
+                Some(side) => side.king_to(color),
+                None => m.to(),
+            };
+            position.play_unchecked(&m);
+
+            if let Some(previous) = played.last_mut() {
+                previous.sacrificed = previous.is_sacrificed_by(&m);
+            }
+            played.push(PlayedMove {
+                role: m.role(),
+                color,
+                to,
+                capture: m.is_capture(),
+                check: self.needs_checks && position.is_check(),
+                promotion: m.promotion(),
+                captured: m.capture(),
+                sacrificed: false,
+            });
+            moves.push(m);
+        }
+
+        let Some(found) = self.find(&played) else {
+            return Ok(None);
+        };
+        let first = found[0];
+        let mut position = start;
+        for m in &moves[..first] {
+            position.play_unchecked(m);
+        }
+        let turn = position.turn();
+        Ok(Some(MovePatternMatch {
+            plies: found.iter().map(|&i| (i + 1) as u16).collect(),
+            san: SanPlus::from_move(position, &moves[first]).to_string(),
+            turn,
+        }))
+    }
+}
+
+impl TryFrom<&MovePatternQueryJs> for MovePatternData {
+    type Error = Error;
+
+    fn try_from(pattern: &MovePatternQueryJs) -> Result<Self, Error> {
+        if pattern.moves.is_empty() {
+            return Err(Error::InvalidPattern("empty move pattern".to_string()));
+        }
+        let constraints: Vec<MoveConstraint> = pattern
+            .moves
+            .iter()
+            .map(MoveConstraint::try_from)
+            .collect::<Result<_, Error>>()?;
+        Ok(Self {
+            needs_checks: constraints.iter().any(|c| c.check.is_some()),
+            constraints,
+            order: pattern.order,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::encoding::encode_sans;
+    use shakmaty::Chess;
+
+    fn find(pattern: &MovePatternQueryJs, sans: &[&str]) -> Option<MovePatternMatch> {
+        let game = encode_sans(&mut Chess::default(), sans);
+        MovePatternData::try_from(pattern)
+            .unwrap()
+            .find_in_game(&game, &None, GameVariant::Standard)
+            .unwrap()
+    }
+
+    const SCHOLARS_MATE: [&str; 7] = ["e4", "e5", "Bc4", "Nc6", "Qh5", "Nf6", "Qxf7#"];
+
+    #[test]
+    fn finds_checking_captures() {
+        let pattern = MovePatternQueryJs {
+            moves: vec![MoveConstraintJs {
+                piece: Some("Q".to_string()),
+                to: Some("f7".to_string()),
+                capture: Some(true),
+                check: Some(true),
+                ..Default::default()
+            }],
+            order: MoveOrder::Consecutive,
+        };
+        let found = find(&pattern, &SCHOLARS_MATE).unwrap();
+        assert_eq!(found.plies, vec![7]);
+        assert_eq!((found.san.as_str(), found.turn), ("Qxf7#", Color::White));
+
+        let sacrifice = MovePatternQueryJs {
+            moves: vec![MoveConstraintJs {
+                sacrifice: true,
+                ..pattern.moves[0].clone()
+            }],
+            order: MoveOrder::Consecutive,
+        };
+        assert_eq!(find(&sacrifice, &SCHOLARS_MATE), None);
+        let found = find(&sacrifice, &["e4", "e5", "Qh5", "Nc6", "Qxf7+", "Kxf7"]).unwrap();
+        assert_eq!(found.plies, vec![5]);
+
+        // trading a bishop for a knight gives nothing up
+        let exchange = MovePatternQueryJs {
+            moves: vec![MoveConstraintJs {
+                piece: Some("B".to_string()),
+                sacrifice: true,
+                ..Default::default()
+            }],
+            order: MoveOrder::Consecutive,
+        };
+        let ruy_lopez = ["e4", "e5", "Nf3", "Nc6", "Bb5", "a6", "Bxc6", "dxc6"];
+        assert_eq!(find(&exchange, &ruy_lopez), None);
+    }
+
+    #[test]
+    fn finds_sequences_in_any_order() {
+        let knight = MoveConstraintJs {
+            piece: Some("N".to_string()),
+            color: Some(ColorJs::White),
+            ..Default::default()
+        };
+        let castles = MoveConstraintJs {
+            piece: Some("K".to_string()),
+            to: Some("g1".to_string()),
+            ..Default::default()
+        };
+        let moves = ["Nf3", "d5", "g3", "Nf6", "Bg2", "e6", "O-O", "Be7", "Nc3"];
+
+        let pattern = |order| MovePatternQueryJs {
+            moves: vec![castles.clone(), knight.clone()],
+            order,
+        };
+        assert_eq!(find(&pattern(MoveOrder::Consecutive), &moves), None);
+        assert_eq!(
+            find(&pattern(MoveOrder::InOrder), &moves).unwrap().plies,
+            vec![7, 9]
+        );
+        let found = find(&pattern(MoveOrder::AnyOrder), &moves).unwrap();
+        assert_eq!(found.plies, vec![1, 7]);
+        assert_eq!(found.san, "Nf3");
+    }
+
+    #[test]
+    fn unmatched_patterns_fail_fast_in_any_order() {
+        let white = MoveConstraintJs {
+            color: Some(ColorJs::White),
+            ..Default::default()
+        };
+        let mut moves = vec![white; 12];
+        moves.push(MoveConstraintJs {
+            piece: Some("Q".to_string()),
+            to: Some("a8".to_string()),
+            ..Default::default()
+        });
+        let pattern = MovePatternQueryJs {
+            moves,
+            order: MoveOrder::AnyOrder,
+        };
+        // trying every assignment of the 12 white moves would never end
+        let knights = ["Nf3", "Nf6", "Ng1", "Ng8"].repeat(10);
+        assert_eq!(find(&pattern, &knights), None);
+    }
+}
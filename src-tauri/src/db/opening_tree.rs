@@ -0,0 +1,545 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use diesel::{
+    prelude::*,
+    sql_query,
+    sql_types::{BigInt, Nullable, Text},
+    sqlite::Sqlite,
+};
+use serde::Serialize;
+use shakmaty::{fen::Fen, san::SanPlus, CastlingMode, Chess, Color, FromSetup, Position};
+use specta::Type;
+
+use crate::{
+    db::{
+        encoding::{decode_move, iter_mainline_move_bytes},
+        get_db_or_create,
+        position_index::board_hash,
+        schema::*,
+        search::performance_rating,
+        update_info_count, ConnectionOptions,
+    },
+    error::Error,
+    AppState,
+};
+
+const OPENING_TREE_SQL: &str = include_str!("opening_tree.sql");
+
+const UPSERT_INSERT: &str = "
+    INSERT INTO OpeningTree (
+        Position, Move, White, Draw, Black,
+        RatedGames, EloSum, OpponentEloSum, RatedScore, LastPlayed
+    )
+    VALUES";
+const UPSERT_ROW: &str = "(?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+const UPSERT_CONFLICT: &str = "
+    ON CONFLICT (Position, Move) DO UPDATE SET
+        White = White + excluded.White,
+        Draw = Draw + excluded.Draw,
+        Black = Black + excluded.Black,
+        RatedGames = RatedGames + excluded.RatedGames,
+        EloSum = EloSum + excluded.EloSum,
+        OpponentEloSum = OpponentEloSum + excluded.OpponentEloSum,
+        RatedScore = RatedScore + excluded.RatedScore,
+        LastPlayed = CASE
+            WHEN LastPlayed IS NULL OR excluded.LastPlayed > LastPlayed THEN excluded.LastPlayed
+            ELSE LastPlayed
+        END;
+";
+
+/// Plies of every game added to the tree of a new database.
+pub const DEFAULT_OPENING_TREE_DEPTH: u16 = 20;
+
+/// Info entry holding the depth of the tree, missing if it was never built.
+const DEPTH_INFO: &str = "OpeningTreeDepth";
+
+/// Number of moves aggregated in memory before they are written.
+const FLUSH_THRESHOLD: usize = 200_000;
+
+/// Moves written per statement, SQLite allows 32766 bound values.
+const UPSERT_ROWS: usize = 1000;
+
+const GAMES_PER_BATCH: i64 = 10_000;
+
+/// Statistics of a move, the Elo sums and score are from the point of view
+/// of the player making it and only cover games where both players are rated.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct MoveStats {
+    white: i64,
+    draw: i64,
+    black: i64,
+    rated_games: i64,
+    elo_sum: i64,
+    opponent_elo_sum: i64,
+    /// Score in half points.
+    rated_score: i64,
+    last_played: Option<String>,
+}
+
+/// Fields of a game the tree is built from.
+pub struct TreeGame<'a> {
+    pub fen: Option<&'a str>,
+    pub moves: &'a [u8],
+    pub result: Option<&'a str>,
+    pub white_elo: Option<i32>,
+    pub black_elo: Option<i32>,
+    pub date: Option<&'a str>,
+}
+
+/// Aggregates the first moves of standard games before adding them to the
+/// `OpeningTree` table.
+pub struct OpeningTreeBuilder {
+    depth: usize,
+    moves: HashMap<(i64, String), MoveStats>,
+}
+
+impl OpeningTreeBuilder {
+    pub fn new(depth: u16) -> Self {
+        Self {
+            depth: depth as usize,
+            moves: HashMap::new(),
+        }
+    }
+
+    pub fn add_game(&mut self, game: TreeGame<'_>) {
+        self.record(game, 1);
+    }
+
+    /// Takes back a game added before. Its date is kept as the last played
+    /// date of its moves until the tree is built again.
+    pub fn remove_game(&mut self, game: TreeGame<'_>) {
+        self.record(game, -1);
+    }
+
+    /// Adds the game's moves to the statistics, `sign` times.
+    fn record(&mut self, game: TreeGame<'_>, sign: i64) {
+        let mut chess = match game.fen {
+            Some(fen) => {
+                let Ok(fen) = Fen::from_ascii(fen.as_bytes()) else {
+                    return;
+                };
+                let setup = fen.into_setup();
+                let castling_mode = CastlingMode::detect(&setup);
+                match Chess::from_setup(setup, castling_mode) {
+                    Ok(chess) => chess,
+                    Err(_) => return,
+                }
+            }
+            None => Chess::default(),
+        };
+
+        // White's score in half points
+        let white_score = match game.result {
+            Some("1-0") => Some(2),
+            Some("0-1") => Some(0),
+            Some("1/2-1/2") => Some(1),
+            _ => None,
+        };
+        let rated = match (game.white_elo, game.black_elo, white_score) {
+            (Some(white_elo), Some(black_elo), Some(score)) if white_elo > 0 && black_elo > 0 => {
+                Some((white_elo as i64, black_elo as i64, score))
+            }
+            _ => None,
+        };
+        let date = game.date.filter(|date| !date.starts_with('?'));
+
+        for byte in iter_mainline_move_bytes(game.moves).take(self.depth) {
+            let Some(m) = decode_move(byte, &chess) else {
+                break;
+            };
+            let turn = chess.turn();
+            let hash = board_hash(chess.board(), turn) as i64;
+            let san = SanPlus::from_move_and_play_unchecked(&mut chess, &m).to_string();
+
+            let stats = self.moves.entry((hash, san)).or_default();
+            match white_score {
+                Some(2) => stats.white += sign,
+                Some(0) => stats.black += sign,
+                _ => stats.draw += sign,
+            }
+            if let Some((white_elo, black_elo, white_score)) = rated {
+                let (elo, opponent_elo, score) = match turn {
+                    Color::White => (white_elo, black_elo, white_score),
+                    Color::Black => (black_elo, white_elo, 2 - white_score),
+                };
+                stats.rated_games += sign;
+                stats.elo_sum += sign * elo;
+                stats.opponent_elo_sum += sign * opponent_elo;
+                stats.rated_score += sign * score;
+            }
+            if let Some(date) = date.filter(|_| sign > 0) {
+                if stats.last_played.as_deref().is_none_or(|last| date > last) {
+                    stats.last_played = Some(date.to_string());
+                }
+            }
+        }
+    }
+
+    /// Writes the aggregated moves once enough of them are in memory.
+    pub fn flush_if_full(&mut self, db: &mut SqliteConnection) -> QueryResult<()> {
+        if self.moves.len() >= FLUSH_THRESHOLD {
+            self.flush(db)?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self, db: &mut SqliteConnection) -> QueryResult<()> {
+        let moves: Vec<_> = self.moves.drain().collect();
+        db.transaction(|db| {
+            for chunk in moves.chunks(UPSERT_ROWS) {
+                let rows = vec![UPSERT_ROW; chunk.len()].join(", ");
+                let mut query = sql_query(format!("{UPSERT_INSERT} {rows} {UPSERT_CONFLICT}"))
+                    .into_boxed::<Sqlite>();
+                for ((position, move_), stats) in chunk {
+                    query = query
+                        .bind::<BigInt, _>(*position)
+                        .bind::<Text, _>(move_.clone())
+                        .bind::<BigInt, _>(stats.white)
+                        .bind::<BigInt, _>(stats.draw)
+                        .bind::<BigInt, _>(stats.black)
+                        .bind::<BigInt, _>(stats.rated_games)
+                        .bind::<BigInt, _>(stats.elo_sum)
+                        .bind::<BigInt, _>(stats.opponent_elo_sum)
+                        .bind::<BigInt, _>(stats.rated_score)
+                        .bind::<Nullable<Text>, _>(stats.last_played.clone());
+                }
+                query.execute(db)?;
+            }
+            // moves whose games were all removed
+            for ((position, move_), _) in moves
+                .iter()
+                .filter(|(_, stats)| stats.white + stats.draw + stats.black < 0)
+            {
+                sql_query(
+                    "DELETE FROM OpeningTree
+                    WHERE Position = ? AND Move = ? AND White + Draw + Black <= 0",
+                )
+                .bind::<BigInt, _>(*position)
+                .bind::<Text, _>(move_)
+                .execute(db)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Creates an empty tree that is filled up to `depth` plies on import.
+pub fn create_opening_tree(db: &mut SqliteConnection, depth: u16) -> QueryResult<()> {
+    db.batch_execute(OPENING_TREE_SQL)?;
+    db.batch_execute("DELETE FROM OpeningTree;")?;
+    update_info_count(db, DEPTH_INFO, depth as i64)
+}
+
+/// Adds a stored game to the tree, if the database has one.
+pub fn add_game_to_tree(db: &mut SqliteConnection, game_id: i32) -> QueryResult<()> {
+    update_tree_with_game(db, game_id, OpeningTreeBuilder::add_game)
+}
+
+/// Takes a stored game out of the tree, before it is deleted or replaced.
+pub fn remove_game_from_tree(db: &mut SqliteConnection, game_id: i32) -> QueryResult<()> {
+    update_tree_with_game(db, game_id, OpeningTreeBuilder::remove_game)
+}
+
+fn update_tree_with_game(
+    db: &mut SqliteConnection,
+    game_id: i32,
+    update: fn(&mut OpeningTreeBuilder, TreeGame<'_>),
+) -> QueryResult<()> {
+    let Some(depth) = opening_tree_depth(db)? else {
+        return Ok(());
+    };
+    let game: Option<(
+        Option<String>,
+        Vec<u8>,
+        Option<String>,
+        Option<i32>,
+        Option<i32>,
+        Option<String>,
+    )> = games::table
+        .select((
+            games::fen,
+            games::moves,
+            games::result,
+            games::white_elo,
+            games::black_elo,
+            games::date,
+        ))
+        .filter(games::id.eq(game_id))
+        .filter(games::variant.is_null())
+        .first(db)
+        .optional()?;
+    let Some((fen, moves, result, white_elo, black_elo, date)) = game else {
+        return Ok(());
+    };
+
+    let mut builder = OpeningTreeBuilder::new(depth);
+    update(
+        &mut builder,
+        TreeGame {
+            fen: fen.as_deref(),
+            moves: &moves,
+            result: result.as_deref(),
+            white_elo,
+            black_elo,
+            date: date.as_deref(),
+        },
+    );
+    builder.flush(db)
+}
+
+/// Depth of the tree, `None` if the database has none.
+pub fn opening_tree_depth(db: &mut SqliteConnection) -> QueryResult<Option<u16>> {
+    let depth: Option<Option<String>> = info::table
+        .filter(info::name.eq(DEPTH_INFO))
+        .select(info::value)
+        .first(db)
+        .optional()?;
+    Ok(depth.flatten().and_then(|depth| depth.parse().ok()))
+}
+
+#[derive(Debug, Clone, Serialize, Type, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OpeningTreeMove {
+    #[serde(rename = "move")]
+    pub move_: String,
+    pub white: i32,
+    pub draw: i32,
+    pub black: i32,
+    /// Average Elo of the players who made the move.
+    pub average_elo: Option<i32>,
+    pub average_opponent_elo: Option<i32>,
+    /// Performance rating of the players who made the move.
+    pub performance: Option<i32>,
+    pub last_played: Option<String>,
+}
+
+#[derive(QueryableByName)]
+struct OpeningTreeRow {
+    #[diesel(sql_type = Text)]
+    move_: String,
+    #[diesel(sql_type = BigInt)]
+    white: i64,
+    #[diesel(sql_type = BigInt)]
+    draw: i64,
+    #[diesel(sql_type = BigInt)]
+    black: i64,
+    #[diesel(sql_type = BigInt)]
+    rated_games: i64,
+    #[diesel(sql_type = BigInt)]
+    elo_sum: i64,
+    #[diesel(sql_type = BigInt)]
+    opponent_elo_sum: i64,
+    #[diesel(sql_type = BigInt)]
+    rated_score: i64,
+    #[diesel(sql_type = Nullable<Text>)]
+    last_played: Option<String>,
+}
+
+impl From<OpeningTreeRow> for OpeningTreeMove {
+    fn from(row: OpeningTreeRow) -> Self {
+        let rated = (row.rated_games > 0).then_some(row.rated_games);
+        let average_opponent_elo = rated.map(|n| row.opponent_elo_sum / n);
+        Self {
+            move_: row.move_,
+            white: row.white as i32,
+            draw: row.draw as i32,
+            black: row.black as i32,
+            average_elo: rated.map(|n| (row.elo_sum / n) as i32),
+            average_opponent_elo: average_opponent_elo.map(|elo| elo as i32),
+            performance: rated
+                .zip(average_opponent_elo)
+                .map(|(n, elo)| performance_rating(elo, row.rated_score, n) as i32),
+            last_played: row.last_played,
+        }
+    }
+}
+
+fn get_tree_moves(db: &mut SqliteConnection, hash: u64) -> QueryResult<Vec<OpeningTreeMove>> {
+    let rows: Vec<OpeningTreeRow> = sql_query(
+        "SELECT Move AS move_, White AS white, Draw AS draw, Black AS black,
+            RatedGames AS rated_games, EloSum AS elo_sum, OpponentEloSum AS opponent_elo_sum,
+            RatedScore AS rated_score, LastPlayed AS last_played
+        FROM OpeningTree WHERE Position = ?
+        ORDER BY White + Draw + Black DESC",
+    )
+    .bind::<BigInt, _>(hash as i64)
+    .load(db)?;
+    Ok(rows.into_iter().map(OpeningTreeMove::from).collect())
+}
+
+/// Moves played in the position according to the opening tree. Returns
+/// `None` if the database has no tree or the position is deeper than it.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_opening_tree(
+    file: PathBuf,
+    fen: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<Option<Vec<OpeningTreeMove>>, Error> {
+    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+    let Some(depth) = opening_tree_depth(db)? else {
+        return Ok(None);
+    };
+
+    let setup = Fen::from_ascii(fen.as_bytes())?.into_setup();
+    let ply = (setup.fullmoves.get() - 1) * 2 + u32::from(setup.turn == Color::Black);
+    if ply >= u32::from(depth) {
+        return Ok(None);
+    }
+    let castling_mode = CastlingMode::detect(&setup);
+    let chess = Chess::from_setup(setup, castling_mode)?;
+
+    Ok(Some(get_tree_moves(
+        db,
+        board_hash(chess.board(), chess.turn()),
+    )?))
+}
+
+/// Builds the opening tree again from all the standard games, e.g. to
+/// change its depth. Edited and deleted games are kept in sync already, only
+/// the last played dates of their moves are not.
+#[tauri::command]
+#[specta::specta]
+pub async fn build_opening_tree(
+    file: PathBuf,
+    depth: u16,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+
+    db.transaction::<_, Error, _>(|db| {
+        create_opening_tree(db, depth)?;
+
+        let mut builder = OpeningTreeBuilder::new(depth);
+        let mut last_id = 0;
+        loop {
+            let games: Vec<(
+                i32,
+                Option<String>,
+                Vec<u8>,
+                Option<String>,
+                Option<i32>,
+                Option<i32>,
+                Option<String>,
+            )> = games::table
+                .select((
+                    games::id,
+                    games::fen,
+                    games::moves,
+                    games::result,
+                    games::white_elo,
+                    games::black_elo,
+                    games::date,
+                ))
+                .filter(games::id.gt(last_id))
+                .filter(games::variant.is_null())
+                .order(games::id)
+                .limit(GAMES_PER_BATCH)
+                .load(db)?;
+            let Some(last) = games.last() else {
+                break;
+            };
+            last_id = last.0;
+
+            for (_, fen, moves, result, white_elo, black_elo, date) in &games {
+                builder.add_game(TreeGame {
+                    fen: fen.as_deref(),
+                    moves,
+                    result: result.as_deref(),
+                    white_elo: *white_elo,
+                    black_elo: *black_elo,
+                    date: date.as_deref(),
+                });
+            }
+            builder.flush_if_full(db)?;
+        }
+        builder.flush(db)?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::encoding::encode_sans;
+
+    fn game<'a>(moves: &'a [u8], result: &'a str, elos: (i32, i32), date: &'a str) -> TreeGame<'a> {
+        TreeGame {
+            fen: None,
+            moves,
+            result: Some(result),
+            white_elo: Some(elos.0),
+            black_elo: Some(elos.1),
+            date: Some(date),
+        }
+    }
+
+    fn setup_test_db() -> SqliteConnection {
+        let mut db = SqliteConnection::establish(":memory:").unwrap();
+        db.batch_execute(crate::db::CREATE_TABLES_SQL).unwrap();
+        db
+    }
+
+    #[test]
+    fn aggregates_moves_across_imports() {
+        let db = &mut setup_test_db();
+        create_opening_tree(db, 2).unwrap();
+        assert_eq!(opening_tree_depth(db).unwrap(), Some(2));
+
+        let e4_e5 = encode_sans(&mut Chess::default(), &["e4", "e5", "Nf3"]);
+        let e4_c5 = encode_sans(&mut Chess::default(), &["e4", "c5"]);
+        let d4 = encode_sans(&mut Chess::default(), &["d4"]);
+
+        let mut builder = OpeningTreeBuilder::new(2);
+        builder.add_game(game(&e4_e5, "1-0", (2400, 2200), "2020.01.01"));
+        builder.add_game(game(&d4, "1/2-1/2", (2000, 2000), "2021.01.01"));
+        builder.flush(db).unwrap();
+
+        // a later import adds to the same moves
+        builder.add_game(game(&e4_c5, "0-1", (2200, 2400), "2022.??.??"));
+        builder.flush(db).unwrap();
+
+        let start = Chess::default();
+        let moves = get_tree_moves(db, board_hash(start.board(), start.turn())).unwrap();
+        assert_eq!(
+            moves[0],
+            OpeningTreeMove {
+                move_: "e4".to_string(),
+                white: 1,
+                draw: 0,
+                black: 1,
+                average_elo: Some(2300),
+                average_opponent_elo: Some(2300),
+                performance: Some(2300),
+                last_played: Some("2022.??.??".to_string()),
+            }
+        );
+        assert_eq!(moves[1].move_, "d4");
+        assert_eq!(moves[1].performance, Some(2000));
+
+        // Black's point of view after 1. e4
+        let mut chess = Chess::default();
+        encode_sans(&mut chess, &["e4"]);
+        let moves = get_tree_moves(db, board_hash(chess.board(), chess.turn())).unwrap();
+        let c5 = moves.iter().find(|m| m.move_ == "c5").unwrap();
+        assert_eq!(c5.average_elo, Some(2400));
+        assert_eq!(c5.performance, Some(2600));
+        let e5 = moves.iter().find(|m| m.move_ == "e5").unwrap();
+        assert_eq!(e5.performance, Some(2000));
+
+        // moves past the depth are left out
+        encode_sans(&mut chess, &["e5"]);
+        assert!(get_tree_moves(db, board_hash(chess.board(), chess.turn()))
+            .unwrap()
+            .is_empty());
+
+        // removing a game takes its moves back out
+        builder.remove_game(game(&d4, "1/2-1/2", (2000, 2000), "2021.01.01"));
+        builder.remove_game(game(&e4_c5, "0-1", (2200, 2400), "2022.??.??"));
+        builder.flush(db).unwrap();
+        let moves = get_tree_moves(db, board_hash(start.board(), start.turn())).unwrap();
+        assert_eq!(moves.len(), 1);
+        assert_eq!((moves[0].white, moves[0].black), (1, 0));
+        assert_eq!(moves[0].performance, Some(2600));
+    }
+}
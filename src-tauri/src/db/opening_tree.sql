@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS OpeningTree (
+    Position INTEGER NOT NULL,
+    Move TEXT NOT NULL,
+    White INTEGER NOT NULL,
+    Draw INTEGER NOT NULL,
+    Black INTEGER NOT NULL,
+    RatedGames INTEGER NOT NULL,
+    EloSum INTEGER NOT NULL,
+    OpponentEloSum INTEGER NOT NULL,
+    RatedScore INTEGER NOT NULL,
+    LastPlayed TEXT,
+    PRIMARY KEY (Position, Move)
+) WITHOUT ROWID;
@@ -0,0 +1,269 @@
+use serde::Deserialize;
+use shakmaty::{Bitboard, Board, Color, Piece, Square};
+use specta::Type;
+
+use crate::{
+    db::search::{is_contained, ColorJs},
+    error::Error,
+};
+
+/// What the squares of a constraint hold.
+#[derive(Debug, Clone, Deserialize, Type, PartialEq, Eq, Hash)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum SquareContentJs {
+    /// Any of these pieces, in FEN notation, e.g. `"bn"` for a black minor
+    /// piece.
+    Pieces {
+        pieces: String,
+    },
+    AnyPiece,
+    Empty,
+}
+
+#[derive(Debug, Clone, Deserialize, Type, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct SquareConstraintJs {
+    /// Squares the constraint applies to, the whole board if empty.
+    pub squares: Vec<String>,
+    pub content: SquareContentJs,
+    /// Only one of the squares has to hold the content.
+    #[serde(default)]
+    #[specta(optional)]
+    pub any: bool,
+    /// The constraint must not hold, e.g. no black knight on d5.
+    #[serde(default)]
+    #[specta(optional)]
+    pub exclude: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Type, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct PatternQueryJs {
+    pub constraints: Vec<SquareConstraintJs>,
+    /// Side to move, either side if missing.
+    #[serde(default)]
+    #[specta(optional)]
+    pub turn: Option<ColorJs>,
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+enum SquareContent {
+    Pieces(Vec<Piece>),
+    AnyPiece,
+    Empty,
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+struct SquareConstraint {
+    squares: Bitboard,
+    content: SquareContent,
+    any: bool,
+    exclude: bool,
+}
+
+impl SquareConstraint {
+    fn holds(&self, board: &Board) -> bool {
+        let found = match &self.content {
+            SquareContent::Pieces(pieces) => {
+                pieces.iter().fold(Bitboard::EMPTY, |found, &piece| {
+                    found | board.by_piece(piece)
+                })
+            }
+            SquareContent::AnyPiece => board.occupied(),
+            SquareContent::Empty => !board.occupied(),
+        };
+        let holds = if self.any {
+            (found & self.squares).any()
+        } else {
+            is_contained(found, self.squares)
+        };
+        holds != self.exclude
+    }
+
+    fn flipped(&self) -> Self {
+        let content = match &self.content {
+            SquareContent::Pieces(pieces) => SquareContent::Pieces(
+                pieces
+                    .iter()
+                    .map(|piece| Piece {
+                        color: !piece.color,
+                        role: piece.role,
+                    })
+                    .collect(),
+            ),
+            content => content.clone(),
+        };
+        Self {
+            squares: self.squares.flip_vertical(),
+            content,
+            any: self.any,
+            exclude: self.exclude,
+        }
+    }
+}
+
+impl TryFrom<&SquareConstraintJs> for SquareConstraint {
+    type Error = Error;
+
+    fn try_from(constraint: &SquareConstraintJs) -> Result<Self, Error> {
+        let squares = if constraint.squares.is_empty() {
+            Bitboard::FULL
+        } else {
+            constraint
+                .squares
+                .iter()
+                .map(|square| {
+                    Square::from_ascii(square.as_bytes())
+                        .map_err(|_| Error::InvalidPattern(format!("unknown square {square}")))
+                })
+                .collect::<Result<Bitboard, Error>>()?
+        };
+        let content = match &constraint.content {
+            SquareContentJs::Pieces { pieces } => SquareContent::Pieces(
+                pieces
+                    .chars()
+                    .map(|c| {
+                        Piece::from_char(c)
+                            .ok_or_else(|| Error::InvalidPattern(format!("unknown piece {c}")))
+                    })
+                    .collect::<Result<_, Error>>()?,
+            ),
+            SquareContentJs::AnyPiece => SquareContent::AnyPiece,
+            SquareContentJs::Empty => SquareContent::Empty,
+        };
+        Ok(Self {
+            squares,
+            content,
+            any: constraint.any,
+            exclude: constraint.exclude,
+        })
+    }
+}
+
+/// Pattern of squares a position has to match, see `PatternQueryJs`.
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+pub struct PatternData {
+    constraints: Vec<SquareConstraint>,
+    /// Constraints with the colours swapped, if flipped matches are wanted.
+    flipped: Option<Vec<SquareConstraint>>,
+    turn: Option<Color>,
+}
+
+impl PatternData {
+    pub fn matches(&self, board: &Board, turn: Color) -> bool {
+        let matches_as = |turn: Color, constraints: &[SquareConstraint]| {
+            self.turn.is_none_or(|wanted| wanted == turn)
+                && constraints.iter().all(|c| c.holds(board))
+        };
+        matches_as(turn, &self.constraints)
+            || self
+                .flipped
+                .as_ref()
+                .is_some_and(|flipped| matches_as(!turn, flipped))
+    }
+
+    /// Also matches the pattern with the colours swapped and the board
+    /// mirrored.
+    pub fn also_flipped(self) -> Self {
+        let flipped = self
+            .constraints
+            .iter()
+            .map(SquareConstraint::flipped)
+            .collect();
+        Self {
+            flipped: Some(flipped),
+            ..self
+        }
+    }
+}
+
+impl TryFrom<&PatternQueryJs> for PatternData {
+    type Error = Error;
+
+    fn try_from(pattern: &PatternQueryJs) -> Result<Self, Error> {
+        let constraints: Vec<SquareConstraint> = pattern
+            .constraints
+            .iter()
+            .map(SquareConstraint::try_from)
+            .collect::<Result<_, Error>>()?;
+        Ok(Self {
+            constraints,
+            flipped: None,
+            turn: pattern.turn.map(Color::from),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::search::board_from_fen;
+
+    fn constraint(squares: &[&str], content: SquareContentJs) -> SquareConstraintJs {
+        SquareConstraintJs {
+            squares: squares.iter().map(|s| s.to_string()).collect(),
+            content,
+            any: false,
+            exclude: false,
+        }
+    }
+
+    fn pieces(pieces: &str) -> SquareContentJs {
+        SquareContentJs::Pieces {
+            pieces: pieces.to_string(),
+        }
+    }
+
+    #[test]
+    fn knight_outpost_without_minor_pieces() {
+        let pattern = PatternQueryJs {
+            constraints: vec![
+                constraint(&["d5"], pieces("N")),
+                SquareConstraintJs {
+                    exclude: true,
+                    any: true,
+                    ..constraint(&[], pieces("bn"))
+                },
+            ],
+            turn: None,
+        };
+        let pattern = PatternData::try_from(&pattern).unwrap();
+
+        let outpost = board_from_fen("4k3/8/8/3N4/8/8/8/4K3 w - - 0 1");
+        assert!(pattern.matches(&outpost, Color::White));
+        let defended = board_from_fen("4k3/4b3/8/3N4/8/8/8/4K3 w - - 0 1");
+        assert!(!pattern.matches(&defended, Color::White));
+        let mirrored = board_from_fen("4k3/8/8/8/3n4/8/8/4K3 w - - 0 1");
+        assert!(!pattern.matches(&mirrored, Color::White));
+    }
+
+    #[test]
+    fn flipped_patterns_swap_the_side_to_move() {
+        let pattern = PatternQueryJs {
+            constraints: vec![
+                constraint(&["e4"], pieces("P")),
+                SquareConstraintJs {
+                    any: true,
+                    ..constraint(&["d5", "f5"], SquareContentJs::Empty)
+                },
+            ],
+            turn: Some(ColorJs::Black),
+        };
+        let pattern = PatternData::try_from(&pattern).unwrap().also_flipped();
+
+        let white_pawn = board_from_fen("4k3/8/8/3p4/4P3/8/8/4K3 b - - 0 1");
+        assert!(pattern.matches(&white_pawn, Color::Black));
+        assert!(!pattern.matches(&white_pawn, Color::White));
+        let black_pawn = board_from_fen("4k3/8/8/4p3/3P4/8/8/4K3 w - - 0 1");
+        assert!(pattern.matches(&black_pawn, Color::White));
+    }
+
+    #[test]
+    fn rejects_unknown_squares() {
+        let pattern = PatternQueryJs {
+            constraints: vec![constraint(&["z9"], SquareContentJs::AnyPiece)],
+            turn: None,
+        };
+        assert!(PatternData::try_from(&pattern).is_err());
+    }
+}
@@ -0,0 +1,181 @@
+use serde::Deserialize;
+use shakmaty::{fen::Fen, Bitboard, Board, ByColor, Color};
+use specta::Type;
+
+use crate::{
+    db::search::{is_contained, ColorJs},
+    error::Error,
+};
+
+/// How the pawns of a position are compared to the structure.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Type, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum PawnStructureMode {
+    /// Same pawns as the structure, and no others.
+    #[default]
+    Exact,
+    /// At least the pawns of the structure.
+    Contains,
+}
+
+#[derive(Debug, Clone, Deserialize, Type, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct PawnStructureQueryJs {
+    /// Board part of a FEN, only its pawns are used, e.g.
+    /// "8/pp3ppp/2p5/3p4/3P4/4P3/PP3PPP/8" for the Carlsbad structure.
+    pub pawns: String,
+    #[serde(default)]
+    #[specta(optional)]
+    pub mode: PawnStructureMode,
+    /// Only compare the pawns of that side.
+    #[serde(default)]
+    #[specta(optional)]
+    pub color: Option<ColorJs>,
+}
+
+/// Pawns of each side, `None` for a side that is not compared.
+type Skeleton = ByColor<Option<Bitboard>>;
+
+/// Same layout as `get_pawn_home`.
+fn skeleton_home(skeleton: &Skeleton) -> u16 {
+    let white = skeleton.white.map_or(0, |pawns| (pawns.0 >> 8) as u8);
+    let black = skeleton.black.map_or(0, |pawns| (pawns.0 >> 48) as u8);
+    (white as u16) | ((black as u16) << 8)
+}
+
+/// Home squares of the compared sides.
+fn compared_home(skeleton: &Skeleton) -> u16 {
+    let white = if skeleton.white.is_some() { 0x00ff } else { 0 };
+    let black = if skeleton.black.is_some() { 0xff00 } else { 0 };
+    white | black
+}
+
+/// Pawn skeleton a position has to match, see `PawnStructureQueryJs`.
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+pub struct PawnStructureData {
+    /// The structure, and its mirror if flipped matches are wanted.
+    skeletons: Vec<Skeleton>,
+    mode: PawnStructureMode,
+}
+
+impl PawnStructureData {
+    pub fn matches(&self, board: &Board) -> bool {
+        self.skeletons.iter().any(|skeleton| {
+            Color::ALL.into_iter().all(|color| {
+                (*skeleton.get(color)).is_none_or(|wanted| {
+                    let pawns = board.pawns() & board.by_color(color);
+                    match self.mode {
+                        PawnStructureMode::Exact => pawns == wanted,
+                        PawnStructureMode::Contains => is_contained(pawns, wanted),
+                    }
+                })
+            })
+        })
+    }
+
+    /// Pawns never go back to their home squares, so the structure can only
+    /// be reached while its home pawns are still there.
+    pub fn is_reachable_by(&self, pawn_home: u16) -> bool {
+        self.skeletons
+            .iter()
+            .any(|skeleton| skeleton_home(skeleton) & !pawn_home == 0)
+    }
+
+    /// Whether a game ending with these home pawns can go through an exact
+    /// match of the structure.
+    pub fn can_reach(&self, end_pawn_home: u16) -> bool {
+        self.mode == PawnStructureMode::Contains
+            || self.skeletons.iter().any(|skeleton| {
+                end_pawn_home & compared_home(skeleton) & !skeleton_home(skeleton) == 0
+            })
+    }
+
+    /// Also matches the structure with the colours swapped and the board
+    /// mirrored.
+    pub fn also_flipped(mut self) -> Self {
+        let skeleton = self.skeletons[0];
+        self.skeletons.push(ByColor {
+            white: skeleton.black.map(Bitboard::flip_vertical),
+            black: skeleton.white.map(Bitboard::flip_vertical),
+        });
+        self
+    }
+}
+
+impl TryFrom<&PawnStructureQueryJs> for PawnStructureData {
+    type Error = Error;
+
+    fn try_from(query: &PawnStructureQueryJs) -> Result<Self, Error> {
+        let board = Fen::from_ascii(query.pawns.as_bytes())?.into_setup().board;
+        let pawns_of = |color| Some(board.pawns() & board.by_color(color));
+        let skeleton = match query.color {
+            None => ByColor {
+                white: pawns_of(Color::White),
+                black: pawns_of(Color::Black),
+            },
+            Some(ColorJs::White) => ByColor {
+                white: pawns_of(Color::White),
+                black: None,
+            },
+            Some(ColorJs::Black) => ByColor {
+                white: None,
+                black: pawns_of(Color::Black),
+            },
+        };
+        Ok(Self {
+            skeletons: vec![skeleton],
+            mode: query.mode,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{get_pawn_home, search::board_from_fen};
+
+    const CARLSBAD: &str = "8/pp3ppp/2p5/3p4/3P4/4P3/PP3PPP/8";
+    const WHITE_IQP: &str = "8/8/8/8/3P4/8/PP3PPP/8";
+
+    fn query(pawns: &str, mode: PawnStructureMode) -> PawnStructureQueryJs {
+        PawnStructureQueryJs {
+            pawns: pawns.to_string(),
+            mode,
+            color: None,
+        }
+    }
+
+    #[test]
+    fn exact_structures_ignore_pieces() {
+        let data = PawnStructureData::try_from(&query(CARLSBAD, PawnStructureMode::Exact)).unwrap();
+        let hanging_pawns = board_from_fen("r1bq1rk1/pp2bppp/2n2n2/2pp4/3P4/2NBPN2/PP3PPP/R2QK2R");
+        assert!(!data.matches(&hanging_pawns));
+        let carlsbad = board_from_fen("r1bq1rk1/pp2bppp/2p2n2/3p4/3P4/2NBPN2/PP3PPP/R2QK2R");
+        assert!(data.matches(&carlsbad));
+        let extra_pawn = board_from_fen("r1bq1rk1/pp2bppp/2p2n2/3p4/3P4/2NBPN1P/PP3PP1/R2QK2R");
+        assert!(!data.matches(&extra_pawn));
+
+        assert!(data.is_reachable_by(get_pawn_home(&carlsbad)));
+        assert!(!data.is_reachable_by(get_pawn_home(&extra_pawn)));
+        assert!(data.can_reach(get_pawn_home(&board_from_fen("8/pp4pp/8/8/8/8/PP4PP/8"))));
+        assert!(!data.can_reach(get_pawn_home(&board_from_fen("8/pp2pppp/8/8/8/8/PP3PPP/8"))));
+    }
+
+    #[test]
+    fn isolated_pawns_of_either_side() {
+        let data = PawnStructureData::try_from(&PawnStructureQueryJs {
+            color: Some(ColorJs::White),
+            ..query(WHITE_IQP, PawnStructureMode::Exact)
+        })
+        .unwrap()
+        .also_flipped();
+        assert!(data.matches(&board_from_fen("8/p4ppp/8/8/3P4/8/PP3PPP/8")));
+        assert!(!data.matches(&board_from_fen("8/p4ppp/8/8/3P4/8/PP2PPPP/8")));
+        assert!(data.matches(&board_from_fen("8/pp3ppp/8/3p4/8/8/P4PPP/8")));
+
+        let data =
+            PawnStructureData::try_from(&query(WHITE_IQP, PawnStructureMode::Contains)).unwrap();
+        assert!(data.matches(&board_from_fen("8/p4ppp/8/8/3P4/8/PP2PPPP/8")));
+        assert!(data.can_reach(0xffff));
+    }
+}
@@ -0,0 +1,362 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashSet},
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Seek, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use diesel::prelude::*;
+use memmap2::Mmap;
+use rayon::prelude::*;
+use shakmaty::{
+    fen::Fen,
+    variant::VariantPosition,
+    zobrist::{Zobrist64, ZobristValue},
+    Board, CastlingMode, Color, Position,
+};
+
+use crate::{
+    db::{
+        encoding::{decode_move, iter_mainline_move_bytes},
+        schema::info,
+        search_index::{verify_header, SearchGameEntry},
+        update_info_count,
+    },
+    variant::GameVariant,
+};
+
+const MAGIC: &[u8; 4] = b"ECPI";
+const VERSION: u32 = 2;
+/// Magic, version, number of games, padding and checksum of the search index
+/// it was built from.
+const HEADER_SIZE: usize = 24;
+/// Hash, entry, ply and padding.
+const POSTING_SIZE: usize = 16;
+/// Games whose postings are sorted in memory at once, about 128 MB of
+/// postings for typical games.
+const RUN_GAMES: usize = 100_000;
+
+/// Info entry set to 1 when the database keeps a position index next to its
+/// search index. Without one, position searches scan every game.
+const ENABLED_INFO: &str = "PositionIndex";
+
+/// Zobrist hash of the pieces and the side to move. Castling rights and en
+/// passant are left out, like in exact position queries.
+pub fn board_hash(board: &Board, turn: Color) -> u64 {
+    let mut hash = Zobrist64::default();
+    for square in board.occupied() {
+        if let Some(piece) = board.piece_at(square) {
+            hash ^= Zobrist64::zobrist_for_piece(square, piece);
+        }
+    }
+    if turn == Color::White {
+        hash ^= Zobrist64::zobrist_for_white_turn();
+    }
+    hash.0
+}
+
+/// A position reached in the mainline of a game.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PositionPosting {
+    pub hash: u64,
+    /// Index of the game in the search index.
+    pub entry: u32,
+    /// First ply the position was reached at.
+    pub ply: u16,
+}
+
+impl PositionPosting {
+    fn to_bytes(self) -> [u8; POSTING_SIZE] {
+        let mut bytes = [0; POSTING_SIZE];
+        bytes[0..8].copy_from_slice(&self.hash.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.entry.to_le_bytes());
+        bytes[12..14].copy_from_slice(&self.ply.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            hash: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            entry: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            ply: u16::from_le_bytes(bytes[12..14].try_into().unwrap()),
+        }
+    }
+
+    pub fn entry(&self) -> usize {
+        self.entry as usize
+    }
+}
+
+fn read_posting(reader: &mut impl Read) -> io::Result<Option<PositionPosting>> {
+    let mut bytes = [0; POSTING_SIZE];
+    match reader.read_exact(&mut bytes) {
+        Ok(()) => Ok(Some(PositionPosting::from_bytes(&bytes))),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn game_postings(entry_index: usize, entry: &SearchGameEntry) -> Vec<PositionPosting> {
+    let variant = GameVariant::from_db_name(entry.variant.as_deref());
+    let position = match &entry.fen {
+        Some(fen) => Fen::from_ascii(fen.as_bytes()).ok().and_then(|fen| {
+            let setup = fen.into_setup();
+            let castling_mode = CastlingMode::detect(&setup);
+            variant.position(setup, castling_mode).ok()
+        }),
+        None => Some(variant.start_position()),
+    };
+    let Some(mut position) = position else {
+        return Vec::new();
+    };
+
+    let mut seen = HashSet::new();
+    let mut postings = Vec::new();
+    let mut push = |position: &VariantPosition, ply: usize| {
+        let hash = board_hash(position.board(), position.turn());
+        if seen.insert(hash) {
+            postings.push(PositionPosting {
+                hash,
+                entry: entry_index as u32,
+                ply: ply.min(u16::MAX as usize) as u16,
+            });
+        }
+    };
+
+    push(&position, 0);
+    for (i, byte) in iter_mainline_move_bytes(&entry.moves).enumerate() {
+        let Some(m) = decode_move(byte, &position) else {
+            break;
+        };
+        position.play_unchecked(&m);
+        push(&position, i + 1);
+    }
+    postings
+}
+
+/// Writes the postings of every mainline position of these games, sorted by
+/// hash. `source` is the checksum of the search index the entries come from.
+pub fn write_position_index(
+    entries: &[SearchGameEntry],
+    source: u64,
+    path: &Path,
+) -> io::Result<()> {
+    write_sorted_runs(entries, source, path, RUN_GAMES)
+}
+
+/// Sorts the postings of `run_games` games at a time into temporary files,
+/// then merges them, so databases of millions of games don't have to fit in
+/// memory.
+fn write_sorted_runs(
+    entries: &[SearchGameEntry],
+    source: u64,
+    path: &Path,
+    run_games: usize,
+) -> io::Result<()> {
+    let dir = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+
+    let mut runs = Vec::new();
+    for (run, games) in entries.chunks(run_games).enumerate() {
+        let first = run * run_games;
+        let mut postings: Vec<PositionPosting> = games
+            .par_iter()
+            .enumerate()
+            .flat_map_iter(|(i, entry)| game_postings(first + i, entry))
+            .collect();
+        postings.par_sort_unstable_by_key(|p| (p.hash, p.entry));
+
+        let mut writer = BufWriter::new(tempfile::tempfile_in(dir)?);
+        for posting in postings {
+            writer.write_all(&posting.to_bytes())?;
+        }
+        let mut file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.rewind()?;
+        runs.push(BufReader::new(file));
+    }
+
+    let partial_path = path.with_extension("ecpi.partial");
+    let mut writer = BufWriter::new(File::create(&partial_path)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    writer.write_all(&(entries.len() as u32).to_le_bytes())?;
+    writer.write_all(&[0; 4])?;
+    writer.write_all(&source.to_le_bytes())?;
+
+    // runs hold consecutive games, so merging by (hash, entry) keeps the
+    // postings of a hash in game order
+    let mut heap = BinaryHeap::new();
+    for (run, reader) in runs.iter_mut().enumerate() {
+        if let Some(p) = read_posting(reader)? {
+            heap.push(Reverse((p.hash, p.entry, p.ply, run)));
+        }
+    }
+    while let Some(Reverse((hash, entry, ply, run))) = heap.pop() {
+        writer.write_all(&PositionPosting { hash, entry, ply }.to_bytes())?;
+        if let Some(p) = read_posting(&mut runs[run])? {
+            heap.push(Reverse((p.hash, p.entry, p.ply, run)));
+        }
+    }
+    writer.flush()?;
+    drop(writer);
+    fs::rename(&partial_path, path)
+}
+
+#[derive(Clone)]
+pub struct MmapPositionIndex {
+    mmap: Arc<Mmap>,
+}
+
+impl MmapPositionIndex {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        verify_header(&mmap, MAGIC, VERSION, HEADER_SIZE)?;
+        if (mmap.len() - HEADER_SIZE) % POSTING_SIZE != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Truncated position index",
+            ));
+        }
+
+        Ok(Self {
+            mmap: Arc::new(mmap),
+        })
+    }
+
+    /// Number of games of the search index it was built from.
+    pub fn games(&self) -> usize {
+        u32::from_le_bytes(self.mmap[8..12].try_into().unwrap()) as usize
+    }
+
+    /// Checksum of the search index it was built from.
+    pub fn source(&self) -> u64 {
+        u64::from_le_bytes(self.mmap[16..HEADER_SIZE].try_into().unwrap())
+    }
+
+    fn len(&self) -> usize {
+        (self.mmap.len() - HEADER_SIZE) / POSTING_SIZE
+    }
+
+    fn posting(&self, index: usize) -> PositionPosting {
+        let start = HEADER_SIZE + index * POSTING_SIZE;
+        PositionPosting::from_bytes(&self.mmap[start..start + POSTING_SIZE])
+    }
+
+    /// Games reaching a position with this hash. Hashes can collide, so the
+    /// games still have to be checked against the query.
+    pub fn lookup(&self, hash: u64) -> Vec<PositionPosting> {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.posting(mid).hash < hash {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        (low..self.len())
+            .map(|i| self.posting(i))
+            .take_while(|p| p.hash == hash)
+            .collect()
+    }
+
+    pub fn is_valid<P: AsRef<Path>>(path: P) -> bool {
+        let path = path.as_ref();
+        let Ok(file) = File::open(path) else {
+            return false;
+        };
+
+        let mut header = [0u8; HEADER_SIZE];
+        let mut reader = BufReader::new(file);
+        if reader.read_exact(&mut header).is_err() {
+            return false;
+        }
+
+        verify_header(&header, MAGIC, VERSION, HEADER_SIZE).is_ok()
+    }
+}
+
+pub fn get_position_index_path(db_path: &Path) -> PathBuf {
+    db_path.with_extension("ecpi")
+}
+
+/// Whether the position index is built with the search index of the database.
+pub fn position_index_enabled(db: &mut SqliteConnection) -> QueryResult<bool> {
+    let enabled: Option<Option<String>> = info::table
+        .filter(info::name.eq(ENABLED_INFO))
+        .select(info::value)
+        .first(db)
+        .optional()?;
+    Ok(enabled.flatten().is_some_and(|enabled| enabled == "1"))
+}
+
+pub fn set_position_index_enabled(db: &mut SqliteConnection, enabled: bool) -> QueryResult<()> {
+    update_info_count(db, ENABLED_INFO, enabled as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{encoding::encode_sans, search_index::GameResult};
+    use shakmaty::Chess;
+    use tempfile::tempdir;
+
+    fn entry(sans: &[&str]) -> SearchGameEntry {
+        SearchGameEntry {
+            id: 0,
+            white_id: 0,
+            black_id: 0,
+            date: None,
+            result: GameResult::None,
+            pawn_home: 0,
+            white_material: 0,
+            black_material: 0,
+            white_elo: 0,
+            black_elo: 0,
+            fen: None,
+            moves: encode_sans(&mut Chess::default(), sans),
+            variant: None,
+        }
+    }
+
+    fn hash_after(sans: &[&str]) -> u64 {
+        let mut chess = Chess::default();
+        encode_sans(&mut chess, sans);
+        board_hash(chess.board(), chess.turn())
+    }
+
+    #[test]
+    fn finds_games_by_position() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.ecpi");
+
+        let entries = vec![
+            entry(&["e4", "e5", "Nf3"]),
+            entry(&["d4", "d5"]),
+            entry(&["Nf3", "e5", "e4"]),
+        ];
+        // one game per run, so the runs have to be merged
+        write_sorted_runs(&entries, 42, &path, 1).unwrap();
+        assert!(MmapPositionIndex::is_valid(&path));
+
+        let index = MmapPositionIndex::open(&path).unwrap();
+        assert_eq!((index.games(), index.source()), (3, 42));
+
+        // transpositions are found at the ply they happen
+        let postings: Vec<_> = index
+            .lookup(hash_after(&["e4", "e5", "Nf3"]))
+            .iter()
+            .map(|p| (p.entry(), p.ply))
+            .collect();
+        assert_eq!(postings, vec![(0, 3), (2, 3)]);
+
+        assert_eq!(index.lookup(hash_after(&[])).len(), 3);
+        assert!(index.lookup(hash_after(&["c4"])).is_empty());
+    }
+}
@@ -4,15 +4,16 @@ use log::info;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use shakmaty::{
-    fen::Fen, san::SanPlus, Bitboard, ByColor, CastlingMode, Chess, FromSetup, Position, Setup,
+    fen::Fen, san::SanPlus, variant::VariantPosition, Bitboard, Board, ByColor, CastlingMode,
+    Color, Position, Setup,
 };
 use specta::Type;
 use std::{
     cmp::Reverse,
-    collections::BinaryHeap,
-    path::PathBuf,
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc, Mutex,
     },
     time::Instant,
@@ -21,15 +22,22 @@ use tauri::Emitter;
 
 use crate::{
     db::{
-        encoding::{decode_move, iter_mainline_move_bytes},
+        check_multi_query,
+        encoding::{decode_move, iter_mainline_move_bytes, variation_lines},
         get_db_or_create, get_material_count, get_pawn_home,
+        material::{MaterialData, MaterialQueryJs},
         models::*,
+        move_pattern::MovePatternData,
         normalize_games,
+        pattern::{PatternData, PatternQueryJs},
+        pawn_structure::{PawnStructureData, PawnStructureQueryJs},
+        position_index::{board_hash, get_position_index_path, MmapPositionIndex},
         schema::*,
         search_index::{get_index_path, GameResult, MmapSearchIndex, SearchGameEntryRef},
-        ConnectionOptions, MaterialCount,
+        ConnectionOptions, MaterialCount, QueryResponse, SourcedGame,
     },
     error::Error,
+    variant::GameVariant,
     AppState,
 };
 
@@ -39,7 +47,8 @@ use super::GameQuery;
 pub struct ExactData {
     pawn_home: u16,
     material: MaterialCount,
-    position: Chess,
+    board: Board,
+    turn: Color,
 }
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone)]
@@ -53,20 +62,24 @@ pub struct PartialData {
 pub enum PositionQuery {
     Exact(ExactData),
     Partial(PartialData),
+    Pattern(PatternData),
+    Material(MaterialData),
+    PawnStructure(PawnStructureData),
 }
 
 impl PositionQuery {
-    pub fn exact_from_fen(fen: &str) -> Result<PositionQuery, Error> {
+    pub fn exact_from_fen(fen: &str, variant: GameVariant) -> Result<PositionQuery, Error> {
         let fen = Fen::from_ascii(fen.as_bytes())?;
         let setup = fen.into_setup();
         let castling_mode = CastlingMode::detect(&setup);
-        let position: Chess = setup.position(castling_mode)?;
+        let position = variant.position(setup, castling_mode)?;
         let pawn_home = get_pawn_home(position.board());
         let material = get_material_count(position.board());
         Ok(PositionQuery::Exact(ExactData {
             pawn_home,
             material,
-            position,
+            board: position.board().clone(),
+            turn: position.turn(),
         }))
     }
 
@@ -79,27 +92,138 @@ impl PositionQuery {
             material,
         }))
     }
+
+    /// Key of the query in the position index, only exact queries have one.
+    fn zobrist_hash(&self) -> Option<u64> {
+        match self {
+            PositionQuery::Exact(ref data) => Some(board_hash(&data.board, data.turn)),
+            PositionQuery::Partial(_)
+            | PositionQuery::Pattern(_)
+            | PositionQuery::Material(_)
+            | PositionQuery::PawnStructure(_) => None,
+        }
+    }
+
+    /// Also matches the pattern, material or pawn structure with the colours
+    /// swapped. Exact and partial positions are kept as they are.
+    fn also_flipped(self) -> Self {
+        match self {
+            PositionQuery::Pattern(data) => PositionQuery::Pattern(data.also_flipped()),
+            PositionQuery::Material(data) => PositionQuery::Material(data.also_flipped()),
+            PositionQuery::PawnStructure(data) => PositionQuery::PawnStructure(data.also_flipped()),
+            query @ (PositionQuery::Exact(_) | PositionQuery::Partial(_)) => query,
+        }
+    }
+
+    /// Whether the matching positions have to last until the end of the game.
+    fn at_end_only(&self) -> bool {
+        match self {
+            PositionQuery::Material(ref data) => data.at_end_only(),
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Type, PartialEq, Eq, Hash)]
 pub struct PositionQueryJs {
     pub fen: String,
     pub type_: String,
+    /// Used by "pattern" queries instead of the FEN.
+    #[serde(default)]
+    #[specta(optional)]
+    pub pattern: Option<PatternQueryJs>,
+    /// Used by "material" queries instead of the FEN.
+    #[serde(default)]
+    #[specta(optional)]
+    pub material: Option<MaterialQueryJs>,
+    /// Used by "pawns" queries instead of the FEN.
+    #[serde(default)]
+    #[specta(optional)]
+    pub pawn_structure: Option<PawnStructureQueryJs>,
+    /// Also look for the position in the variations of annotated games.
+    #[serde(default)]
+    #[specta(optional)]
+    pub variations: bool,
+    /// Also match the pattern, material or pawn structure with the colours
+    /// swapped and the board mirrored.
+    #[serde(default)]
+    #[specta(optional)]
+    pub flip: bool,
+    /// Number of consecutive positions of a game that have to match, e.g. 3
+    /// for a structure that stays on the board for two plies.
+    #[serde(default)]
+    #[specta(optional)]
+    pub min_positions: u16,
+}
+
+impl PositionQueryJs {
+    /// Number of consecutive positions of a game that have to match.
+    fn persistence(&self) -> usize {
+        self.min_positions.max(1) as usize
+    }
+}
+
+/// A side in the queries.
+#[derive(Debug, Clone, Copy, Deserialize, Type, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum ColorJs {
+    White,
+    Black,
+}
+
+impl From<ColorJs> for Color {
+    fn from(color: ColorJs) -> Self {
+        match color {
+            ColorJs::White => Color::White,
+            ColorJs::Black => Color::Black,
+        }
+    }
 }
 
-fn convert_position_query(query: PositionQueryJs) -> Result<PositionQuery, Error> {
-    match query.type_.as_str() {
-        "exact" => PositionQuery::exact_from_fen(&query.fen),
+fn convert_position_query(
+    query: PositionQueryJs,
+    variant: GameVariant,
+) -> Result<PositionQuery, Error> {
+    let position_query = match query.type_.as_str() {
+        "exact" => PositionQuery::exact_from_fen(&query.fen, variant),
         "partial" => PositionQuery::partial_from_fen(&query.fen),
+        "pattern" => {
+            let pattern = query
+                .pattern
+                .as_ref()
+                .ok_or_else(|| Error::InvalidPattern("missing pattern".to_string()))?;
+            Ok(PositionQuery::Pattern(PatternData::try_from(pattern)?))
+        }
+        "material" => {
+            let material = query
+                .material
+                .as_ref()
+                .ok_or_else(|| Error::InvalidPattern("missing material".to_string()))?;
+            Ok(PositionQuery::Material(MaterialData::try_from(material)?))
+        }
+        "pawns" => {
+            let pawn_structure = query
+                .pawn_structure
+                .as_ref()
+                .ok_or_else(|| Error::InvalidPattern("missing pawn structure".to_string()))?;
+            Ok(PositionQuery::PawnStructure(PawnStructureData::try_from(
+                pawn_structure,
+            )?))
+        }
         _ => unreachable!(),
-    }
+    }?;
+    Ok(if query.flip {
+        position_query.also_flipped()
+    } else {
+        position_query
+    })
 }
 
 impl PositionQuery {
-    fn matches(&self, position: &Chess) -> bool {
+    fn matches<P: Position>(&self, position: &P) -> bool {
         match self {
             PositionQuery::Exact(ref data) => {
-                data.position.turn() == position.turn() && data.position.board() == position.board()
+                data.turn == position.turn() && &data.board == position.board()
             }
             PositionQuery::Partial(ref data) => {
                 let query_board = &data.piece_positions.board;
@@ -114,6 +238,9 @@ impl PositionQuery {
                     && is_contained(tested_board.queens(), query_board.queens())
                     && is_contained(tested_board.kings(), query_board.kings())
             }
+            PositionQuery::Pattern(ref data) => data.matches(position.board(), position.turn()),
+            PositionQuery::Material(ref data) => data.matches(position.board()),
+            PositionQuery::PawnStructure(ref data) => data.matches(position.board()),
         }
     }
 
@@ -124,6 +251,13 @@ impl PositionQuery {
                     && is_material_reachable(&data.material, material)
             }
             PositionQuery::Partial(ref data) => is_material_reachable(&data.material, material),
+            PositionQuery::Pattern(_) => true,
+            PositionQuery::Material(ref data) => data.material_counts().is_none_or(|counts| {
+                counts
+                    .iter()
+                    .any(|count| is_material_reachable(count, material))
+            }),
+            PositionQuery::PawnStructure(ref data) => data.is_reachable_by(pawn_home),
         }
     }
 
@@ -133,7 +267,13 @@ impl PositionQuery {
                 is_end_reachable(pawn_home, data.pawn_home)
                     && is_material_reachable(material, &data.material)
             }
-            PositionQuery::Partial(_) => true,
+            PositionQuery::Material(ref data) => data.material_counts().is_none_or(|counts| {
+                counts
+                    .iter()
+                    .any(|count| is_material_reachable(material, count))
+            }),
+            PositionQuery::PawnStructure(ref data) => data.can_reach(pawn_home),
+            PositionQuery::Partial(_) | PositionQuery::Pattern(_) => true,
         }
     }
 }
@@ -149,51 +289,246 @@ fn is_material_reachable(end: &MaterialCount, pos: &MaterialCount) -> bool {
 }
 
 /// Returns true if the subset is contained in the container
-fn is_contained(container: Bitboard, subset: Bitboard) -> bool {
+pub fn is_contained(container: Bitboard, subset: Bitboard) -> bool {
     container & subset == subset
 }
 
+/// Board part of a FEN, for the tests of the queries.
+#[cfg(test)]
+pub fn board_from_fen(fen: &str) -> Board {
+    Fen::from_ascii(fen.as_bytes()).unwrap().into_setup().board
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Type)]
+#[serde(rename_all = "camelCase")]
 pub struct PositionStats {
     #[serde(rename = "move")]
     pub move_: String,
     pub white: i32,
     pub draw: i32,
     pub black: i32,
+    /// Average Elo of the players who chose the move, over the rated games.
+    pub average_elo: Option<i32>,
+    /// Performance rating of the players who chose the move.
+    pub performance: Option<i32>,
+    pub first_year: Option<i32>,
+    pub last_year: Option<i32>,
+    /// Highest rated player who chose the move.
+    pub top_player: Option<Player>,
+    /// Game in which the top player chose the move.
+    pub top_game: Option<i32>,
+    /// How many of the games counted above only had the move in a
+    /// variation. They are included in the results and ratings, which are
+    /// those of the games the variations belong to.
+    #[serde(default)]
+    pub variations: i32,
 }
 
-fn get_move_after_match(
-    move_blob: &[u8],
+/// Linear approximation of the FIDE performance rating, with the score in
+/// half points.
+pub fn performance_rating(average_opponent_elo: i64, score: i64, games: i64) -> i64 {
+    average_opponent_elo + 400 * (score - games) / games
+}
+
+/// Running totals of a move, turned into `PositionStats` once the search is
+/// done. Ratings are from the point of view of the player making the move.
+#[derive(Debug, Default)]
+struct MoveTotals {
+    white: i32,
+    draw: i32,
+    black: i32,
+    rated_games: i64,
+    elo_sum: i64,
+    opponent_elo_sum: i64,
+    /// Score of the rated games in half points.
+    rated_score: i64,
+    first_year: Option<i32>,
+    last_year: Option<i32>,
+    /// Elo, player and game of the highest rated player who chose the move.
+    top: Option<(i16, i32, i32)>,
+    /// Database of the top game, when searching several at once.
+    top_source: usize,
+    variations: i32,
+}
+
+impl MoveTotals {
+    fn add(&mut self, entry: &SearchGameEntryRef<'_>, turn: Color) {
+        self.add_from(0, entry, turn);
+    }
+
+    fn add_from(&mut self, source: usize, entry: &SearchGameEntryRef<'_>, turn: Color) {
+        match entry.result {
+            GameResult::WhiteWin => self.white += 1,
+            GameResult::BlackWin => self.black += 1,
+            GameResult::Draw | GameResult::Other | GameResult::None => self.draw += 1,
+        }
+
+        let (elo, opponent_elo, player) = match turn {
+            Color::White => (entry.white_elo, entry.black_elo, entry.white_id),
+            Color::Black => (entry.black_elo, entry.white_elo, entry.black_id),
+        };
+        let score = match (entry.result, turn) {
+            (GameResult::WhiteWin, Color::White) | (GameResult::BlackWin, Color::Black) => Some(2),
+            (GameResult::WhiteWin, Color::Black) | (GameResult::BlackWin, Color::White) => Some(0),
+            (GameResult::Draw, _) => Some(1),
+            _ => None,
+        };
+        if let Some(score) = score.filter(|_| elo > 0 && opponent_elo > 0) {
+            self.rated_games += 1;
+            self.elo_sum += elo as i64;
+            self.opponent_elo_sum += opponent_elo as i64;
+            self.rated_score += score;
+        }
+
+        if let Some(year) = entry
+            .date
+            .and_then(|date| date.get(..4))
+            .and_then(|year| year.parse::<i32>().ok())
+        {
+            self.first_year = Some(self.first_year.map_or(year, |first| first.min(year)));
+            self.last_year = Some(self.last_year.map_or(year, |last| last.max(year)));
+        }
+
+        if elo > 0 && self.top.is_none_or(|(top_elo, _, _)| elo > top_elo) {
+            self.top = Some((elo, player, entry.id));
+            self.top_source = source;
+        }
+    }
+
+    fn merge(&mut self, other: &MoveTotals) {
+        self.white += other.white;
+        self.draw += other.draw;
+        self.black += other.black;
+        self.rated_games += other.rated_games;
+        self.elo_sum += other.elo_sum;
+        self.opponent_elo_sum += other.opponent_elo_sum;
+        self.rated_score += other.rated_score;
+        self.variations += other.variations;
+        self.first_year = [self.first_year, other.first_year]
+            .into_iter()
+            .flatten()
+            .min();
+        self.last_year = [self.last_year, other.last_year]
+            .into_iter()
+            .flatten()
+            .max();
+        if let Some((elo, _, _)) = other.top {
+            if self.top.is_none_or(|(top_elo, _, _)| elo > top_elo) {
+                self.top = other.top;
+                self.top_source = other.top_source;
+            }
+        }
+    }
+
+    fn to_stats(&self, move_: String, players: &HashMap<i32, Player>) -> PositionStats {
+        let rated = (self.rated_games > 0).then_some(self.rated_games);
+        PositionStats {
+            move_,
+            white: self.white,
+            draw: self.draw,
+            black: self.black,
+            average_elo: rated.map(|n| (self.elo_sum / n) as i32),
+            performance: rated
+                .map(|n| performance_rating(self.opponent_elo_sum / n, self.rated_score, n) as i32),
+            first_year: self.first_year,
+            last_year: self.last_year,
+            top_player: self
+                .top
+                .and_then(|(_, player, _)| players.get(&player).cloned()),
+            top_game: self.top.map(|(_, _, game)| game),
+            variations: self.variations,
+        }
+    }
+}
+
+/// Position a game starts from, either its FEN or the start position of the
+/// variant.
+pub fn game_start_position(
     fen: &Option<&str>,
-    query: &PositionQuery,
-) -> Result<Option<String>, Error> {
-    let mut chess = if let Some(fen) = fen {
+    variant: GameVariant,
+) -> Result<VariantPosition, Error> {
+    Ok(if let Some(fen) = fen {
         let fen = Fen::from_ascii(fen.as_bytes())?;
         let setup = fen.into_setup();
         let castling_mode = CastlingMode::detect(&setup);
-        Chess::from_setup(setup, castling_mode)?
+        variant.position(setup, castling_mode)?
     } else {
-        Chess::default()
-    };
+        variant.start_position()
+    })
+}
 
-    if query.matches(&chess) {
-        let mut mainline = iter_mainline_move_bytes(move_blob).peekable();
-        if mainline.peek().is_none() {
-            return Ok(Some("*".to_string()));
+/// Same as `find_move_after_match`, looking in the variations as well if the
+/// position is not in the mainline. Also tells whether the match was found in
+/// a variation, in which case the move is the continuation of the variation.
+/// Queries on the end of the game only look at the mainline, which is the
+/// only line the game ended with.
+fn find_move_in_game(
+    move_blob: &[u8],
+    fen: &Option<&str>,
+    variant: GameVariant,
+    query: &PositionQuery,
+    persistence: usize,
+    variations: bool,
+) -> Result<Option<(String, Color, bool)>, Error> {
+    if let Some((san, turn)) = find_move_after_match(move_blob, fen, variant, query, persistence)? {
+        return Ok(Some((san, turn, false)));
+    }
+    if variations && !query.at_end_only() {
+        for line in variation_lines(move_blob) {
+            if let Some((san, turn)) =
+                find_move_after_match(&line, fen, variant, query, persistence)?
+            {
+                return Ok(Some((san, turn, true)));
+            }
         }
-        let Some(next_byte) = mainline.peek().copied() else {
-            return Ok(Some("*".to_string()));
-        };
-        let Some(next_move) = decode_move(next_byte, &chess) else {
-            return Ok(None);
-        };
-        let san = SanPlus::from_move(chess, &next_move);
-        return Ok(Some(san.to_string()));
     }
+    Ok(None)
+}
 
+/// Finds the move played after the first position of the game that matches
+/// the query, along with the side that played it. The `persistence - 1`
+/// following positions need to match as well, and queries on the end of the
+/// game the final position.
+fn find_move_after_match(
+    move_blob: &[u8],
+    fen: &Option<&str>,
+    variant: GameVariant,
+    query: &PositionQuery,
+    persistence: usize,
+) -> Result<Option<(String, Color)>, Error> {
+    let mut chess = game_start_position(fen, variant)?;
+
+    let at_end_only = query.at_end_only();
+    // consecutive matching positions and the move after the first of them
+    let mut run = 0;
+    let mut found = None;
     let mut mainline = iter_mainline_move_bytes(move_blob).peekable();
 
-    while let Some(byte) = mainline.next() {
+    loop {
+        if query.matches(&chess) {
+            if run == 0 {
+                let san = match mainline.peek().copied() {
+                    None => "*".to_string(),
+                    Some(next_byte) => {
+                        let Some(next_move) = decode_move(next_byte, &chess) else {
+                            return Ok(None);
+                        };
+                        SanPlus::from_move(chess.clone(), &next_move).to_string()
+                    }
+                };
+                found = Some((san, chess.turn()));
+            }
+            run += 1;
+            if run >= persistence && !(at_end_only && mainline.peek().is_some()) {
+                return Ok(found);
+            }
+        } else {
+            run = 0;
+        }
+
+        let Some(byte) = mainline.next() else {
+            return Ok(None);
+        };
         let Some(m) = decode_move(byte, &chess) else {
             return Ok(None);
         };
@@ -202,27 +537,13 @@ fn get_move_after_match(
         let is_irreversible =
             m.is_capture() || m.role() == shakmaty::Role::Pawn || m.is_promotion();
 
-        if is_irreversible {
+        if is_irreversible && variant.has_monotonic_material() {
             let board = chess.board();
             if !query.is_reachable_by(&get_material_count(board), get_pawn_home(board)) {
                 return Ok(None);
             }
         }
-        if query.matches(&chess) {
-            if mainline.peek().is_none() {
-                return Ok(Some("*".to_string()));
-            }
-            let Some(next_byte) = mainline.peek().copied() else {
-                return Ok(Some("*".to_string()));
-            };
-            let Some(next_move) = decode_move(next_byte, &chess) else {
-                return Ok(None);
-            };
-            let san = SanPlus::from_move(chess, &next_move);
-            return Ok(Some(san.to_string()));
-        }
     }
-    Ok(None)
 }
 
 #[derive(Clone, serde::Serialize)]
@@ -232,6 +553,43 @@ pub struct ProgressPayload {
     pub finished: bool,
 }
 
+/// Next-move stats found so far by a streaming search. The top players are
+/// only filled in the final result.
+#[derive(Clone, serde::Serialize)]
+pub struct SearchStatsPayload {
+    pub id: String,
+    pub stats: Vec<PositionStats>,
+}
+
+/// Opens the position index of the database, unless it is missing or was
+/// built from a different search index.
+fn load_position_index(
+    file: &Path,
+    mmap_index: &MmapSearchIndex,
+    state: &tauri::State<'_, AppState>,
+) -> Option<MmapPositionIndex> {
+    if let Some(index) = state.position_indexes.get(file) {
+        if index.source() == mmap_index.checksum() {
+            return Some(index.clone());
+        }
+    }
+
+    let index_path = get_position_index_path(file);
+    if !MmapPositionIndex::is_valid(&index_path) {
+        return None;
+    }
+    let index = MmapPositionIndex::open(&index_path).ok()?;
+    if index.source() != mmap_index.checksum() {
+        info!("Position index is out of date, falling back to a full scan");
+        return None;
+    }
+    info!("Opened position index of {} games", index.games());
+    state
+        .position_indexes
+        .insert(file.to_path_buf(), index.clone());
+    Some(index)
+}
+
 #[tauri::command]
 #[specta::specta]
 pub async fn search_position(
@@ -241,202 +599,464 @@ pub async fn search_position(
     tab_id: String,
     state: tauri::State<'_, AppState>,
 ) -> Result<(Vec<PositionStats>, Vec<NormalizedGame>), Error> {
-    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
-
-    let collision_lock = {
-        let entry = state
-            .search_collisions
-            .entry((query.clone(), file.clone()))
-            .or_insert_with(|| Arc::new(tokio::sync::Mutex::new(())));
-        entry.value().clone()
-    };
+    run_position_search(file, query, app, tab_id, state, false).await
+}
 
-    let _guard = collision_lock.lock().await;
+/// Same as `search_position`, but also emits the next-move stats found so
+/// far as `search_stats` events while the games are scanned.
+#[tauri::command]
+#[specta::specta]
+pub async fn stream_search_position(
+    file: PathBuf,
+    query: GameQuery,
+    app: tauri::AppHandle,
+    tab_id: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(Vec<PositionStats>, Vec<NormalizedGame>), Error> {
+    run_position_search(file, query, app, tab_id, state, true).await
+}
 
-    if let Some(pos) = state.line_cache.get(&(query.clone(), file.clone())) {
-        return Ok(pos.clone());
+/// Stops the search running for the tab, which then fails with
+/// `Error::SearchStopped`.
+#[tauri::command]
+#[specta::specta]
+pub async fn cancel_search(tab_id: String, state: tauri::State<'_, AppState>) -> Result<(), Error> {
+    if let Some(flags) = state.search_cancel_flags.get(&tab_id) {
+        for flag in flags.iter() {
+            flag.store(true, Ordering::SeqCst);
+        }
     }
+    Ok(())
+}
 
-    let start = Instant::now();
-    info!("start loading games");
+/// Searches whose matches and totals are kept for paging.
+const MAX_CACHED_SEARCHES: usize = 8;
+/// Matches kept over all the cached searches, the most recent search is kept
+/// whatever its size.
+const MAX_CACHED_MATCHES: usize = 2_000_000;
 
-    let permit = state.new_request.acquire().await.unwrap();
+struct CachedSearch {
+    file: PathBuf,
+    query: GameQuery,
+    matches: Arc<Vec<SearchMatch>>,
+    totals: PositionStats,
+}
 
-    let mmap_index = {
-        let mut cache = state.db_cache.lock().unwrap();
-        if cache.is_none() {
-            let index_path = get_index_path(&file);
-
-            if !MmapSearchIndex::is_valid(&index_path) {
-                info!("Search index not found, generating automatically...");
-                drop(cache);
-                if let Err(e) = super::generate_search_index(&file, &state) {
-                    return Err(Error::from(std::io::Error::other(format!(
-                        "Failed to generate search index: {}",
-                        e
-                    ))));
-                }
-                cache = state.db_cache.lock().unwrap();
+/// Matches and totals of the most recently used searches, so their games can
+/// be paged through without searching again.
+#[derive(Default)]
+pub struct SearchResults(Mutex<VecDeque<CachedSearch>>);
+
+impl SearchResults {
+    fn insert(
+        &self,
+        file: PathBuf,
+        query: GameQuery,
+        matches: Vec<SearchMatch>,
+        totals: PositionStats,
+    ) {
+        let mut searches = self.0.lock().unwrap();
+        searches.retain(|search| search.file != file || search.query != query);
+        searches.push_back(CachedSearch {
+            file,
+            query,
+            matches: Arc::new(matches),
+            totals,
+        });
+        let mut cached: usize = searches.iter().map(|search| search.matches.len()).sum();
+        while searches.len() > MAX_CACHED_SEARCHES
+            || (searches.len() > 1 && cached > MAX_CACHED_MATCHES)
+        {
+            if let Some(evicted) = searches.pop_front() {
+                cached -= evicted.matches.len();
             }
+        }
+    }
 
-            info!("Loading games from mmap binary search index");
-            match MmapSearchIndex::open(&index_path) {
-                Ok(index) => {
-                    info!(
-                        "Opened mmap index with {} games: {:?}",
-                        index.len(),
-                        start.elapsed()
-                    );
-                    *cache = Some(index);
-                }
-                Err(e) => {
-                    return Err(Error::from(e));
-                }
-            }
+    fn get(
+        &self,
+        file: &Path,
+        query: &GameQuery,
+    ) -> Option<(Arc<Vec<SearchMatch>>, PositionStats)> {
+        let mut searches = self.0.lock().unwrap();
+        let index = searches
+            .iter()
+            .position(|search| search.file == file && &search.query == query)?;
+        let search = searches.remove(index)?;
+        let found = (search.matches.clone(), search.totals.clone());
+        searches.push_back(search);
+        Some(found)
+    }
+
+    fn contains(&self, file: &Path, query: &GameQuery) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|search| search.file == file && &search.query == query)
+    }
+
+    fn remove_file(&self, file: &Path) {
+        self.0.lock().unwrap().retain(|search| search.file != file);
+    }
+}
+
+/// Forgets the searches of a database whose games changed or that was
+/// deleted.
+pub fn forget_searches(file: &Path, state: &AppState) {
+    state.search_results.remove_file(file);
+    state.line_cache.retain(|(_, path), _| path != file);
+}
+
+/// Registers a search so it can be cancelled from its tab, and unregisters
+/// it however the search ends.
+struct SearchGuard<'a> {
+    state: &'a AppState,
+    tab_id: Option<String>,
+    cancel_flag: Arc<AtomicBool>,
+    /// Key of `search_collisions` taken by the search.
+    collision: Option<(GameQuery, PathBuf)>,
+}
+
+impl<'a> SearchGuard<'a> {
+    fn new(
+        state: &'a AppState,
+        tab_id: Option<&str>,
+        collision: Option<(GameQuery, PathBuf)>,
+    ) -> Self {
+        let cancel_flag = Arc::new(AtomicBool::new(false));
+        if let Some(tab_id) = tab_id {
+            // a tab can run several searches, cancelling it stops all of them
+            state
+                .search_cancel_flags
+                .entry(tab_id.to_string())
+                .or_default()
+                .push(cancel_flag.clone());
         }
-        cache.as_ref().unwrap().clone()
-    };
+        Self {
+            state,
+            tab_id: tab_id.map(str::to_string),
+            cancel_flag,
+            collision,
+        }
+    }
 
-    let game_count = mmap_index.len();
+    fn is_cancelled(&self) -> bool {
+        self.cancel_flag.load(Ordering::SeqCst)
+    }
+}
 
-    info!(
-        "Ready to search {} games: {:?}",
-        game_count,
-        start.elapsed()
-    );
+impl Drop for SearchGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(tab_id) = &self.tab_id {
+            if let Some(mut flags) = self.state.search_cancel_flags.get_mut(tab_id) {
+                flags.retain(|flag| !Arc::ptr_eq(flag, &self.cancel_flag));
+            }
+            self.state
+                .search_cancel_flags
+                .remove_if(tab_id, |_, flags| flags.is_empty());
+        }
+        if let Some(collision) = &self.collision {
+            self.state.search_collisions.remove(collision);
+        }
+    }
+}
 
-    let openings: DashMap<String, PositionStats> = DashMap::new();
-    const MAX_SAMPLES: usize = 500;
-    // Min-heap of (elo_key, game_id) to track top-rated sample games.
-    // Using Reverse so peek() returns the entry with the lowest ELO,
-    // which we can evict when a higher-rated game is found.
-    let top_games: Mutex<BinaryHeap<Reverse<(i16, i32)>>> =
-        Mutex::new(BinaryHeap::with_capacity(MAX_SAMPLES + 1));
+/// Totals of all the games matching a finished search, e.g. the outcomes of
+/// a pawn structure whatever the move played. The move is empty. Returns
+/// `None` if the query was not searched yet.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_search_totals(
+    file: PathBuf,
+    query: GameQuery,
+    state: tauri::State<'_, AppState>,
+) -> Result<Option<PositionStats>, Error> {
+    Ok(state
+        .search_results
+        .get(&file, &query)
+        .map(|(_, totals)| totals))
+}
 
-    let processed = AtomicUsize::new(0);
+/// A game matching a search.
+#[derive(Debug, Clone, Serialize, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchMatch {
+    pub id: i32,
+    /// Plies of the moves matching the move pattern of the query, empty
+    /// without one.
+    pub plies: Vec<u16>,
+    /// The position was only found in a variation.
+    pub in_variation: bool,
+}
 
-    let parsed_position_query: Option<PositionQuery> = if let Some(pq) = &query.position {
-        Some(convert_position_query(pq.clone())?)
-    } else {
-        None
+/// Page of all the games matching a finished search, highest rated first.
+/// Returns `None` if the query was not searched yet.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_search_matches(
+    file: PathBuf,
+    query: GameQuery,
+    page: i32,
+    page_size: i32,
+    state: tauri::State<'_, AppState>,
+) -> Result<Option<QueryResponse<Vec<SearchMatch>>>, Error> {
+    let Some((matches, _)) = state.search_results.get(&file, &query) else {
+        return Ok(None);
     };
+    let page_size = page_size.max(1) as usize;
+    let page_matches = matches
+        .iter()
+        .skip((page.max(1) as usize - 1) * page_size)
+        .take(page_size)
+        .cloned()
+        .collect();
+    Ok(Some(QueryResponse {
+        data: page_matches,
+        count: Some(matches.len() as i32),
+    }))
+}
 
-    let wanted_result = query.wanted_result.as_ref().and_then(|r| match r.as_str() {
-        "whitewon" => Some(GameResult::WhiteWin),
-        "blackwon" => Some(GameResult::BlackWin),
-        "draw" => Some(GameResult::Draw),
-        _ => None,
-    });
+/// Opens the search index of the database, generating it if it is missing.
+fn load_search_index(
+    file: &Path,
+    state: &tauri::State<'_, AppState>,
+) -> Result<MmapSearchIndex, Error> {
+    if let Some(index) = state.db_cache.get(file) {
+        return Ok(index.clone());
+    }
 
-    info!("start search on {tab_id}");
+    let index_path = get_index_path(file);
+    if !MmapSearchIndex::is_valid(&index_path) {
+        info!("Search index not found, generating automatically...");
+        if let Err(e) = super::generate_search_index(file, state) {
+            return Err(Error::from(std::io::Error::other(format!(
+                "Failed to generate search index: {}",
+                e
+            ))));
+        }
+    }
 
-    let process_entry = |entry: SearchGameEntryRef<'_>| {
-        let index = processed.fetch_add(1, Ordering::Relaxed) + 1;
-        if index.is_multiple_of(50000) {
-            let _ = app.emit(
-                "search_progress",
-                ProgressPayload {
-                    progress: (index as f64 / game_count as f64) * 100.0,
-                    id: tab_id.clone(),
-                    finished: false,
-                },
-            );
+    info!("Loading games from mmap binary search index");
+    let index = MmapSearchIndex::open(&index_path)?;
+    info!("Opened mmap index with {} games", index.len());
+    state.db_cache.insert(file.to_path_buf(), index.clone());
+    Ok(index)
+}
+
+/// The filters of a `GameQuery` that are checked on the search index.
+struct EntryMatcher<'a> {
+    query: &'a GameQuery,
+    variant: GameVariant,
+    wanted_result: Option<GameResult>,
+    position_query: Option<PositionQuery>,
+    persistence: usize,
+    variations: bool,
+    move_pattern: Option<MovePatternData>,
+}
+
+impl<'a> EntryMatcher<'a> {
+    fn new(query: &'a GameQuery) -> Result<Self, Error> {
+        let variant = query.variant.unwrap_or_default();
+        let position_query = match &query.position {
+            Some(pq) => Some(convert_position_query(pq.clone(), variant)?),
+            None => None,
+        };
+        let move_pattern = query
+            .move_pattern
+            .as_ref()
+            .map(MovePatternData::try_from)
+            .transpose()?;
+        let wanted_result = query.wanted_result.as_ref().and_then(|r| match r.as_str() {
+            "whitewon" => Some(GameResult::WhiteWin),
+            "blackwon" => Some(GameResult::BlackWin),
+            "draw" => Some(GameResult::Draw),
+            _ => None,
+        });
+        Ok(Self {
+            query,
+            variant,
+            wanted_result,
+            position_query,
+            persistence: query
+                .position
+                .as_ref()
+                .map_or(1, PositionQueryJs::persistence),
+            variations: query.position.as_ref().is_some_and(|pq| pq.variations),
+            move_pattern,
+        })
+    }
+
+    /// Key of the position in the position index, which only knows the
+    /// mainlines.
+    fn position_hash(&self) -> Option<u64> {
+        self.position_query
+            .as_ref()
+            .filter(|_| !self.variations)
+            .and_then(PositionQuery::zobrist_hash)
+    }
+
+    /// The move the game is counted under, the side that played it and the
+    /// match itself.
+    fn find(&self, entry: &SearchGameEntryRef<'_>) -> Option<(String, Color, SearchMatch)> {
+        if entry.variant != self.variant.db_name() {
+            return None;
         }
 
-        if let Some(white) = query.player1 {
+        if let Some(white) = self.query.player1 {
             if white != entry.white_id {
-                return;
+                return None;
             }
         }
 
-        if let Some(black) = query.player2 {
+        if let Some(black) = self.query.player2 {
             if black != entry.black_id {
-                return;
+                return None;
             }
         }
 
-        if let Some(wanted) = wanted_result {
+        if let Some(wanted) = self.wanted_result {
             if entry.result != wanted {
-                return;
+                return None;
             }
         }
 
-        if let Some(start_date) = &query.start_date {
+        if let Some(start_date) = &self.query.start_date {
             if let Some(date) = entry.date {
                 if date < start_date.as_str() {
-                    return;
+                    return None;
                 }
             }
         }
 
-        if let Some(end_date) = &query.end_date {
+        if let Some(end_date) = &self.query.end_date {
             if let Some(date) = entry.date {
                 if date > end_date.as_str() {
-                    return;
+                    return None;
                 }
             }
         }
 
-        if let Some(position_query) = &parsed_position_query {
+        let mut found = None;
+        let mut in_variation = false;
+        if let Some(position_query) = &self.position_query {
             let end_material: MaterialCount = ByColor {
                 white: entry.white_material,
                 black: entry.black_material,
             };
-            if position_query.can_reach(&end_material, entry.pawn_home) {
-                if let Ok(Some(m)) = get_move_after_match(entry.moves, &entry.fen, position_query) {
-                    let elo_key = entry.white_elo.max(entry.black_elo);
-                    let mut heap = top_games.lock().unwrap();
-                    if heap.len() < MAX_SAMPLES {
-                        heap.push(Reverse((elo_key, entry.id)));
-                    } else if let Some(&Reverse((min_elo, _))) = heap.peek() {
-                        if elo_key > min_elo {
-                            heap.pop();
-                            heap.push(Reverse((elo_key, entry.id)));
-                        }
-                    }
-                    drop(heap);
-
-                    openings
-                        .entry(m)
-                        .and_modify(|opening| match entry.result {
-                            GameResult::WhiteWin => opening.white += 1,
-                            GameResult::BlackWin => opening.black += 1,
-                            GameResult::Draw => opening.draw += 1,
-                            GameResult::Other | GameResult::None => opening.draw += 1,
-                        })
-                        .or_insert_with(|| PositionStats {
-                            black: i32::from(entry.result == GameResult::BlackWin),
-                            white: i32::from(entry.result == GameResult::WhiteWin),
-                            draw: i32::from(
-                                entry.result == GameResult::Draw
-                                    || entry.result == GameResult::Other
-                                    || entry.result == GameResult::None,
-                            ),
-                            move_: String::new(),
-                        });
-                }
+            // the index only knows how the mainline ends
+            if !self.variations
+                && self.variant.has_monotonic_material()
+                && !position_query.can_reach(&end_material, entry.pawn_home)
+            {
+                return None;
             }
+            let (m, turn, variation) = find_move_in_game(
+                entry.moves,
+                &entry.fen,
+                self.variant,
+                position_query,
+                self.persistence,
+                self.variations,
+            )
+            .ok()??;
+            found = Some((m, turn));
+            in_variation = variation;
         }
-    };
 
-    mmap_index.par_iter().for_each(process_entry);
+        // without a position, the stats are those of the first matching move
+        let mut plies = Vec::new();
+        if let Some(move_pattern) = &self.move_pattern {
+            let pattern_match = move_pattern
+                .find_in_game(entry.moves, &entry.fen, self.variant)
+                .ok()??;
+            plies = pattern_match.plies;
+            found = found.or(Some((pattern_match.san, pattern_match.turn)));
+        }
 
-    let openings: Vec<PositionStats> = openings
-        .into_iter()
-        .map(|(k, mut v)| {
-            v.move_ = k;
-            v
-        })
-        .collect();
-    let ids: Vec<i32> = top_games
-        .into_inner()
-        .unwrap()
-        .into_iter()
-        .map(|Reverse((_, id))| id)
-        .collect();
+        let (m, turn) = found?;
+        Some((
+            m,
+            turn,
+            SearchMatch {
+                id: entry.id,
+                plies,
+                in_variation,
+            },
+        ))
+    }
+}
 
-    info!("finished search in {:?}", start.elapsed());
+/// Runs `process` on the games of the index that can match, in parallel,
+/// until the search is cancelled.
+fn for_each_candidate<F>(
+    file: &Path,
+    mmap_index: &MmapSearchIndex,
+    matcher: &EntryMatcher<'_>,
+    cancel_flag: &AtomicBool,
+    state: &tauri::State<'_, AppState>,
+    process: F,
+) where
+    F: Fn(SearchGameEntryRef<'_>) + Sync + Send,
+{
+    let position_index = matcher
+        .position_hash()
+        .zip(load_position_index(file, mmap_index, state));
+    let is_running = |_: &SearchGameEntryRef<'_>| !cancel_flag.load(Ordering::Relaxed);
+    match position_index {
+        Some((hash, position_index)) => position_index
+            .lookup(hash)
+            .into_par_iter()
+            .filter_map(|posting| mmap_index.get_entry_ref(posting.entry()))
+            .take_any_while(is_running)
+            .for_each(process),
+        None => mmap_index
+            .par_iter()
+            .take_any_while(is_running)
+            .for_each(process),
+    }
+}
+
+/// Emits the progress of a search every 50000 games, along with the stats
+/// found so far when streaming.
+fn emit_search_progress(
+    app: &tauri::AppHandle,
+    tab_id: &str,
+    processed: usize,
+    game_count: usize,
+    openings: Option<&DashMap<String, MoveTotals>>,
+) {
+    if !processed.is_multiple_of(50000) {
+        return;
+    }
+    let _ = app.emit(
+        "search_progress",
+        ProgressPayload {
+            progress: (processed as f64 / game_count as f64) * 100.0,
+            id: tab_id.to_string(),
+            finished: false,
+        },
+    );
+    if let Some(openings) = openings {
+        let stats = openings
+            .iter()
+            .map(|totals| totals.to_stats(totals.key().clone(), &HashMap::new()))
+            .collect();
+        let _ = app.emit(
+            "search_stats",
+            SearchStatsPayload {
+                id: tab_id.to_string(),
+                stats,
+            },
+        );
+    }
+}
 
+/// Loads the games with these ids, highest rated first.
+fn load_sample_games(
+    db: &mut SqliteConnection,
+    ids: Vec<i32>,
+) -> Result<Vec<NormalizedGame>, Error> {
     let (white_players, black_players) = diesel::alias!(players as white, players as black);
     let games: Vec<(Game, Player, Player, Event, Site)> = games::table
         .inner_join(white_players.on(games::white_id.eq(white_players.field(players::id))))
@@ -446,21 +1066,275 @@ pub async fn search_position(
         .filter(games::id.eq_any(ids))
         .order((games::white_elo.desc(), games::black_elo.desc()))
         .load(db)?;
-    let normalized_games = normalize_games(games);
-    let file_path = file.clone();
+    Ok(normalize_games(games))
+}
+
+const MAX_SAMPLES: usize = 500;
+
+async fn run_position_search(
+    file: PathBuf,
+    query: GameQuery,
+    app: tauri::AppHandle,
+    tab_id: String,
+    state: tauri::State<'_, AppState>,
+    stream: bool,
+) -> Result<(Vec<PositionStats>, Vec<NormalizedGame>), Error> {
+    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+
+    let collision_lock = {
+        let entry = state
+            .search_collisions
+            .entry((query.clone(), file.clone()))
+            .or_insert_with(|| Arc::new(tokio::sync::Mutex::new(())));
+        entry.value().clone()
+    };
+    let guard = SearchGuard::new(
+        state.inner(),
+        Some(&tab_id),
+        Some((query.clone(), file.clone())),
+    );
+
+    let _collision_guard = collision_lock.lock().await;
+
+    // the matches may have been evicted since, the search is run again then
+    if let Some(pos) = state.line_cache.get(&(query.clone(), file.clone())) {
+        if state.search_results.contains(&file, &query) {
+            return Ok(pos.clone());
+        }
+    }
+
+    let start = Instant::now();
+    info!("start loading games");
+
+    let permit = state.new_request.acquire().await.unwrap();
+    if guard.is_cancelled() {
+        return Err(Error::SearchStopped);
+    }
+
+    let mmap_index = load_search_index(&file, &state)?;
+    let game_count = mmap_index.len();
+
+    info!(
+        "Ready to search {} games: {:?}",
+        game_count,
+        start.elapsed()
+    );
+
+    let openings: DashMap<String, MoveTotals> = DashMap::new();
+    // (elo_key, match) of every matching game, the highest rated ones are
+    // returned as samples.
+    let matches: Mutex<Vec<(i16, SearchMatch)>> = Mutex::new(Vec::new());
+
+    let processed = AtomicUsize::new(0);
+
+    let matcher = EntryMatcher::new(&query)?;
+
+    info!("start search on {tab_id}");
+
+    let process_entry = |entry: SearchGameEntryRef<'_>| {
+        let index = processed.fetch_add(1, Ordering::Relaxed) + 1;
+        emit_search_progress(
+            &app,
+            &tab_id,
+            index,
+            game_count,
+            stream.then_some(&openings),
+        );
+
+        if let Some((m, turn, search_match)) = matcher.find(&entry) {
+            let elo_key = entry.white_elo.max(entry.black_elo);
+            let in_variation = search_match.in_variation;
+            matches.lock().unwrap().push((elo_key, search_match));
+
+            let mut totals = openings.entry(m).or_default();
+            totals.add(&entry, turn);
+            if in_variation {
+                totals.variations += 1;
+            }
+        }
+    };
+
+    for_each_candidate(
+        &file,
+        &mmap_index,
+        &matcher,
+        &guard.cancel_flag,
+        &state,
+        process_entry,
+    );
+
+    if guard.is_cancelled() {
+        info!("search on {tab_id} stopped after {:?}", start.elapsed());
+        return Err(Error::SearchStopped);
+    }
+
+    let mut totals = MoveTotals::default();
+    for move_totals in openings.iter() {
+        totals.merge(move_totals.value());
+    }
+
+    let top_player_ids: Vec<i32> = openings
+        .iter()
+        .filter_map(|totals| totals.top.map(|(_, player, _)| player))
+        .collect();
+    let top_players: HashMap<i32, Player> = players::table
+        .filter(players::id.eq_any(top_player_ids))
+        .load::<Player>(db)?
+        .into_iter()
+        .map(|player| (player.id, player))
+        .collect();
+    let openings: Vec<PositionStats> = openings
+        .into_iter()
+        .map(|(move_, totals)| totals.to_stats(move_, &top_players))
+        .collect();
+    let totals = totals.to_stats(String::new(), &top_players);
+    let mut matches = matches.into_inner().unwrap();
+    matches.par_sort_unstable_by_key(|(elo_key, m)| (Reverse(*elo_key), m.id));
+    let matches: Vec<SearchMatch> = matches.into_iter().map(|(_, m)| m).collect();
+    let ids: Vec<i32> = matches.iter().take(MAX_SAMPLES).map(|m| m.id).collect();
+
+    info!("finished search in {:?}", start.elapsed());
+
+    let normalized_games = load_sample_games(db, ids)?;
 
     state.line_cache.insert(
-        (query.clone(), file),
+        (query.clone(), file.clone()),
         (openings.clone(), normalized_games.clone()),
     );
-
-    state.search_collisions.remove(&(query, file_path));
+    state
+        .search_results
+        .insert(file.clone(), query.clone(), matches, totals);
 
     drop(permit);
 
     Ok((openings, normalized_games))
 }
 
+/// Same as `search_position` over several databases at once, with the
+/// next-move stats of all of them merged. The results are not cached.
+#[tauri::command]
+#[specta::specta]
+pub async fn search_position_multi(
+    mut files: Vec<PathBuf>,
+    query: GameQuery,
+    app: tauri::AppHandle,
+    tab_id: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(Vec<PositionStats>, Vec<SourcedGame>), Error> {
+    check_multi_query(&mut files, &query)?;
+    let guard = SearchGuard::new(state.inner(), Some(&tab_id), None);
+
+    let start = Instant::now();
+    let permit = state.new_request.acquire().await.unwrap();
+    if guard.is_cancelled() {
+        return Err(Error::SearchStopped);
+    }
+
+    let mmap_indexes = files
+        .iter()
+        .map(|file| load_search_index(file, &state))
+        .collect::<Result<Vec<_>, Error>>()?;
+    let game_count = mmap_indexes.iter().map(MmapSearchIndex::len).sum();
+
+    let openings: DashMap<String, MoveTotals> = DashMap::new();
+    // (elo_key, database, game_id) of every matching game
+    let matches: Mutex<Vec<(i16, usize, i32)>> = Mutex::new(Vec::new());
+    let processed = AtomicUsize::new(0);
+    let matcher = EntryMatcher::new(&query)?;
+
+    info!(
+        "start search of {} databases on {tab_id}: {:?}",
+        files.len(),
+        start.elapsed()
+    );
+
+    files
+        .par_iter()
+        .zip(&mmap_indexes)
+        .enumerate()
+        .for_each(|(source, (file, mmap_index))| {
+            let process_entry = |entry: SearchGameEntryRef<'_>| {
+                let index = processed.fetch_add(1, Ordering::Relaxed) + 1;
+                emit_search_progress(&app, &tab_id, index, game_count, None);
+
+                if let Some((m, turn, search_match)) = matcher.find(&entry) {
+                    let elo_key = entry.white_elo.max(entry.black_elo);
+                    matches.lock().unwrap().push((elo_key, source, entry.id));
+
+                    let mut totals = openings.entry(m).or_default();
+                    totals.add_from(source, &entry, turn);
+                    if search_match.in_variation {
+                        totals.variations += 1;
+                    }
+                }
+            };
+            for_each_candidate(
+                file,
+                mmap_index,
+                &matcher,
+                &guard.cancel_flag,
+                &state,
+                process_entry,
+            );
+        });
+
+    if guard.is_cancelled() {
+        info!("search on {tab_id} stopped after {:?}", start.elapsed());
+        return Err(Error::SearchStopped);
+    }
+
+    let mut matches = matches.into_inner().unwrap();
+    matches.par_sort_unstable_by_key(|&(elo_key, source, id)| (Reverse(elo_key), source, id));
+    matches.truncate(MAX_SAMPLES);
+
+    let mut top_players: Vec<HashMap<i32, Player>> = Vec::with_capacity(files.len());
+    let mut games: Vec<SourcedGame> = Vec::new();
+    for (source, file) in files.iter().enumerate() {
+        let db =
+            &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+
+        let top_player_ids: Vec<i32> = openings
+            .iter()
+            .filter(|totals| totals.top_source == source)
+            .filter_map(|totals| totals.top.map(|(_, player, _)| player))
+            .collect();
+        top_players.push(
+            players::table
+                .filter(players::id.eq_any(top_player_ids))
+                .load::<Player>(db)?
+                .into_iter()
+                .map(|player| (player.id, player))
+                .collect(),
+        );
+
+        let ids: Vec<i32> = matches
+            .iter()
+            .filter(|&&(_, match_source, _)| match_source == source)
+            .map(|&(_, _, id)| id)
+            .collect();
+        games.extend(
+            load_sample_games(db, ids)?
+                .into_iter()
+                .map(|game| SourcedGame {
+                    file: file.clone(),
+                    game,
+                }),
+        );
+    }
+    games.sort_by_key(|g| (Reverse(g.game.white_elo), Reverse(g.game.black_elo)));
+
+    let openings: Vec<PositionStats> = openings
+        .into_iter()
+        .map(|(move_, totals)| totals.to_stats(move_, &top_players[totals.top_source]))
+        .collect();
+
+    info!("finished search in {:?}", start.elapsed());
+
+    drop(permit);
+
+    Ok((openings, games))
+}
+
 pub async fn is_position_in_db(
     file: PathBuf,
     query: GameQuery,
@@ -473,58 +1347,38 @@ pub async fn is_position_in_db(
             .or_insert_with(|| Arc::new(tokio::sync::Mutex::new(())));
         entry.value().clone()
     };
+    let _guard = SearchGuard::new(state.inner(), None, Some((query.clone(), file.clone())));
 
-    let _guard = collision_lock.lock().await;
+    let _collision_guard = collision_lock.lock().await;
 
     if let Some(pos) = state.line_cache.get(&(query.clone(), file.clone())) {
         return Ok(!pos.0.is_empty());
     }
 
+    let variant = query.variant.unwrap_or_default();
     let parsed_position_query: Option<PositionQuery> = if let Some(pq) = &query.position {
-        Some(convert_position_query(pq.clone())?)
+        Some(convert_position_query(pq.clone(), variant)?)
     } else {
         None
     };
+    let persistence = query
+        .position
+        .as_ref()
+        .map_or(1, PositionQueryJs::persistence);
+    let variations = query.position.as_ref().is_some_and(|pq| pq.variations);
+    let parsed_move_pattern = query
+        .move_pattern
+        .as_ref()
+        .map(MovePatternData::try_from)
+        .transpose()?;
 
     let start = Instant::now();
     info!("start loading games for is_position_in_db");
 
     let permit = state.new_request.acquire().await.unwrap();
 
-    let mmap_index = {
-        let mut cache = state.db_cache.lock().unwrap();
-        if cache.is_none() {
-            let index_path = get_index_path(&file);
-
-            if !MmapSearchIndex::is_valid(&index_path) {
-                info!("Search index not found, generating automatically...");
-                drop(cache);
-                if let Err(e) = super::generate_search_index(&file, &state) {
-                    return Err(Error::from(std::io::Error::other(format!(
-                        "Failed to generate search index: {}",
-                        e
-                    ))));
-                }
-                cache = state.db_cache.lock().unwrap();
-            }
-
-            info!("Loading games from mmap binary search index");
-            match MmapSearchIndex::open(&index_path) {
-                Ok(index) => {
-                    info!(
-                        "Opened mmap index with {} games: {:?}",
-                        index.len(),
-                        start.elapsed()
-                    );
-                    *cache = Some(index);
-                }
-                Err(e) => {
-                    return Err(Error::from(e));
-                }
-            }
-        }
-        cache.as_ref().unwrap().clone()
-    };
+    let mmap_index = load_search_index(&file, &state)?;
+    info!("Opened search index: {:?}", start.elapsed());
 
     let check_entry = |entry: SearchGameEntryRef<'_>| -> bool {
         let end_material: MaterialCount = ByColor {
@@ -532,16 +1386,44 @@ pub async fn is_position_in_db(
             black: entry.black_material,
         };
         if let Some(position_query) = &parsed_position_query {
-            position_query.can_reach(&end_material, entry.pawn_home)
-                && get_move_after_match(entry.moves, &entry.fen, position_query)
-                    .unwrap_or(None)
-                    .is_some()
+            entry.variant == variant.db_name()
+                && (variations
+                    || !variant.has_monotonic_material()
+                    || position_query.can_reach(&end_material, entry.pawn_home))
+                && find_move_in_game(
+                    entry.moves,
+                    &entry.fen,
+                    variant,
+                    position_query,
+                    persistence,
+                    variations,
+                )
+                .unwrap_or(None)
+                .is_some()
+                && parsed_move_pattern.as_ref().is_none_or(|move_pattern| {
+                    move_pattern
+                        .find_in_game(entry.moves, &entry.fen, variant)
+                        .unwrap_or(None)
+                        .is_some()
+                })
         } else {
             false
         }
     };
 
-    let exists = mmap_index.par_iter().any(check_entry);
+    let position_index = parsed_position_query
+        .as_ref()
+        .filter(|_| !variations)
+        .and_then(PositionQuery::zobrist_hash)
+        .zip(load_position_index(&file, &mmap_index, &state));
+    let exists = match position_index {
+        Some((hash, position_index)) => position_index
+            .lookup(hash)
+            .into_par_iter()
+            .filter_map(|posting| mmap_index.get_entry_ref(posting.entry()))
+            .any(check_entry),
+        None => mmap_index.par_iter().any(check_entry),
+    };
 
     info!("finished search in {:?}", start.elapsed());
 
@@ -549,10 +1431,14 @@ pub async fn is_position_in_db(
         state
             .line_cache
             .insert((query.clone(), file.clone()), (vec![], vec![]));
+        state.search_results.insert(
+            file.clone(),
+            query.clone(),
+            vec![],
+            MoveTotals::default().to_stats(String::new(), &HashMap::new()),
+        );
     }
 
-    state.search_collisions.remove(&(query, file));
-
     drop(permit);
 
     Ok(exists)
@@ -561,6 +1447,21 @@ pub async fn is_position_in_db(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::db::{
+        encoding::{encode_move, encode_sans, VARIATION_END_MARKER, VARIATION_START_MARKER},
+        material::{MaterialPhase, PawnCondition},
+        pattern::{SquareConstraintJs, SquareContentJs},
+    };
+    use shakmaty::{Chess, FromSetup, Move, Role, Square};
+
+    fn get_move_after_match(
+        move_blob: &[u8],
+        fen: &Option<&str>,
+        variant: GameVariant,
+        query: &PositionQuery,
+    ) -> Result<Option<String>, Error> {
+        Ok(find_move_after_match(move_blob, fen, variant, query, 1)?.map(|(san, _)| san))
+    }
 
     fn assert_partial_match(fen1: &str, fen2: &str) {
         let query = PositionQuery::partial_from_fen(fen1).unwrap();
@@ -569,10 +1470,31 @@ mod tests {
         assert!(query.matches(&chess));
     }
 
+    #[test]
+    fn finds_drops_in_crazyhouse_games() {
+        let fen = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR[N] w KQkq - 0 1";
+        let position = GameVariant::Crazyhouse
+            .position(
+                Fen::from_ascii(fen.as_bytes()).unwrap().into_setup(),
+                CastlingMode::Standard,
+            )
+            .unwrap();
+        let drop = Move::Put {
+            role: Role::Knight,
+            to: Square::E4,
+        };
+        let moves = vec![encode_move(&drop, &position).unwrap()];
+
+        let query = PositionQuery::exact_from_fen(fen, GameVariant::Crazyhouse).unwrap();
+        let next = get_move_after_match(&moves, &Some(fen), GameVariant::Crazyhouse, &query);
+        assert_eq!(next.unwrap(), Some("N@e4".to_string()));
+    }
+
     #[test]
     fn exact_matches() {
         let query = PositionQuery::exact_from_fen(
             "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            GameVariant::Standard,
         )
         .unwrap();
         let chess = Chess::default();
@@ -610,9 +1532,11 @@ mod tests {
 
     #[test]
     fn correct_exact_is_reachable() {
-        let query =
-            PositionQuery::exact_from_fen("rnbqkb1r/pppp1ppp/5n2/4p3/4P3/2N5/PPPP1PPP/R1BQKBNR")
-                .unwrap();
+        let query = PositionQuery::exact_from_fen(
+            "rnbqkb1r/pppp1ppp/5n2/4p3/4P3/2N5/PPPP1PPP/R1BQKBNR",
+            GameVariant::Standard,
+        )
+        .unwrap();
         let chess = Chess::default();
         assert!(query.is_reachable_by(
             &get_material_count(chess.board()),
@@ -644,29 +1568,315 @@ mod tests {
     fn get_move_after_exact_match_test() {
         let game = vec![12, 12]; // 1. e4 e5
 
-        let query =
-            PositionQuery::exact_from_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR").unwrap();
-        let result = get_move_after_match(&game, &None, &query).unwrap();
+        let query = PositionQuery::exact_from_fen(
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR",
+            GameVariant::Standard,
+        )
+        .unwrap();
+        let result = get_move_after_match(&game, &None, GameVariant::Standard, &query).unwrap();
         assert_eq!(result, Some("e4".to_string()));
 
-        let query =
-            PositionQuery::exact_from_fen("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR").unwrap();
-        let result = get_move_after_match(&game, &None, &query).unwrap();
+        let query = PositionQuery::exact_from_fen(
+            "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR",
+            GameVariant::Standard,
+        )
+        .unwrap();
+        let result = get_move_after_match(&game, &None, GameVariant::Standard, &query).unwrap();
         assert_eq!(result, Some("e5".to_string()));
 
-        let query =
-            PositionQuery::exact_from_fen("rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR")
-                .unwrap();
-        let result = get_move_after_match(&game, &None, &query).unwrap();
+        let query = PositionQuery::exact_from_fen(
+            "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR",
+            GameVariant::Standard,
+        )
+        .unwrap();
+        let result = get_move_after_match(&game, &None, GameVariant::Standard, &query).unwrap();
         assert_eq!(result, Some("*".to_string()));
     }
 
+    #[test]
+    fn finds_positions_in_variations() {
+        // 1. e4 (1. d4 d5) 1... e5 2. Nf3
+        let mut game = encode_sans(&mut Chess::default(), &["e4"]);
+        game.push(VARIATION_START_MARKER);
+        game.extend_from_slice(&encode_sans(&mut Chess::default(), &["d4", "d5"]));
+        game.push(VARIATION_END_MARKER);
+        // the mainline goes on from 1. e4
+        game.extend_from_slice(&encode_sans(&mut Chess::default(), &["e4", "e5", "Nf3"])[1..]);
+
+        let after_d4 = PositionQuery::exact_from_fen(
+            "rnbqkbnr/pppppppp/8/8/3P4/8/PPP1PPPP/RNBQKBNR b KQkq - 0 1",
+            GameVariant::Standard,
+        )
+        .unwrap();
+        let find = |query, variations| {
+            find_move_in_game(&game, &None, GameVariant::Standard, query, 1, variations).unwrap()
+        };
+        assert_eq!(find(&after_d4, false), None);
+        assert_eq!(
+            find(&after_d4, true),
+            Some(("d5".to_string(), Color::Black, true))
+        );
+
+        let after_e5 = PositionQuery::exact_from_fen(
+            "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2",
+            GameVariant::Standard,
+        )
+        .unwrap();
+        assert_eq!(
+            find(&after_e5, true),
+            Some(("Nf3".to_string(), Color::White, false))
+        );
+    }
+
     #[test]
     fn get_move_after_partial_match_test() {
         let game = vec![12, 12]; // 1. e4 e5
 
         let query = PositionQuery::partial_from_fen("8/pppppppp/8/8/8/8/PPPPPPPP/8").unwrap();
-        let result = get_move_after_match(&game, &None, &query).unwrap();
+        let result = get_move_after_match(&game, &None, GameVariant::Standard, &query).unwrap();
         assert_eq!(result, Some("e4".to_string()));
     }
+
+    #[test]
+    fn patterns_have_to_persist() {
+        let game = encode_sans(&mut Chess::default(), &["e4", "e5", "Nf3"]);
+
+        let pattern = PatternQueryJs {
+            constraints: vec![SquareConstraintJs {
+                squares: vec!["e4".to_string()],
+                content: SquareContentJs::Pieces {
+                    pieces: "P".to_string(),
+                },
+                any: false,
+                exclude: false,
+            }],
+            turn: None,
+        };
+        let query = PositionQuery::Pattern(PatternData::try_from(&pattern).unwrap());
+        let find = |persistence| {
+            find_move_after_match(&game, &None, GameVariant::Standard, &query, persistence)
+        };
+
+        // the pawn is on e4 after 1. e4, 1... e5 and 2. Nf3
+        assert_eq!(find(3).unwrap(), Some(("e5".to_string(), Color::Black)));
+        assert_eq!(find(4).unwrap(), None);
+    }
+
+    #[test]
+    fn material_can_be_matched_at_the_end() {
+        let fen = "r3k3/8/8/8/8/8/4P3/R3K3 w - - 0 1";
+        let mut chess: Chess = Fen::from_ascii(fen.as_bytes())
+            .unwrap()
+            .into_position(CastlingMode::Standard)
+            .unwrap();
+        let game = encode_sans(&mut chess, &["e4", "Rxa1+", "Ke2"]);
+
+        let query = |signature: &str, phase| {
+            let material = MaterialQueryJs {
+                signature: signature.to_string(),
+                pawns: PawnCondition::Exact,
+                opposite_bishops: false,
+                phase,
+            };
+            PositionQuery::Material(MaterialData::try_from(&material).unwrap())
+        };
+        let find =
+            |query| find_move_after_match(&game, &Some(fen), GameVariant::Standard, &query, 1);
+
+        let result = find(query("KRPvKR", MaterialPhase::Any)).unwrap();
+        assert_eq!(result, Some(("e4".to_string(), Color::White)));
+        let result = find(query("KRPvKR", MaterialPhase::End)).unwrap();
+        assert_eq!(result, None);
+        let result = find(query("KPvKR", MaterialPhase::End)).unwrap();
+        assert_eq!(result, Some(("Ke2".to_string(), Color::White)));
+
+        // 1. e4 Kd8 (1... Rxa1+ 2. Ke2), only the mainline ends the game
+        let mut after_e4: Chess = Fen::from_ascii(fen.as_bytes())
+            .unwrap()
+            .into_position(CastlingMode::Standard)
+            .unwrap();
+        let mut game = encode_sans(&mut after_e4, &["e4"]);
+        game.extend(encode_sans(&mut after_e4.clone(), &["Kd8"]));
+        game.push(VARIATION_START_MARKER);
+        game.extend(encode_sans(&mut after_e4, &["Rxa1+", "Ke2"]));
+        game.push(VARIATION_END_MARKER);
+        let find = |query| {
+            find_move_in_game(&game, &Some(fen), GameVariant::Standard, &query, 1, true).unwrap()
+        };
+        assert_eq!(find(query("KPvKR", MaterialPhase::End)), None);
+        assert_eq!(
+            find(query("KPvKR", MaterialPhase::Any)),
+            Some(("Ke2".to_string(), Color::White, true))
+        );
+    }
+
+    #[test]
+    fn flipped_material_is_not_pruned() {
+        let material = MaterialQueryJs {
+            signature: "KRPvKR".to_string(),
+            pawns: PawnCondition::Exact,
+            opposite_bishops: false,
+            phase: MaterialPhase::Any,
+        };
+        let query =
+            PositionQuery::Material(MaterialData::try_from(&material).unwrap()).also_flipped();
+        // the game ends in KRvKRP
+        let end = ByColor { white: 5, black: 6 };
+        assert!(query.can_reach(&end, 0));
+        assert!(query.is_reachable_by(&end, 0));
+        assert!(!query.can_reach(&ByColor { white: 6, black: 6 }, 0));
+    }
+
+    fn entry(
+        id: i32,
+        result: GameResult,
+        elos: (i16, i16),
+        date: Option<&str>,
+    ) -> SearchGameEntryRef<'_> {
+        SearchGameEntryRef {
+            id,
+            white_id: id * 10,
+            black_id: id * 10 + 1,
+            date,
+            result,
+            pawn_home: 0,
+            white_material: 0,
+            black_material: 0,
+            white_elo: elos.0,
+            black_elo: elos.1,
+            fen: None,
+            moves: &[],
+            variant: None,
+        }
+    }
+
+    #[test]
+    fn move_totals_are_from_the_movers_point_of_view() {
+        let game = vec![12, 12]; // 1. e4 e5
+        let query = PositionQuery::exact_from_fen(
+            "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1",
+            GameVariant::Standard,
+        )
+        .unwrap();
+        let (san, turn) = find_move_after_match(&game, &None, GameVariant::Standard, &query, 1)
+            .unwrap()
+            .unwrap();
+        assert_eq!((san.as_str(), turn), ("e5", Color::Black));
+
+        let mut totals = MoveTotals::default();
+        totals.add(
+            &entry(1, GameResult::BlackWin, (2200, 2400), Some("2019.05.01")),
+            turn,
+        );
+        totals.add(
+            &entry(2, GameResult::Draw, (2600, 2500), Some("2023.??.??")),
+            turn,
+        );
+        totals.add(
+            &entry(3, GameResult::None, (2700, 2700), Some("????.??.??")),
+            turn,
+        );
+        totals.add(&entry(4, GameResult::WhiteWin, (0, 2000), None), turn);
+
+        let top = Player {
+            id: 31,
+            name: Some("Top".to_string()),
+            elo: None,
+        };
+        let players = HashMap::from([(top.id, top)]);
+        let stats = totals.to_stats(san, &players);
+
+        assert_eq!((stats.white, stats.draw, stats.black), (1, 2, 1));
+        // the unrated and unfinished games are left out of the ratings
+        assert_eq!(stats.average_elo, Some(2450));
+        assert_eq!(stats.performance, Some(2600));
+        assert_eq!(
+            (stats.first_year, stats.last_year),
+            (Some(2019), Some(2023))
+        );
+        assert_eq!(
+            stats.top_player.and_then(|p| p.name),
+            Some("Top".to_string())
+        );
+        assert_eq!(stats.top_game, Some(3));
+    }
+
+    #[test]
+    fn merged_totals_cover_every_move() {
+        let mut e4 = MoveTotals::default();
+        e4.add(
+            &entry(1, GameResult::WhiteWin, (2500, 2400), Some("2001.01.01")),
+            Color::White,
+        );
+        let mut d4 = MoveTotals::default();
+        d4.add(
+            &entry(2, GameResult::Draw, (2700, 2600), None),
+            Color::White,
+        );
+        d4.add(
+            &entry(3, GameResult::BlackWin, (2300, 2400), Some("1999.01.01")),
+            Color::White,
+        );
+
+        let mut totals = MoveTotals::default();
+        totals.merge(&e4);
+        totals.merge(&d4);
+        let stats = totals.to_stats(String::new(), &HashMap::new());
+
+        assert_eq!((stats.white, stats.draw, stats.black), (1, 1, 1));
+        assert_eq!(stats.average_elo, Some(2500));
+        assert_eq!(
+            (stats.first_year, stats.last_year),
+            (Some(1999), Some(2001))
+        );
+        assert_eq!(stats.top_game, Some(2));
+    }
+
+    #[test]
+    fn top_game_remembers_its_database() {
+        let mut totals = MoveTotals::default();
+        totals.add_from(
+            0,
+            &entry(1, GameResult::WhiteWin, (2500, 2400), None),
+            Color::White,
+        );
+        let mut other = MoveTotals::default();
+        other.add_from(
+            1,
+            &entry(1, GameResult::Draw, (2600, 2400), None),
+            Color::White,
+        );
+        totals.merge(&other);
+        assert_eq!((totals.top_source, totals.top), (1, Some((2600, 1, 1))));
+
+        totals.add_from(
+            2,
+            &entry(2, GameResult::Draw, (2550, 2400), None),
+            Color::White,
+        );
+        assert_eq!(totals.top_source, 1);
+    }
+
+    #[test]
+    fn least_recently_used_searches_are_evicted() {
+        let results = SearchResults::default();
+        let file = PathBuf::from("test.db3");
+        let query = |id| GameQuery {
+            tournament_id: Some(id),
+            ..Default::default()
+        };
+        let totals = || MoveTotals::default().to_stats(String::new(), &HashMap::new());
+
+        for id in 0..MAX_CACHED_SEARCHES as i32 {
+            results.insert(file.clone(), query(id), Vec::new(), totals());
+        }
+        // using the oldest search makes the second one the next to go
+        assert!(results.get(&file, &query(0)).is_some());
+        results.insert(file.clone(), query(100), Vec::new(), totals());
+        assert!(results.contains(&file, &query(0)));
+        assert!(!results.contains(&file, &query(1)));
+
+        results.remove_file(&file);
+        assert!(!results.contains(&file, &query(0)));
+    }
 }
@@ -10,35 +10,50 @@ use rayon::prelude::*;
 use rkyv::{Archive, Deserialize, Serialize};
 
 const MAGIC: &[u8; 4] = b"ECSI";
-const VERSION: u32 = 4;
-const HEADER_SIZE: usize = 8;
-
-fn verify_header(header: &[u8]) -> io::Result<()> {
-    if header.len() < HEADER_SIZE {
+const VERSION: u32 = 6;
+/// Magic, version and checksum of the archived entries.
+const HEADER_SIZE: usize = 16;
+
+/// Checks the size, magic bytes and version of an index file header.
+pub fn verify_header(
+    header: &[u8],
+    magic: &[u8; 4],
+    version: u32,
+    header_size: usize,
+) -> io::Result<()> {
+    if header.len() < header_size {
         return Err(io::Error::new(
             io::ErrorKind::InvalidData,
             "File too small for header",
         ));
     }
 
-    if &header[0..4] != MAGIC {
+    if &header[0..4] != magic {
         return Err(io::Error::new(
             io::ErrorKind::InvalidData,
             "Invalid magic bytes",
         ));
     }
 
-    let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
-    if version != VERSION {
+    let found = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    if found != version {
         return Err(io::Error::new(
             io::ErrorKind::InvalidData,
-            format!("Unsupported version: {} (expected {})", version, VERSION),
+            format!("Unsupported version: {} (expected {})", found, version),
         ));
     }
 
     Ok(())
 }
 
+/// FNV-1a hash of the archived entries, so files built from a search index
+/// can tell whether it changed since.
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Archive, Serialize, Deserialize)]
 #[rkyv(compare(PartialEq), derive(Debug))]
 #[repr(u8)]
@@ -87,6 +102,8 @@ pub struct SearchGameEntry {
     pub black_elo: i16,
     pub fen: Option<String>,
     pub moves: Vec<u8>,
+    /// Database name of the variant, `None` for standard games.
+    pub variant: Option<String>,
 }
 
 #[derive(Archive, Serialize, Deserialize)]
@@ -111,7 +128,8 @@ impl SearchIndex {
         self.entries.push(entry);
     }
 
-    pub fn write_to<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+    /// Writes the index and returns its checksum.
+    pub fn write_to<P: AsRef<Path>>(&self, path: P) -> io::Result<u64> {
         let file = OpenOptions::new()
             .write(true)
             .create(true)
@@ -120,15 +138,17 @@ impl SearchIndex {
 
         let mut writer = BufWriter::new(file);
 
-        writer.write_all(MAGIC)?;
-        writer.write_all(&VERSION.to_le_bytes())?;
-
         let bytes = rkyv::to_bytes::<rkyv::rancor::Error>(self).map_err(|e| {
             io::Error::other(format!("Serialization error: {}", e))
         })?;
+        let checksum = checksum(&bytes);
 
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&checksum.to_le_bytes())?;
         writer.write_all(&bytes)?;
-        writer.flush()
+        writer.flush()?;
+        Ok(checksum)
     }
 }
 
@@ -152,6 +172,7 @@ pub struct SearchGameEntryRef<'a> {
     pub black_elo: i16,
     pub fen: Option<&'a str>,
     pub moves: &'a [u8],
+    pub variant: Option<&'a str>,
 }
 
 impl<'a> From<&'a ArchivedSearchGameEntry> for SearchGameEntryRef<'a> {
@@ -175,11 +196,13 @@ impl<'a> From<&'a ArchivedSearchGameEntry> for SearchGameEntryRef<'a> {
             black_elo: archived.black_elo.into(),
             fen: archived.fen.as_ref().map(|s| s.as_str()),
             moves: &archived.moves,
+            variant: archived.variant.as_ref().map(|s| s.as_str()),
         }
     }
 }
 
 impl SearchGameEntry {
+    #[allow(clippy::too_many_arguments)]
     pub fn from_game_data(
         id: i32,
         white_id: i32,
@@ -193,6 +216,7 @@ impl SearchGameEntry {
         black_material: i32,
         white_elo: Option<i32>,
         black_elo: Option<i32>,
+        variant: Option<String>,
     ) -> Self {
         Self {
             id,
@@ -207,13 +231,14 @@ impl SearchGameEntry {
             black_elo: black_elo.unwrap_or(0) as i16,
             fen,
             moves,
+            variant,
         }
     }
 }
 
 #[derive(Clone)]
 pub struct MmapSearchIndex {
-    #[allow(dead_code)] // mmap must be kept alive to back the archived reference
+    // mmap must be kept alive to back the archived reference
     mmap: Arc<Mmap>,
     archived: &'static ArchivedSearchIndex,
 }
@@ -226,7 +251,7 @@ impl MmapSearchIndex {
         let file = File::open(path)?;
         let mmap = unsafe { Mmap::map(&file)? };
 
-        verify_header(&mmap)?;
+        verify_header(&mmap, MAGIC, VERSION, HEADER_SIZE)?;
 
         let mmap = Arc::new(mmap);
 
@@ -243,6 +268,11 @@ impl MmapSearchIndex {
         self.archived.entries.len()
     }
 
+    /// Checksum of the entries, as returned by `SearchIndex::write_to`.
+    pub fn checksum(&self) -> u64 {
+        u64::from_le_bytes(self.mmap[8..HEADER_SIZE].try_into().unwrap())
+    }
+
     #[inline]
     #[allow(dead_code)]
     pub fn is_empty(&self) -> bool {
@@ -250,7 +280,6 @@ impl MmapSearchIndex {
     }
 
     #[inline]
-    #[allow(dead_code)]
     pub fn get_entry_ref(&self, index: usize) -> Option<SearchGameEntryRef<'_>> {
         self.archived.entries.get(index).map(SearchGameEntryRef::from)
     }
@@ -283,7 +312,7 @@ impl MmapSearchIndex {
             return false;
         }
 
-        verify_header(&header).is_ok()
+        verify_header(&header, MAGIC, VERSION, HEADER_SIZE).is_ok()
     }
 
     #[allow(dead_code)]
@@ -342,6 +371,7 @@ mod tests {
                 black_elo: 2650,
                 fen: None,
                 moves: vec![12, 12, 9, 9], // e4 e5 Nf3 Nc6
+                variant: None,
             },
             SearchGameEntry {
                 id: 2,
@@ -358,12 +388,13 @@ mod tests {
                     "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2".to_string(),
                 ),
                 moves: vec![15, 10],
+                variant: None,
             },
         ];
 
         // Write
         let index = SearchIndex { entries: entries.clone() };
-        index.write_to(&path).unwrap();
+        let checksum = index.write_to(&path).unwrap();
 
         // Verify valid
         assert!(MmapSearchIndex::is_valid(&path));
@@ -371,6 +402,7 @@ mod tests {
         // Read back using mmap
         let index = MmapSearchIndex::open(&path).unwrap();
         assert_eq!(index.len(), entries.len());
+        assert_eq!(index.checksum(), checksum);
 
         for (i, original) in entries.iter().enumerate() {
             let loaded = index.get_entry_ref(i).unwrap();
@@ -441,6 +473,7 @@ mod tests {
                     None
                 },
                 moves: vec![12, 12, 9, 9],
+                variant: None,
             });
         }
         index.write_to(&path).unwrap();
@@ -476,6 +509,7 @@ mod tests {
                 black_elo: 0,
                 fen: None,
                 moves: vec![],
+                variant: None,
             });
         }
         index.write_to(&path).unwrap();
@@ -0,0 +1,580 @@
+//! Support for engines speaking the Chess Engine Communication Protocol
+//! (XBoard/WinBoard). Commands are built from the same calls used for UCI
+//! engines, and the engine output is translated into UCI lines so the rest of
+//! the app can keep parsing it with `vampirc_uci`.
+
+use serde::{Deserialize, Serialize};
+use shakmaty::{
+    fen::Fen, san::SanPlus, uci::UciMove, CastlingMode, CastlingSide, Chess, Color, Move, Position,
+};
+use specta::Type;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::error::Error;
+
+use super::{parse_fen_to_position, types::GoMode};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, Type, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum EngineProtocol {
+    #[default]
+    Uci,
+    Cecp,
+}
+
+/// CECP scores with an absolute value above this are mate scores, encoded as
+/// `100000 - plies to mate`.
+const MATE_SCORE: i64 = 100000;
+const MATE_THRESHOLD: i64 = MATE_SCORE - 1000;
+
+/// Depth sent with `sd` to lift a previous depth limit.
+const UNLIMITED_DEPTH: u32 = 100;
+
+/// Features the engine announced with `feature` during the handshake.
+#[derive(Debug, Clone)]
+pub struct CecpFeatures {
+    pub setboard: bool,
+    pub usermove: bool,
+    pub analyze: bool,
+    /// Accepts `memory N`, the hash size in MB.
+    pub memory: bool,
+    /// Accepts `cores N`, the number of search threads.
+    pub cores: bool,
+    pub variants: Vec<String>,
+    pub options: Vec<String>,
+}
+
+impl Default for CecpFeatures {
+    fn default() -> Self {
+        Self {
+            setboard: false,
+            usermove: false,
+            analyze: true,
+            memory: false,
+            cores: false,
+            variants: Vec::new(),
+            options: Vec::new(),
+        }
+    }
+}
+
+/// Splits a `feature` line into key/value pairs, handling quoted values.
+fn parse_feature_pairs(line: &str) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    let mut rest = line.trim_start_matches("feature").trim_start();
+    while let Some(eq) = rest.find('=') {
+        let key = rest[..eq].trim().to_string();
+        let after = &rest[eq + 1..];
+        let (value, remaining) = if let Some(quoted) = after.strip_prefix('"') {
+            match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            }
+        } else {
+            match after.find(char::is_whitespace) {
+                Some(end) => (&after[..end], &after[end..]),
+                None => (after, ""),
+            }
+        };
+        pairs.push((key, value.to_string()));
+        rest = remaining.trim_start();
+    }
+    pairs
+}
+
+fn format_clock(ms: u32) -> String {
+    let secs = ms / 1000;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+pub struct CecpState {
+    pub features: CecpFeatures,
+    position: Chess,
+    castling_mode: CastlingMode,
+    chess960: bool,
+    analyzing: bool,
+    /// Searching with `go`, until the engine replies with `move`.
+    thinking: bool,
+    node_limit: Option<u64>,
+    finished: bool,
+    last_pv: Vec<String>,
+    injected: UnboundedSender<String>,
+}
+
+impl CecpState {
+    /// `injected` receives lines that have to be delivered to the reader even
+    /// though the engine didn't print them, like the `bestmove` that ends an
+    /// analysis.
+    pub fn new(injected: UnboundedSender<String>) -> Self {
+        Self {
+            features: CecpFeatures::default(),
+            position: Chess::default(),
+            castling_mode: CastlingMode::Standard,
+            chess960: false,
+            analyzing: false,
+            thinking: false,
+            node_limit: None,
+            finished: false,
+            last_pv: Vec::new(),
+            injected,
+        }
+    }
+
+    /// Handles a `feature` line, returning the replies to send and whether the
+    /// engine is done (`Some(true)`) or asked for more time (`Some(false)`).
+    pub fn parse_features(&mut self, line: &str) -> (Vec<String>, Option<bool>) {
+        let mut replies = Vec::new();
+        let mut done = None;
+        for (key, value) in parse_feature_pairs(line) {
+            let enabled = value == "1";
+            let accepted = match key.as_str() {
+                "done" => {
+                    done = Some(enabled);
+                    true
+                }
+                "setboard" => {
+                    self.features.setboard = enabled;
+                    true
+                }
+                "usermove" => {
+                    self.features.usermove = enabled;
+                    true
+                }
+                "analyze" => {
+                    self.features.analyze = enabled;
+                    true
+                }
+                "memory" => {
+                    self.features.memory = enabled;
+                    true
+                }
+                // `smp` is the name in the protocol, some engines send `cores`
+                "smp" | "cores" => {
+                    self.features.cores = enabled;
+                    true
+                }
+                "variants" => {
+                    self.features.variants = value.split(',').map(|v| v.to_string()).collect();
+                    true
+                }
+                "option" => {
+                    if let Some(name) = value.split_whitespace().next() {
+                        self.features.options.push(name.to_string());
+                    }
+                    true
+                }
+                // informational, or commands the engine accepts but is never sent
+                "myname" | "name" | "ping" | "reuse" | "draw" | "ics" | "debug" => true,
+                "time" => enabled,
+                // moves are always sent in coordinate notation, and neither
+                // signals nor `white`/`black` are ever sent
+                "san" | "sigint" | "sigterm" | "colors" => !enabled,
+                _ => false,
+            };
+            if key != "done" {
+                let verb = if accepted { "accepted" } else { "rejected" };
+                replies.push(format!("{} {}", verb, key));
+            }
+        }
+        (replies, done)
+    }
+
+    pub fn supports_option(&self, name: &str) -> bool {
+        self.features
+            .options
+            .iter()
+            .any(|o| o.eq_ignore_ascii_case(name))
+    }
+
+    /// Translates a UCI option. Returns `None` for options that have no CECP
+    /// equivalent or are handled internally. `Hash` and `Threads` become the
+    /// `memory` and `cores` commands when the engine accepts them.
+    pub fn option_command(&mut self, name: &str, value: &str) -> Option<String> {
+        match name {
+            "UCI_Chess960" => {
+                self.chess960 = value == "true";
+                None
+            }
+            "MultiPV" => None,
+            _ if self.features.memory && name.eq_ignore_ascii_case("Hash") => {
+                Some(format!("memory {}", value))
+            }
+            _ if self.features.cores && name.eq_ignore_ascii_case("Threads") => {
+                Some(format!("cores {}", value))
+            }
+            _ => Some(format!("option {}={}", name, value)),
+        }
+    }
+
+    fn exit_analysis(&mut self, commands: &mut Vec<String>) {
+        if self.analyzing {
+            commands.push("exit".to_string());
+            self.analyzing = false;
+        }
+    }
+
+    /// Castling is sent as `O-O`/`O-O-O` in Chess960, like XBoard does.
+    fn format_move(&self, m: &Move) -> String {
+        let notation = match m.castling_side() {
+            Some(CastlingSide::KingSide) if self.chess960 => "O-O".to_string(),
+            Some(CastlingSide::QueenSide) if self.chess960 => "O-O-O".to_string(),
+            _ => UciMove::from_move(m, CastlingMode::Standard).to_string(),
+        };
+        if self.features.usermove {
+            format!("usermove {}", notation)
+        } else {
+            notation
+        }
+    }
+
+    pub fn position_commands(&mut self, fen: &str, moves: &[String]) -> Result<Vec<String>, Error> {
+        let mut commands = Vec::new();
+        self.exit_analysis(&mut commands);
+        self.thinking = false;
+        commands.push("new".to_string());
+        if self.chess960 {
+            if !self.features.variants.iter().any(|v| v == "fischerandom") {
+                return Err(Error::EngineProtocol(
+                    "Engine does not support Chess960".to_string(),
+                ));
+            }
+            commands.push("variant fischerandom".to_string());
+        }
+        commands.push("force".to_string());
+
+        let parsed: Fen = fen.parse()?;
+        self.castling_mode = CastlingMode::detect(parsed.as_setup());
+        if fen != Fen::default().to_string() {
+            if !self.features.setboard {
+                return Err(Error::EngineProtocol(
+                    "Engine does not support setting up positions".to_string(),
+                ));
+            }
+            commands.push(format!("setboard {}", fen));
+        }
+        let mut position = parse_fen_to_position(fen)?;
+        for m in moves {
+            let m = UciMove::from_ascii(m.as_bytes())?.to_move(&position)?;
+            commands.push(self.format_move(&m));
+            position.play_unchecked(&m);
+        }
+        self.position = position;
+        Ok(commands)
+    }
+
+    pub fn go_commands(&mut self, mode: &GoMode) -> Result<Vec<String>, Error> {
+        if matches!(mode, GoMode::Nodes(_) | GoMode::Infinite) && !self.features.analyze {
+            return Err(Error::EngineProtocol(
+                "Engine does not support analysis mode".to_string(),
+            ));
+        }
+        let mut commands = Vec::new();
+        self.exit_analysis(&mut commands);
+        self.finished = false;
+        self.node_limit = None;
+        self.last_pv.clear();
+        commands.push("post".to_string());
+        self.thinking = !matches!(mode, GoMode::Nodes(_) | GoMode::Infinite);
+        match mode {
+            GoMode::Depth(depth) => {
+                commands.push(format!("sd {}", depth));
+                commands.push("go".to_string());
+            }
+            GoMode::Time(ms) => {
+                commands.push(format!("sd {}", UNLIMITED_DEPTH));
+                commands.push(format!("st {}", ms.div_ceil(1000).max(1)));
+                commands.push("go".to_string());
+            }
+            GoMode::PlayersTime(times) => {
+                let (own, opponent, inc) = match self.position.turn() {
+                    Color::White => (times.white, times.black, times.winc),
+                    Color::Black => (times.black, times.white, times.binc),
+                };
+                commands.push(format!("sd {}", UNLIMITED_DEPTH));
+                commands.push(format!("level 0 {} {}", format_clock(own), inc / 1000));
+                commands.push(format!("time {}", own / 10));
+                commands.push(format!("otim {}", opponent / 10));
+                commands.push("go".to_string());
+            }
+            GoMode::Nodes(nodes) => {
+                // CECP has no node limit, so analyze until enough nodes were searched
+                self.node_limit = Some(*nodes as u64);
+                self.analyzing = true;
+                commands.push("analyze".to_string());
+            }
+            GoMode::Infinite => {
+                self.analyzing = true;
+                commands.push("analyze".to_string());
+            }
+        }
+        Ok(commands)
+    }
+
+    pub fn stop_commands(&mut self) -> Vec<String> {
+        if self.analyzing {
+            let mut commands = Vec::new();
+            self.exit_analysis(&mut commands);
+            if !self.finished {
+                self.finish();
+            }
+            commands
+        } else if self.thinking {
+            // ask the engine to move now, it will reply with `move`
+            vec!["?".to_string()]
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Ends an analysis by injecting a `bestmove` for the last reported line.
+    fn finish(&mut self) {
+        self.finished = true;
+        let best = self.last_pv.first().cloned().or_else(|| {
+            self.position
+                .legal_moves()
+                .first()
+                .map(|m| UciMove::from_move(m, self.castling_mode).to_string())
+        });
+        if let Some(best) = best {
+            let _ = self.injected.send(format!("bestmove {}", best));
+        }
+    }
+
+    fn parse_move(&self, pos: &Chess, token: &str) -> Option<Move> {
+        if let Ok(san) = token.parse::<SanPlus>() {
+            if let Ok(m) = san.san.to_move(pos) {
+                return Some(m);
+            }
+        }
+        UciMove::from_ascii(token.as_bytes())
+            .ok()?
+            .to_move(pos)
+            .ok()
+    }
+
+    fn pv_to_uci(&self, tokens: &[&str]) -> Vec<String> {
+        let mut pos = self.position.clone();
+        let mut pv = Vec::new();
+        for token in tokens {
+            // skip move numbers like `12.` or `12...`
+            if token.ends_with('.') {
+                continue;
+            }
+            let Some(m) = self.parse_move(&pos, token.trim_end_matches(['!', '?'])) else {
+                break;
+            };
+            pv.push(UciMove::from_move(&m, self.castling_mode).to_string());
+            pos.play_unchecked(&m);
+        }
+        pv
+    }
+
+    /// Translates an engine line into its UCI equivalent, if there is one.
+    pub fn translate(&mut self, line: &str) -> Option<String> {
+        let line = line.trim();
+        if let Some(m) = line.strip_prefix("move ") {
+            self.thinking = false;
+            let m = self.parse_move(&self.position, m.trim())?;
+            return Some(format!(
+                "bestmove {}",
+                UciMove::from_move(&m, self.castling_mode)
+            ));
+        }
+        if self.finished {
+            return None;
+        }
+
+        // thinking output: ply score time nodes [extra fields] pv
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.len() < 4 {
+            return None;
+        }
+        let depth: u32 = tokens[0]
+            .trim_end_matches(|c: char| !c.is_ascii_digit())
+            .parse()
+            .ok()?;
+        let score: i64 = tokens[1].parse().ok()?;
+        let centis: u64 = tokens[2].parse().ok()?;
+        let nodes: u64 = tokens[3].parse().ok()?;
+        let pv_start = tokens[4..]
+            .iter()
+            .position(|t| t.parse::<u64>().is_err())
+            .map_or(tokens.len(), |i| i + 4);
+        let pv = self.pv_to_uci(&tokens[pv_start..]);
+
+        let score = if score.abs() >= MATE_THRESHOLD {
+            let moves = (MATE_SCORE - score.abs() + 1) / 2;
+            format!("mate {}", moves.max(1) * score.signum())
+        } else {
+            format!("cp {}", score)
+        };
+        let mut info = format!(
+            "info depth {} score {} nodes {} time {}",
+            depth,
+            score,
+            nodes,
+            centis * 10
+        );
+        if !pv.is_empty() {
+            info.push_str(" pv ");
+            info.push_str(&pv.join(" "));
+            self.last_pv = pv;
+        }
+
+        if self.analyzing && self.node_limit.is_some_and(|limit| nodes >= limit) {
+            self.finish();
+        }
+        Some(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    use super::*;
+
+    fn state() -> (CecpState, UnboundedReceiver<String>) {
+        let (tx, rx) = unbounded_channel();
+        (CecpState::new(tx), rx)
+    }
+
+    #[test]
+    fn parses_features() {
+        let (mut state, _) = state();
+        let (replies, done) = state.parse_features(
+            r#"feature myname="Crafty 25.2" setboard=1 usermove=1 san=1 option="Hash -spin 64 1 1024" done=1"#,
+        );
+        assert_eq!(done, Some(true));
+        assert!(state.features.setboard);
+        assert!(state.features.usermove);
+        assert!(state.supports_option("hash"));
+        assert!(replies.contains(&"rejected san".to_string()));
+        assert!(replies.contains(&"accepted setboard".to_string()));
+
+        let (replies, _) = state.parse_features("feature sigint=0 sigterm=1 nps=1 ping=1");
+        assert_eq!(
+            replies,
+            vec![
+                "accepted sigint",
+                "rejected sigterm",
+                "rejected nps",
+                "accepted ping"
+            ]
+        );
+    }
+
+    #[test]
+    fn maps_hash_and_threads_to_memory_and_cores() {
+        let (mut state, _) = state();
+        assert_eq!(
+            state.option_command("Hash", "64"),
+            Some("option Hash=64".to_string())
+        );
+
+        let (replies, _) = state.parse_features("feature memory=1 smp=1");
+        assert_eq!(replies, vec!["accepted memory", "accepted smp"]);
+        assert_eq!(
+            state.option_command("Hash", "256"),
+            Some("memory 256".to_string())
+        );
+        assert_eq!(
+            state.option_command("Threads", "4"),
+            Some("cores 4".to_string())
+        );
+        assert_eq!(
+            state.option_command("Ponder", "false"),
+            Some("option Ponder=false".to_string())
+        );
+    }
+
+    #[test]
+    fn position_requires_setboard() {
+        let (mut state, _) = state();
+        let fen = "4k3/8/8/8/8/8/8/4K2R w K - 0 1";
+        assert!(state.position_commands(fen, &[]).is_err());
+
+        state.features.setboard = true;
+        state.features.usermove = true;
+        let commands = state.position_commands(fen, &["h1h8".to_string()]).unwrap();
+        assert_eq!(
+            commands,
+            vec![
+                "new",
+                "force",
+                format!("setboard {}", fen).as_str(),
+                "usermove h1h8"
+            ]
+        );
+    }
+
+    #[test]
+    fn sends_chess960_castling_as_o_o() {
+        let (mut state, _) = state();
+        state.features.setboard = true;
+        state.features.variants = vec!["fischerandom".to_string()];
+        let fen = "4k3/8/8/8/8/8/8/4K2R w K - 0 1";
+        let castle = ["e1g1".to_string()];
+        assert_eq!(
+            state
+                .position_commands(fen, &castle)
+                .unwrap()
+                .last()
+                .unwrap(),
+            "e1g1"
+        );
+
+        state.option_command("UCI_Chess960", "true");
+        let commands = state.position_commands(fen, &castle).unwrap();
+        assert_eq!(commands[1], "variant fischerandom");
+        assert_eq!(commands.last().unwrap(), "O-O");
+    }
+
+    #[test]
+    fn only_interrupts_a_search() {
+        let (mut state, _) = state();
+        let start = Fen::default().to_string();
+        state.position_commands(&start, &[]).unwrap();
+        assert!(state.stop_commands().is_empty());
+
+        state.go_commands(&GoMode::Depth(10)).unwrap();
+        assert_eq!(state.stop_commands(), vec!["?"]);
+        state.translate("move e2e4");
+        assert!(state.stop_commands().is_empty());
+    }
+
+    #[test]
+    fn translates_thinking_output() {
+        let (mut state, _) = state();
+        let start = Fen::default().to_string();
+        state.position_commands(&start, &[]).unwrap();
+        assert_eq!(
+            state.translate("12 35 150 123456 1. e4 e5 2. Nf3"),
+            Some("info depth 12 score cp 35 nodes 123456 time 1500 pv e2e4 e7e5 g1f3".to_string())
+        );
+        assert_eq!(
+            state.translate("9& 99995 20 500 14 e4"),
+            Some("info depth 9 score mate 3 nodes 500 time 200 pv e2e4".to_string())
+        );
+        assert_eq!(
+            state.translate("move e2e4"),
+            Some("bestmove e2e4".to_string())
+        );
+        assert_eq!(state.translate("tellics say hello"), None);
+    }
+
+    #[test]
+    fn stopping_analysis_injects_bestmove() {
+        let (mut state, mut rx) = state();
+        let start = Fen::default().to_string();
+        state.position_commands(&start, &[]).unwrap();
+        assert_eq!(
+            state.go_commands(&GoMode::Infinite).unwrap(),
+            vec!["post", "analyze"]
+        );
+        state.translate("5 20 10 1000 d4 d5");
+        assert_eq!(state.stop_commands(), vec!["exit"]);
+        assert_eq!(rx.try_recv().unwrap(), "bestmove d2d4");
+        assert_eq!(state.translate("6 20 10 2000 e4"), None);
+    }
+}
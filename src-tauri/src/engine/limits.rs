@@ -0,0 +1,301 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use sysinfo::SystemExt;
+use tokio::{
+    process::{Child, Command},
+    sync::{oneshot, Mutex},
+};
+
+use crate::error::Error;
+
+use super::EngineOption;
+
+/// Memory the engine needs on top of its hash table (code, stack, tablebase
+/// caches).
+const MEMORY_HEADROOM_MB: u32 = 64;
+
+/// Optional per-engine resource controls. They are applied when the engine
+/// process is spawned and are only supported on Linux; elsewhere they are
+/// ignored.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Type, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ResourceLimits {
+    /// Address space limit in MiB.
+    pub memory_mb: Option<u32>,
+    /// CPU cores the engine is allowed to run on.
+    pub cores: Option<Vec<u32>>,
+    /// Niceness, from 0 (default priority) to 19 (lowest priority).
+    pub nice: Option<i32>,
+    /// Kill the engine after it has been running for this many seconds.
+    pub timeout_secs: Option<u32>,
+}
+
+/// Kills the engine once its wall-clock timeout expires. Dropping the timer
+/// cancels it.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub struct KillTimer {
+    _cancel: oneshot::Sender<()>,
+    fired: Arc<AtomicBool>,
+}
+
+impl KillTimer {
+    pub fn fired(&self) -> bool {
+        self.fired.load(Ordering::SeqCst)
+    }
+}
+
+pub fn total_memory_mb() -> u32 {
+    let mut system = sysinfo::System::new();
+    system.refresh_memory();
+    (system.total_memory() / 1024 / 1024) as u32
+}
+
+fn invalid(message: String) -> Error {
+    Error::InvalidResourceLimits(message)
+}
+
+/// Cores the app is allowed to run on, which the engine inherits.
+#[cfg(target_os = "linux")]
+fn allowed_cores() -> Vec<u32> {
+    // SAFETY: the set is zeroed and as large as the size given to the kernel.
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) == 0 {
+            return (0..libc::CPU_SETSIZE as usize)
+                .filter(|&core| libc::CPU_ISSET(core, &set))
+                .map(|core| core as u32)
+                .collect();
+        }
+    }
+    (0..available_cores()).collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allowed_cores() -> Vec<u32> {
+    (0..available_cores()).collect()
+}
+
+fn available_cores() -> u32 {
+    std::thread::available_parallelism()
+        .map(|n| n.get() as u32)
+        .unwrap_or(1)
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Checks the limits against the machine and the engine's `Hash` option.
+    pub fn validate(&self, options: &[EngineOption]) -> Result<(), Error> {
+        let hash = options
+            .iter()
+            .find(|o| o.name.eq_ignore_ascii_case("Hash"))
+            .and_then(|o| o.value.parse::<u32>().ok());
+
+        if let Some(memory) = self.memory_mb {
+            let total_memory = total_memory_mb();
+            if memory > total_memory {
+                return Err(invalid(format!(
+                    "Memory limit of {} MB exceeds the system memory of {} MB",
+                    memory, total_memory
+                )));
+            }
+            if let Some(hash) = hash {
+                if hash.saturating_add(MEMORY_HEADROOM_MB) > memory {
+                    return Err(invalid(format!(
+                        "Hash of {} MB does not fit in the memory limit of {} MB",
+                        hash, memory
+                    )));
+                }
+            }
+        }
+
+        if let Some(cores) = &self.cores {
+            if cores.is_empty() {
+                return Err(invalid("No CPU cores selected".to_string()));
+            }
+            let allowed = allowed_cores();
+            if let Some(core) = cores.iter().find(|c| !allowed.contains(c)) {
+                return Err(invalid(format!(
+                    "CPU core {} is not available, the app can run on cores {:?}",
+                    core, allowed
+                )));
+            }
+        }
+
+        if let Some(nice) = self.nice {
+            if !(0..=19).contains(&nice) {
+                return Err(invalid(format!(
+                    "Nice level must be between 0 and 19, got {}",
+                    nice
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Configures the command so the limits are applied in the child process
+    /// before the engine binary is executed.
+    #[cfg(target_os = "linux")]
+    pub fn apply(&self, command: &mut Command) -> Result<(), Error> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let memory = self.memory_mb.map(|mb| mb as libc::rlim_t * 1024 * 1024);
+        let nice = self.nice;
+        // the set is built before forking, `CPU_SET` panics on cores past
+        // `CPU_SETSIZE`
+        let cores = match &self.cores {
+            Some(cores) => {
+                if let Some(core) = cores
+                    .iter()
+                    .find(|&&c| c as usize >= libc::CPU_SETSIZE as usize)
+                {
+                    return Err(invalid(format!("CPU core {} does not exist", core)));
+                }
+                // SAFETY: a zeroed set is empty, the cores are in its range.
+                let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+                for &core in cores {
+                    unsafe { libc::CPU_SET(core as usize, &mut set) };
+                }
+                Some(set)
+            }
+            None => None,
+        };
+
+        // SAFETY: only async-signal-safe syscalls are made between fork and exec.
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(memory) = memory {
+                    let limit = libc::rlimit {
+                        rlim_cur: memory,
+                        rlim_max: memory,
+                    };
+                    if libc::setrlimit(libc::RLIMIT_AS, &limit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                if let Some(set) = &cores {
+                    if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), set) != 0
+                    {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                if let Some(nice) = nice {
+                    if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn apply(&self, _command: &mut Command) -> Result<(), Error> {
+        if !self.is_empty() {
+            log::warn!("Engine resource limits are only supported on Linux");
+        }
+        Ok(())
+    }
+
+    /// Kills the engine through its handle, which knows if the process was
+    /// already reaped, unlike its pid.
+    #[cfg(target_os = "linux")]
+    pub fn start_kill_timer(&self, child: &Arc<Mutex<Child>>) -> Option<KillTimer> {
+        let secs = self.timeout_secs?;
+        let child = child.clone();
+        let (cancel, cancelled) = oneshot::channel::<()>();
+        let fired = Arc::new(AtomicBool::new(false));
+        let timer_fired = fired.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = tokio::time::sleep(std::time::Duration::from_secs(secs.into())) => {
+                    log::warn!("Engine reached its {}s timeout, killing it", secs);
+                    timer_fired.store(true, Ordering::SeqCst);
+                    let _ = child.lock().await.start_kill();
+                }
+                _ = cancelled => {}
+            }
+        });
+        Some(KillTimer {
+            _cancel: cancel,
+            fired,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn start_kill_timer(&self, _child: &Arc<Mutex<Child>>) -> Option<KillTimer> {
+        None
+    }
+}
+
+#[tauri::command]
+#[specta::specta]
+pub fn validate_resource_limits(
+    limits: ResourceLimits,
+    options: Vec<EngineOption>,
+) -> Result<(), Error> {
+    limits.validate(&options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(mb: u32) -> Vec<EngineOption> {
+        vec![EngineOption {
+            name: "Hash".to_string(),
+            value: mb.to_string(),
+        }]
+    }
+
+    #[test]
+    fn empty_limits_are_valid() {
+        assert!(ResourceLimits::default().is_empty());
+        assert!(ResourceLimits::default().validate(&hash(16)).is_ok());
+    }
+
+    #[test]
+    fn hash_must_fit_in_memory_limit() {
+        let limits = ResourceLimits {
+            memory_mb: Some(128),
+            ..Default::default()
+        };
+        assert!(limits.validate(&hash(32)).is_ok());
+        assert!(limits.validate(&hash(100)).is_err());
+        assert!(limits.validate(&hash(u32::MAX)).is_err());
+
+        // without a memory limit the engine decides what its hash can be
+        assert!(ResourceLimits::default().validate(&hash(u32::MAX)).is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_nice_and_cores() {
+        let limits = ResourceLimits {
+            nice: Some(-5),
+            ..Default::default()
+        };
+        assert!(limits.validate(&[]).is_err());
+
+        let limits = ResourceLimits {
+            cores: Some(vec![]),
+            ..Default::default()
+        };
+        assert!(limits.validate(&[]).is_err());
+
+        let limits = ResourceLimits {
+            cores: Some(vec![u32::MAX]),
+            ..Default::default()
+        };
+        assert!(limits.validate(&[]).is_err());
+    }
+}
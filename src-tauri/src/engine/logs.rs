@@ -0,0 +1,230 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime},
+};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::error::Error;
+
+const DEFAULT_LOG_CAPACITY: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Type, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum LogDirection {
+    /// Sent to the engine.
+    Gui,
+    /// Read from the engine's output.
+    Engine,
+    Stderr,
+}
+
+impl LogDirection {
+    fn marker(self) -> &'static str {
+        match self {
+            LogDirection::Gui => ">",
+            LogDirection::Engine => "<",
+            LogDirection::Stderr => "!",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct EngineLog {
+    #[serde(rename = "type")]
+    pub direction: LogDirection,
+    pub value: String,
+    /// Milliseconds since the engine was started.
+    pub time_ms: u32,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct EngineLogFilter {
+    /// Only keep these directions, all of them if empty.
+    #[serde(default)]
+    pub directions: Vec<LogDirection>,
+    /// Regular expression the line has to match.
+    pub pattern: Option<String>,
+    pub since_ms: Option<u32>,
+    pub until_ms: Option<u32>,
+}
+
+impl EngineLogFilter {
+    fn matcher(&self) -> Result<impl Fn(&EngineLog) -> bool + '_, Error> {
+        let pattern = self.pattern.as_deref().map(Regex::new).transpose()?;
+        Ok(move |log: &EngineLog| {
+            (self.directions.is_empty() || self.directions.contains(&log.direction))
+                && self.since_ms.is_none_or(|since| log.time_ms >= since)
+                && self.until_ms.is_none_or(|until| log.time_ms <= until)
+                && pattern.as_ref().is_none_or(|p| p.is_match(&log.value))
+        })
+    }
+}
+
+struct LogBuffer {
+    entries: VecDeque<EngineLog>,
+    capacity: usize,
+    dropped: usize,
+}
+
+/// Bounded log of an engine's traffic, shared with the task reading stderr.
+#[derive(Clone)]
+pub struct EngineLogs {
+    buffer: Arc<Mutex<LogBuffer>>,
+    start: Instant,
+    started_at: SystemTime,
+}
+
+impl Default for EngineLogs {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_LOG_CAPACITY)
+    }
+}
+
+impl EngineLogs {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            buffer: Arc::new(Mutex::new(LogBuffer {
+                entries: VecDeque::new(),
+                capacity: capacity.max(1),
+                dropped: 0,
+            })),
+            start: Instant::now(),
+            started_at: SystemTime::now(),
+        }
+    }
+
+    pub fn push(&self, direction: LogDirection, value: String) {
+        let log = EngineLog {
+            direction,
+            value,
+            time_ms: self.start.elapsed().as_millis().min(u32::MAX as u128) as u32,
+        };
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer.entries.len() == buffer.capacity {
+            buffer.entries.pop_front();
+            buffer.dropped += 1;
+        }
+        buffer.entries.push_back(log);
+    }
+
+    /// The last `n` lines logged in this direction, oldest first.
+    pub fn last_lines(&self, direction: LogDirection, n: usize) -> Vec<String> {
+        let buffer = self.buffer.lock().unwrap();
+        let mut lines: Vec<String> = buffer
+            .entries
+            .iter()
+            .rev()
+            .filter(|l| l.direction == direction)
+            .take(n)
+            .map(|l| l.value.clone())
+            .collect();
+        lines.reverse();
+        lines
+    }
+
+    pub fn filtered(&self, filter: &EngineLogFilter) -> Result<Vec<EngineLog>, Error> {
+        let matches = filter.matcher()?;
+        let buffer = self.buffer.lock().unwrap();
+        Ok(buffer
+            .entries
+            .iter()
+            .filter(|l| matches(l))
+            .cloned()
+            .collect())
+    }
+
+    /// Writes the matching lines as plain text, one per line, prefixed with
+    /// their time and `>` (sent), `<` (received) or `!` (stderr).
+    pub fn export(&self, path: &Path, filter: &EngineLogFilter) -> Result<(), Error> {
+        let logs = self.filtered(filter)?;
+        let dropped = self.buffer.lock().unwrap().dropped;
+        let started_at: chrono::DateTime<chrono::Utc> = self.started_at.into();
+
+        let mut out = String::new();
+        let _ = writeln!(out, "# Engine started at {}", started_at.to_rfc3339());
+        if dropped > 0 {
+            let _ = writeln!(out, "# {} older lines were dropped", dropped);
+        }
+        for log in logs {
+            let _ = writeln!(
+                out,
+                "[{:>6}.{:03}] {} {}",
+                log.time_ms / 1000,
+                log.time_ms % 1000,
+                log.direction.marker(),
+                log.value.trim_end()
+            );
+        }
+        std::fs::write(path, out)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(logs: &[EngineLog]) -> Vec<&str> {
+        logs.iter().map(|l| l.value.as_str()).collect()
+    }
+
+    #[test]
+    fn drops_oldest_entries() {
+        let logs = EngineLogs::with_capacity(2);
+        logs.push(LogDirection::Gui, "uci".to_string());
+        logs.push(LogDirection::Engine, "id name Fake".to_string());
+        logs.push(LogDirection::Engine, "uciok".to_string());
+        let all = logs.filtered(&EngineLogFilter::default()).unwrap();
+        assert_eq!(values(&all), vec!["id name Fake", "uciok"]);
+        assert_eq!(logs.buffer.lock().unwrap().dropped, 1);
+    }
+
+    #[test]
+    fn returns_last_lines_of_a_direction() {
+        let logs = EngineLogs::with_capacity(10);
+        logs.push(LogDirection::Stderr, "a".to_string());
+        logs.push(LogDirection::Engine, "uciok".to_string());
+        logs.push(LogDirection::Stderr, "b".to_string());
+        logs.push(LogDirection::Stderr, "c".to_string());
+        assert_eq!(logs.last_lines(LogDirection::Stderr, 2), vec!["b", "c"]);
+        assert!(logs.last_lines(LogDirection::Gui, 2).is_empty());
+    }
+
+    #[test]
+    fn filters_by_direction_and_pattern() {
+        let logs = EngineLogs::with_capacity(10);
+        logs.push(LogDirection::Gui, "go depth 10".to_string());
+        logs.push(LogDirection::Engine, "info depth 1 score cp 20".to_string());
+        logs.push(LogDirection::Stderr, "warning: no NNUE".to_string());
+        logs.push(LogDirection::Engine, "bestmove e2e4".to_string());
+
+        let filter = EngineLogFilter {
+            directions: vec![LogDirection::Engine, LogDirection::Stderr],
+            pattern: Some("^(bestmove|warning)".to_string()),
+            ..Default::default()
+        };
+        let filtered = logs.filtered(&filter).unwrap();
+        assert_eq!(values(&filtered), vec!["warning: no NNUE", "bestmove e2e4"]);
+
+        let filter = EngineLogFilter {
+            until_ms: Some(u32::MAX),
+            since_ms: Some(u32::MAX),
+            ..Default::default()
+        };
+        assert!(logs.filtered(&filter).unwrap().is_empty());
+
+        let filter = EngineLogFilter {
+            pattern: Some("(".to_string()),
+            ..Default::default()
+        };
+        assert!(logs.filtered(&filter).is_err());
+    }
+}
@@ -1,7 +1,21 @@
+mod cecp;
+mod limits;
+mod logs;
+mod options;
 mod process;
+mod supervisor;
+mod transport;
 mod types;
 mod uci;
+mod wdl;
 
-pub use process::{BaseEngine, EngineLog, EngineReader};
+pub use cecp::EngineProtocol;
+pub use limits::{total_memory_mb, validate_resource_limits, ResourceLimits};
+pub use logs::{EngineLog, EngineLogFilter};
+pub use options::{format_option_errors, validate_options, EngineOptionError};
+pub use process::{BaseEngine, EngineReader};
+pub use supervisor::EngineCrashed;
+pub use transport::{EngineCommand, EngineSpawnConfig};
 pub use types::*;
 pub use uci::*;
+pub use wdl::*;
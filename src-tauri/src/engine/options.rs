@@ -0,0 +1,234 @@
+use std::fmt;
+
+use serde::Serialize;
+use specta::Type;
+use vampirc_uci::UciOptionConfig;
+
+use super::{process::option_name, types::EngineOption};
+
+#[derive(Debug, Clone, Serialize, Type, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum EngineOptionErrorKind {
+    Unknown,
+    NotANumber {
+        value: String,
+    },
+    OutOfRange {
+        value: i64,
+        min: Option<i64>,
+        max: Option<i64>,
+    },
+    InvalidChoice {
+        value: String,
+        allowed: Vec<String>,
+    },
+    NotABoolean {
+        value: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Type, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct EngineOptionError {
+    pub name: String,
+    pub kind: EngineOptionErrorKind,
+}
+
+impl fmt::Display for EngineOptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            EngineOptionErrorKind::Unknown => write!(f, "unknown option {}", self.name),
+            EngineOptionErrorKind::NotANumber { value } => {
+                write!(f, "{} must be a number, got {}", self.name, value)
+            }
+            EngineOptionErrorKind::OutOfRange { value, min, max } => {
+                let min = min.map_or("-∞".to_string(), |m| m.to_string());
+                let max = max.map_or("∞".to_string(), |m| m.to_string());
+                write!(
+                    f,
+                    "{} must be between {} and {}, got {}",
+                    self.name, min, max, value
+                )
+            }
+            EngineOptionErrorKind::InvalidChoice { value, allowed } => write!(
+                f,
+                "{} must be one of {}, got {}",
+                self.name,
+                allowed.join(", "),
+                value
+            ),
+            EngineOptionErrorKind::NotABoolean { value } => {
+                write!(f, "{} must be true or false, got {}", self.name, value)
+            }
+        }
+    }
+}
+
+/// Joins validation errors for `Error::InvalidEngineOptions`.
+pub fn format_option_errors(errors: &[EngineOptionError]) -> String {
+    errors
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Checks an option against the ones the engine declared and returns the
+/// `setoption` command to send. Buttons are pressed regardless of the value.
+pub fn option_command(
+    declared: &[UciOptionConfig],
+    option: &EngineOption,
+) -> Result<String, EngineOptionError> {
+    let error = |kind| EngineOptionError {
+        name: option.name.clone(),
+        kind,
+    };
+    let config = declared
+        .iter()
+        .find(|o| option_name(o).eq_ignore_ascii_case(&option.name))
+        .ok_or_else(|| error(EngineOptionErrorKind::Unknown))?;
+    let name = option_name(config);
+    let value = option.value.trim();
+
+    match config {
+        UciOptionConfig::Spin { min, max, .. } => {
+            let parsed: i64 = value.parse().map_err(|_| {
+                error(EngineOptionErrorKind::NotANumber {
+                    value: value.to_string(),
+                })
+            })?;
+            if min.is_some_and(|min| parsed < min) || max.is_some_and(|max| parsed > max) {
+                return Err(error(EngineOptionErrorKind::OutOfRange {
+                    value: parsed,
+                    min: *min,
+                    max: *max,
+                }));
+            }
+        }
+        UciOptionConfig::Combo { var, .. } => {
+            if !var.iter().any(|v| v.eq_ignore_ascii_case(value)) {
+                return Err(error(EngineOptionErrorKind::InvalidChoice {
+                    value: value.to_string(),
+                    allowed: var.clone(),
+                }));
+            }
+        }
+        UciOptionConfig::Check { .. } => {
+            let value = value.to_ascii_lowercase();
+            if value != "true" && value != "false" {
+                return Err(error(EngineOptionErrorKind::NotABoolean { value }));
+            }
+            return Ok(format!("setoption name {} value {}", name, value));
+        }
+        UciOptionConfig::Button { .. } => return Ok(format!("setoption name {}", name)),
+        UciOptionConfig::String { .. } => {
+            return Ok(format!("setoption name {} value {}", name, option.value));
+        }
+    }
+    Ok(format!("setoption name {} value {}", name, value))
+}
+
+/// Validates all options, collecting every error.
+pub fn validate_options(
+    declared: &[UciOptionConfig],
+    options: &[EngineOption],
+) -> Vec<EngineOptionError> {
+    options
+        .iter()
+        .filter_map(|option| option_command(declared, option).err())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn declared() -> Vec<UciOptionConfig> {
+        [
+            "option name Hash type spin default 16 min 1 max 1024",
+            "option name Ponder type check default false",
+            "option name Style type combo default Normal var Solid var Normal var Risky",
+            "option name Clear Hash type button",
+            "option name SyzygyPath type string default <empty>",
+        ]
+        .iter()
+        .filter_map(|line| match vampirc_uci::parse_one(line) {
+            vampirc_uci::UciMessage::Option(option) => Some(option),
+            _ => None,
+        })
+        .collect()
+    }
+
+    fn option(name: &str, value: &str) -> EngineOption {
+        EngineOption {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn builds_commands_for_valid_options() {
+        let declared = declared();
+        assert_eq!(
+            option_command(&declared, &option("hash", "256")).unwrap(),
+            "setoption name Hash value 256"
+        );
+        assert_eq!(
+            option_command(&declared, &option("Style", "risky")).unwrap(),
+            "setoption name Style value risky"
+        );
+        assert_eq!(
+            option_command(&declared, &option("Ponder", "True")).unwrap(),
+            "setoption name Ponder value true"
+        );
+        assert_eq!(
+            option_command(&declared, &option("Clear Hash", "")).unwrap(),
+            "setoption name Clear Hash"
+        );
+        assert_eq!(
+            option_command(&declared, &option("SyzygyPath", "/tb/a b")).unwrap(),
+            "setoption name SyzygyPath value /tb/a b"
+        );
+    }
+
+    #[test]
+    fn reports_every_invalid_option() {
+        let errors = validate_options(
+            &declared(),
+            &[
+                option("Hsah", "16"),
+                option("Hash", "4096"),
+                option("Hash", "lots"),
+                option("Ponder", "yes"),
+                option("Style", "Wild"),
+                option("Ponder", "true"),
+            ],
+        );
+        let kinds: Vec<_> = errors.iter().map(|e| e.kind.clone()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                EngineOptionErrorKind::Unknown,
+                EngineOptionErrorKind::OutOfRange {
+                    value: 4096,
+                    min: Some(1),
+                    max: Some(1024)
+                },
+                EngineOptionErrorKind::NotANumber {
+                    value: "lots".to_string()
+                },
+                EngineOptionErrorKind::NotABoolean {
+                    value: "yes".to_string()
+                },
+                EngineOptionErrorKind::InvalidChoice {
+                    value: "Wild".to_string(),
+                    allowed: vec![
+                        "Solid".to_string(),
+                        "Normal".to_string(),
+                        "Risky".to_string()
+                    ]
+                },
+            ]
+        );
+    }
+}
@@ -1,42 +1,136 @@
-use std::{fmt::Display, path::PathBuf, process::Stdio};
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use log::error;
-use serde::Serialize;
-use specta::Type;
 use tokio::{
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
-    process::{Child, ChildStdin, ChildStdout, Command},
+    process::Command,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver},
 };
-use vampirc_uci::UciMessage;
+use vampirc_uci::{UciMessage, UciOptionConfig};
 
-use crate::error::Error;
+use crate::{error::Error, variant::GameVariant};
 
-use super::{normalize_uci_moves_for_fen, types::GoMode};
+use super::{
+    cecp::{CecpState, EngineProtocol},
+    limits::KillTimer,
+    logs::{EngineLog, EngineLogFilter, EngineLogs, LogDirection},
+    normalize_uci_moves_for_fen,
+    options::{option_command, validate_options, EngineOptionErrorKind},
+    supervisor::{EngineCrash, CRASH_STDERR_LINES},
+    transport::{tcp_address, Connection, EngineInput, EngineOutput, EngineSpawnConfig},
+    types::{EngineOption, GoMode},
+};
 
 #[cfg(target_os = "windows")]
 pub const CREATE_NO_WINDOW: u32 = 0x08000000;
 
-#[derive(Debug, Clone, Serialize, Type)]
-#[serde(tag = "type", content = "value", rename_all = "camelCase")]
-pub enum EngineLog {
-    Gui(String),
-    Engine(String),
+/// How long to wait for CECP `feature` lines before assuming the engine has
+/// none, and how long to wait after the engine asked for more time.
+const CECP_FEATURE_TIMEOUT: Duration = Duration::from_secs(2);
+const CECP_DONE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The output may close slightly before the process can be reaped.
+const EXIT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Reads lines from the engine. Once a CECP engine is initialized, its output
+/// is translated into UCI lines.
+pub struct EngineReader {
+    lines: Lines<BufReader<EngineOutput>>,
+    cecp: Option<(Arc<Mutex<CecpState>>, UnboundedReceiver<String>)>,
 }
 
-pub type EngineReader = Lines<BufReader<ChildStdout>>;
+impl EngineReader {
+    pub async fn next_line(&mut self) -> std::io::Result<Option<String>> {
+        let Some((state, injected)) = &mut self.cecp else {
+            return self.lines.next_line().await;
+        };
+        loop {
+            let line = tokio::select! {
+                biased;
+                Some(line) = injected.recv() => return Ok(Some(line)),
+                line = self.lines.next_line() => line?,
+            };
+            let Some(line) = line else {
+                return Ok(None);
+            };
+            if let Some(translated) = state.lock().unwrap().translate(&line) {
+                return Ok(Some(translated));
+            }
+        }
+    }
+}
 
 pub struct BaseEngine {
-    pub stdin: ChildStdin,
+    pub stdin: EngineInput,
     pub reader: Option<EngineReader>,
-    #[allow(dead_code)]
-    child: Child,
-    logs: Vec<EngineLog>,
+    connection: Connection,
+    path: PathBuf,
+    logs: EngineLogs,
+    options: Vec<UciOptionConfig>,
+    quit_requested: bool,
+    kill_timer: Option<KillTimer>,
+    cecp: Option<Arc<Mutex<CecpState>>>,
+    variant: GameVariant,
+}
+
+/// Returns the name of a declared UCI option.
+pub fn option_name(option: &UciOptionConfig) -> &str {
+    match option {
+        UciOptionConfig::Check { name, .. }
+        | UciOptionConfig::Spin { name, .. }
+        | UciOptionConfig::Combo { name, .. }
+        | UciOptionConfig::Button { name }
+        | UciOptionConfig::String { name, .. } => name,
+    }
 }
 
 impl BaseEngine {
     pub async fn spawn(path: PathBuf) -> Result<Self, Error> {
+        Self::spawn_with(path, &EngineSpawnConfig::default()).await
+    }
+
+    /// Starts the engine and initializes it with its protocol.
+    pub async fn start(path: PathBuf, config: &EngineSpawnConfig) -> Result<Self, Error> {
+        let mut engine = Self::spawn_with(path, config).await?;
+        engine.init(config.protocol).await?;
+        Ok(engine)
+    }
+
+    /// Starts a local engine, or connects to it if `path` is a `tcp://` address.
+    /// The protocol handshake is left to the caller.
+    pub async fn spawn_with(path: PathBuf, config: &EngineSpawnConfig) -> Result<Self, Error> {
+        let (engine_command, limits) = (&config.command, &config.limits);
+        let logs = match config.log_capacity {
+            Some(capacity) => EngineLogs::with_capacity(capacity as usize),
+            None => EngineLogs::default(),
+        };
+        if let Some(address) = tcp_address(&path) {
+            if !limits.is_empty() {
+                log::warn!("Resource limits are ignored for TCP engines");
+            }
+            let (connection, stdin, stdout) = Connection::connect_tcp(address).await?;
+            return Ok(Self::new(path, connection, stdin, stdout, logs, None));
+        }
+
         let mut command = Command::new(&path);
-        command.current_dir(path.parent().unwrap_or(&path));
+        command.args(&engine_command.args).envs(&engine_command.env);
+        match &engine_command.cwd {
+            Some(cwd) => {
+                command.current_dir(cwd);
+            }
+            None => {
+                // wrapper commands like `ssh` are looked up in PATH and have no parent
+                if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                    command.current_dir(parent);
+                }
+            }
+        }
         command
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
@@ -45,29 +139,88 @@ impl BaseEngine {
         #[cfg(target_os = "windows")]
         command.creation_flags(CREATE_NO_WINDOW);
 
-        let mut child = command.spawn()?;
+        limits.apply(&mut command)?;
 
+        let mut child = command.spawn()?;
         let stdin = child.stdin.take().ok_or(Error::NoStdin)?;
         let stdout = child.stdout.take().ok_or(Error::NoStdout)?;
-        let reader = BufReader::new(stdout).lines();
 
         if let Some(stderr) = child.stderr.take() {
+            let logs = logs.clone();
             tokio::spawn(async move {
                 let mut stderr_reader = BufReader::new(stderr).lines();
                 while let Ok(Some(line)) = stderr_reader.next_line().await {
                     error!("Engine stderr: {}", line);
+                    logs.push(LogDirection::Stderr, line);
                 }
             });
         }
 
-        Ok(Self {
+        let child = Arc::new(tokio::sync::Mutex::new(child));
+        let kill_timer = limits.start_kill_timer(&child);
+
+        Ok(Self::new(
+            path,
+            Connection::Process(child),
+            Box::new(stdin),
+            Box::new(stdout),
+            logs,
+            kill_timer,
+        ))
+    }
+
+    fn new(
+        path: PathBuf,
+        connection: Connection,
+        stdin: EngineInput,
+        stdout: EngineOutput,
+        logs: EngineLogs,
+        kill_timer: Option<KillTimer>,
+    ) -> Self {
+        let reader = EngineReader {
+            lines: BufReader::new(stdout).lines(),
+            cecp: None,
+        };
+        Self {
             stdin,
             reader: Some(reader),
-            child,
-            logs: Vec::new(),
+            connection,
+            path,
+            logs,
+            options: Vec::new(),
+            quit_requested: false,
+            kill_timer,
+            cecp: None,
+            variant: GameVariant::Standard,
+        }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Collects diagnostics once the engine stopped responding. Returns `None`
+    /// if the engine was asked to quit or hit its timeout, since that's not a
+    /// crash.
+    pub async fn crash_report(&mut self) -> Option<EngineCrash> {
+        if self.quit_requested || self.kill_timer.as_ref().is_some_and(KillTimer::fired) {
+            return None;
+        }
+        let status = self.connection.exit_status(EXIT_TIMEOUT).await;
+        Some(EngineCrash {
+            exit: status.map(Into::into),
+            stderr: self
+                .logs
+                .last_lines(LogDirection::Stderr, CRASH_STDERR_LINES),
         })
     }
 
+    /// Whether an I/O error means the engine is gone, rather than a malformed
+    /// line from an engine that is still running.
+    pub async fn is_gone(&mut self) -> bool {
+        self.connection.is_gone(EXIT_TIMEOUT).await
+    }
+
     pub fn take_reader(&mut self) -> Option<EngineReader> {
         self.reader.take()
     }
@@ -76,26 +229,117 @@ impl BaseEngine {
         self.reader.as_mut()
     }
 
-    pub fn get_logs(&self) -> Vec<EngineLog> {
-        self.logs.clone()
+    pub fn get_logs(&self, filter: &EngineLogFilter) -> Result<Vec<EngineLog>, Error> {
+        self.logs.filtered(filter)
+    }
+
+    pub fn export_logs(&self, path: &Path, filter: &EngineLogFilter) -> Result<(), Error> {
+        self.logs.export(path, filter)
     }
 
     fn log_gui(&mut self, cmd: &str) {
-        self.logs.push(EngineLog::Gui(format!("{}\n", cmd)));
+        self.logs.push(LogDirection::Gui, format!("{}\n", cmd));
     }
 
     pub fn log_engine(&mut self, line: &str) {
-        self.logs.push(EngineLog::Engine(line.to_string()));
+        self.logs.push(LogDirection::Engine, line.to_string());
+    }
+
+    pub fn supports_option(&self, name: &str) -> bool {
+        if let Some(cecp) = &self.cecp {
+            return cecp.lock().unwrap().supports_option(name);
+        }
+        self.options
+            .iter()
+            .any(|o| option_name(o).eq_ignore_ascii_case(name))
+    }
+
+    /// CECP engines and UCI engines without `MultiPV` only report a single line.
+    pub fn supports_multipv(&self) -> bool {
+        self.cecp.is_none() && self.supports_option("MultiPV")
+    }
+
+    pub async fn init(&mut self, protocol: EngineProtocol) -> Result<(), Error> {
+        match protocol {
+            EngineProtocol::Uci => self.init_uci().await,
+            EngineProtocol::Cecp => self.init_cecp().await,
+        }
+    }
+
+    async fn init_cecp(&mut self) -> Result<(), Error> {
+        let (injected_tx, injected_rx) = unbounded_channel();
+        let mut state = CecpState::new(injected_tx);
+
+        self.send("xboard").await?;
+        self.send("protover 2").await?;
+        let mut wait = CECP_FEATURE_TIMEOUT;
+        loop {
+            let reader = self.reader.as_mut().ok_or(Error::EngineDisconnected)?;
+            let line = match tokio::time::timeout(wait, reader.next_line()).await {
+                Ok(line) => line?,
+                // protocol version 1 engines don't announce features
+                Err(_) => break,
+            };
+            let Some(line) = line else {
+                return Err(Error::EngineDisconnected);
+            };
+            self.log_engine(&line);
+            if line.starts_with("feature") {
+                let (replies, done) = state.parse_features(&line);
+                for reply in replies {
+                    self.send(&reply).await?;
+                }
+                match done {
+                    Some(true) => break,
+                    Some(false) => wait = CECP_DONE_TIMEOUT,
+                    None => {}
+                }
+            }
+        }
+        // don't ponder on the opponent's time
+        self.send("easy").await?;
+
+        let state = Arc::new(Mutex::new(state));
+        let reader = self.reader.as_mut().ok_or(Error::EngineDisconnected)?;
+        reader.cecp = Some((state.clone(), injected_rx));
+        self.cecp = Some(state);
+        Ok(())
     }
 
     pub async fn init_uci(&mut self) -> Result<(), Error> {
         self.send("uci").await?;
-        self.wait_for("uciok").await?;
+        self.options.clear();
+        loop {
+            let line = {
+                let reader = self.reader.as_mut().ok_or(Error::EngineDisconnected)?;
+                reader.next_line().await?
+            };
+            let Some(line) = line else {
+                return Err(Error::EngineDisconnected);
+            };
+            self.logs.push(LogDirection::Engine, line.clone());
+            match vampirc_uci::parse_one(&line) {
+                UciMessage::Option(option) => self.options.push(option),
+                UciMessage::UciOk => break,
+                _ => {}
+            }
+        }
         self.send("isready").await?;
         self.wait_for("readyok").await?;
         Ok(())
     }
 
+    /// Clears the engine's state between unrelated positions. CECP engines get
+    /// `new` with every position already.
+    pub async fn new_game(&mut self) -> Result<(), Error> {
+        if self.cecp.is_some() {
+            return Ok(());
+        }
+        self.send("ucinewgame").await?;
+        self.send("isready").await?;
+        self.wait_for("readyok").await
+    }
+
     pub async fn send(&mut self, cmd: &str) -> Result<(), Error> {
         self.log_gui(cmd);
         let msg = format!("{}\n", cmd);
@@ -103,6 +347,13 @@ impl BaseEngine {
         Ok(())
     }
 
+    async fn send_all(&mut self, commands: &[String]) -> Result<(), Error> {
+        for cmd in commands {
+            self.send(cmd).await?;
+        }
+        Ok(())
+    }
+
     pub async fn wait_for(&mut self, expected: &str) -> Result<(), Error> {
         loop {
             let line = {
@@ -112,7 +363,7 @@ impl BaseEngine {
             let Some(line) = line else {
                 return Err(Error::EngineDisconnected);
             };
-            self.logs.push(EngineLog::Engine(line.clone()));
+            self.logs.push(LogDirection::Engine, line.clone());
             if line.starts_with(expected) {
                 return Ok(());
             }
@@ -123,12 +374,99 @@ impl BaseEngine {
     where
         T: Display,
     {
-        let cmd = format!("setoption name {} value {}", name, value);
-        self.send(&cmd).await
+        if let Some(cecp) = &self.cecp {
+            let cmd = cecp
+                .lock()
+                .unwrap()
+                .option_command(name, &value.to_string());
+            return match cmd {
+                Some(cmd) => self.send(&cmd).await,
+                None => Ok(()),
+            };
+        }
+        let option = EngineOption {
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+        match option_command(&self.options, &option) {
+            Ok(cmd) => self.send(&cmd).await,
+            // saved options can outlive the engine version that declared them
+            Err(e) if e.kind == EngineOptionErrorKind::Unknown => {
+                log::warn!("Skipping option {} the engine doesn't declare", name);
+                Ok(())
+            }
+            Err(e) => Err(Error::InvalidEngineOptions(vec![e])),
+        }
+    }
+
+    /// Checks options against the ones declared by a UCI engine before any of
+    /// them is sent. Undeclared options are skipped when set, not rejected.
+    pub fn check_options(&self, options: &[EngineOption]) -> Result<(), Error> {
+        if self.cecp.is_some() {
+            return Ok(());
+        }
+        let mut errors = validate_options(&self.options, options);
+        errors.retain(|e| e.kind != EngineOptionErrorKind::Unknown);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidEngineOptions(errors))
+        }
+    }
+
+    /// Engines that don't declare `UCI_Chess960` are left alone.
+    pub async fn set_chess960(&mut self, enabled: bool) -> Result<(), Error> {
+        if self.cecp.is_none() && !self.supports_option("UCI_Chess960") {
+            return Ok(());
+        }
+        self.set_option("UCI_Chess960", enabled).await
+    }
+
+    /// Selects the rules with `UCI_Variant`, using the first name of the
+    /// variant the engine declares. Engines without the option only play
+    /// standard chess.
+    pub async fn set_variant(&mut self, variant: GameVariant) -> Result<(), Error> {
+        let unsupported = || Error::UnsupportedVariant(variant.pgn_name().to_string());
+        if self.cecp.is_some() {
+            self.variant = variant;
+            return if variant.is_standard() {
+                Ok(())
+            } else {
+                Err(unsupported())
+            };
+        }
+        let declared = self
+            .options
+            .iter()
+            .find(|o| option_name(o).eq_ignore_ascii_case("UCI_Variant"));
+        let name = match declared {
+            Some(UciOptionConfig::Combo { var, .. }) => variant
+                .uci_names()
+                .iter()
+                .find(|name| var.iter().any(|v| v.eq_ignore_ascii_case(name)))
+                .copied(),
+            Some(_) => variant.uci_names().first().copied(),
+            None if variant.is_standard() => {
+                self.variant = variant;
+                return Ok(());
+            }
+            None => None,
+        };
+        let name = name.ok_or_else(unsupported)?;
+        self.set_option("UCI_Variant", name).await?;
+        self.variant = variant;
+        Ok(())
     }
 
     pub async fn set_position(&mut self, fen: &str, moves: &[String]) -> Result<(), Error> {
-        let normalized_moves = normalize_uci_moves_for_fen(fen, moves)?;
+        let normalized_moves = normalize_uci_moves_for_fen(fen, moves, self.variant)?;
+        if let Some(cecp) = &self.cecp {
+            let commands = cecp
+                .lock()
+                .unwrap()
+                .position_commands(fen, &normalized_moves)?;
+            return self.send_all(&commands).await;
+        }
         let cmd = if moves.is_empty() {
             format!("position fen {}", fen)
         } else {
@@ -138,22 +476,31 @@ impl BaseEngine {
     }
 
     pub async fn go(&mut self, mode: &GoMode) -> Result<(), Error> {
+        if let Some(cecp) = &self.cecp {
+            let commands = cecp.lock().unwrap().go_commands(mode)?;
+            return self.send_all(&commands).await;
+        }
         let cmd = mode.to_uci_string();
         self.send(&cmd).await
     }
 
     pub async fn stop(&mut self) -> Result<(), Error> {
+        if let Some(cecp) = &self.cecp {
+            let commands = cecp.lock().unwrap().stop_commands();
+            return self.send_all(&commands).await;
+        }
         self.send("stop").await
     }
 
     pub async fn quit(&mut self) -> Result<(), Error> {
+        self.quit_requested = true;
         self.send("quit").await
     }
 
     pub async fn wait_for_bestmove(&mut self) -> Result<String, Error> {
         let reader = self.reader.as_mut().ok_or(Error::EngineDisconnected)?;
         while let Some(line) = reader.next_line().await? {
-            self.logs.push(EngineLog::Engine(line.clone()));
+            self.logs.push(LogDirection::Engine, line.clone());
             if let UciMessage::BestMove { best_move, .. } = vampirc_uci::parse_one(&line) {
                 return Ok(best_move.to_string());
             }
@@ -162,12 +509,83 @@ impl BaseEngine {
     }
 
     pub fn kill_sync(&mut self) {
-        let _ = self.child.start_kill();
+        self.quit_requested = true;
+        self.connection.kill();
     }
 }
 
 impl Drop for BaseEngine {
     fn drop(&mut self) {
-        let _ = self.child.start_kill();
+        self.connection.kill();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Serves a minimal UCI engine on a local socket.
+    async fn fake_tcp_engine() -> (String, tokio::task::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("tcp://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+            let mut received = Vec::new();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let reply = match line.as_str() {
+                    "uci" => {
+                        "id name Fake\noption name Hash type spin default 16 min 1 max 64\nuciok\n"
+                    }
+                    "isready" => "readyok\n",
+                    _ => "",
+                };
+                received.push(line);
+                write.write_all(reply.as_bytes()).await.unwrap();
+            }
+            received
+        });
+        (address, server)
+    }
+
+    #[tokio::test]
+    async fn uci_over_tcp() {
+        let (address, server) = fake_tcp_engine().await;
+        let mut engine = BaseEngine::spawn(PathBuf::from(address)).await.unwrap();
+        engine.init(EngineProtocol::Uci).await.unwrap();
+        assert!(engine.supports_option("hash"));
+        let logs = engine.get_logs(&EngineLogFilter::default()).unwrap();
+        assert!(matches!(
+            logs.last(),
+            Some(EngineLog { direction: LogDirection::Engine, value, .. }) if value == "readyok"
+        ));
+
+        engine.kill_sync();
+        assert_eq!(server.await.unwrap(), vec!["uci", "isready"]);
+        assert!(engine.crash_report().await.is_none());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn running_engines_are_not_gone() {
+        let mut engine = BaseEngine::spawn(PathBuf::from("/bin/cat")).await.unwrap();
+        assert!(!engine.is_gone().await);
+        engine.connection.kill();
+        assert!(engine.is_gone().await);
+        assert!(engine.crash_report().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn tcp_connection_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("tcp://{}", listener.local_addr().unwrap());
+        drop(listener);
+        assert!(matches!(
+            BaseEngine::spawn(PathBuf::from(address)).await,
+            Err(Error::EngineConnection(_))
+        ));
     }
 }
@@ -0,0 +1,77 @@
+use std::process::ExitStatus;
+
+use serde::Serialize;
+use specta::Type;
+use tauri_specta::Event;
+
+/// How many times a crashed engine is restarted before giving up.
+pub const MAX_ENGINE_RESTARTS: u32 = 3;
+
+/// Number of stderr lines included in crash reports.
+pub const CRASH_STDERR_LINES: usize = 50;
+
+#[derive(Debug, Clone, Serialize, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct EngineExit {
+    pub code: Option<i32>,
+    /// Signal that terminated the process (Unix only).
+    pub signal: Option<i32>,
+}
+
+impl From<ExitStatus> for EngineExit {
+    fn from(status: ExitStatus) -> Self {
+        #[cfg(unix)]
+        let signal = {
+            use std::os::unix::process::ExitStatusExt;
+            status.signal()
+        };
+        #[cfg(not(unix))]
+        let signal = None;
+        Self {
+            code: status.code(),
+            signal,
+        }
+    }
+}
+
+/// Diagnostics collected after an engine stopped unexpectedly.
+#[derive(Debug, Clone, Serialize, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct EngineCrash {
+    /// `None` if the process closed its output but could not be reaped.
+    pub exit: Option<EngineExit>,
+    pub stderr: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Type, Event)]
+#[serde(rename_all = "camelCase")]
+pub struct EngineCrashed {
+    /// Engine id for analysis, game id for engine games.
+    pub id: String,
+    pub tab: Option<String>,
+    pub path: String,
+    pub crash: EngineCrash,
+    /// Number of crashes so far, including this one.
+    pub attempt: u32,
+    /// Whether the engine is being restarted with its last settings.
+    pub restarting: bool,
+}
+
+impl EngineCrashed {
+    pub fn new(
+        id: String,
+        tab: Option<String>,
+        path: String,
+        crash: EngineCrash,
+        attempt: u32,
+    ) -> Self {
+        Self {
+            id,
+            tab,
+            path,
+            crash,
+            attempt,
+            restarting: attempt <= MAX_ENGINE_RESTARTS,
+        }
+    }
+}
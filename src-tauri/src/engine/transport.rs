@@ -0,0 +1,127 @@
+use std::{
+    collections::HashMap,
+    net::Shutdown,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    process::Child,
+    sync::Mutex,
+};
+
+use crate::{error::Error, variant::GameVariant};
+
+use super::{cecp::EngineProtocol, limits::ResourceLimits};
+
+/// Engine paths starting with this prefix are UCI engines served over a TCP
+/// socket, like `tcp://analysis-box:9000`.
+const TCP_PREFIX: &str = "tcp://";
+
+pub type EngineInput = Box<dyn AsyncWrite + Send + Unpin>;
+pub type EngineOutput = Box<dyn AsyncRead + Send + Unpin>;
+
+/// How to launch a local engine. Lets the engine path be a wrapper (a script,
+/// `ssh`, `docker`...) that needs arguments.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, Type, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct EngineCommand {
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Defaults to the directory containing the engine.
+    pub cwd: Option<PathBuf>,
+}
+
+/// How an engine is started, shared by the options of everything that spawns
+/// one. Changing it has no effect on a running engine.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, Type, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct EngineSpawnConfig {
+    #[serde(default)]
+    pub command: EngineCommand,
+    #[serde(default)]
+    pub limits: ResourceLimits,
+    #[serde(default)]
+    pub protocol: EngineProtocol,
+    /// Games are played in their own variant instead.
+    #[serde(default)]
+    pub variant: GameVariant,
+    /// Lines kept in the engine's log, the oldest are dropped first.
+    #[serde(default)]
+    pub log_capacity: Option<u32>,
+}
+
+/// Returns the `host:port` of a TCP engine path.
+pub fn tcp_address(path: &Path) -> Option<&str> {
+    path.to_str()?.strip_prefix(TCP_PREFIX)
+}
+
+/// What the engine's input and output are attached to.
+pub enum Connection {
+    /// Shared with the kill timer of the resource limits.
+    Process(Arc<Mutex<Child>>),
+    /// A clone of the socket, so it can be shut down without the async halves.
+    Tcp(std::net::TcpStream),
+}
+
+impl Connection {
+    pub async fn connect_tcp(address: &str) -> Result<(Self, EngineInput, EngineOutput), Error> {
+        let connect = async {
+            let stream = TcpStream::connect(address).await?;
+            stream.set_nodelay(true)?;
+            let stream = stream.into_std()?;
+            let handle = stream.try_clone()?;
+            let (read, write) = TcpStream::from_std(stream)?.into_split();
+            Ok::<_, std::io::Error>((handle, read, write))
+        };
+        let (handle, read, write) = connect
+            .await
+            .map_err(|e| Error::EngineConnection(format!("{}: {}", address, e)))?;
+        Ok((Self::Tcp(handle), Box::new(write), Box::new(read)))
+    }
+
+    pub fn kill(&mut self) {
+        match self {
+            Self::Process(child) => {
+                // the kill timer only holds it to kill the engine as well
+                if let Ok(mut child) = child.try_lock() {
+                    let _ = child.start_kill();
+                }
+            }
+            Self::Tcp(stream) => {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    /// Exit status of a local engine that stopped, if it can be reaped in time.
+    pub async fn exit_status(&mut self, timeout: Duration) -> Option<ExitStatus> {
+        match self {
+            Self::Process(child) => {
+                let mut child = child.lock().await;
+                tokio::time::timeout(timeout, child.wait())
+                    .await
+                    .ok()
+                    .and_then(Result::ok)
+            }
+            Self::Tcp(_) => None,
+        }
+    }
+
+    /// Whether the engine is gone after a failed read or write: a local engine
+    /// that exited within `timeout`, or a socket, which doesn't recover.
+    pub async fn is_gone(&mut self, timeout: Duration) -> bool {
+        match self {
+            Self::Process(_) => self.exit_status(timeout).await.is_some(),
+            Self::Tcp(_) => true,
+        }
+    }
+}
@@ -1,6 +1,9 @@
-use shakmaty::{fen::Fen, uci::UciMove, CastlingMode, Chess, Position};
+use shakmaty::{
+    fen::Fen, uci::UciMove, variant::VariantPosition, CastlingMode, Chess, EnPassantMode,
+    FromSetup, Position,
+};
 
-use crate::error::Error;
+use crate::{error::Error, variant::GameVariant};
 
 pub fn parse_fen_to_position(fen: &str) -> Result<Chess, Error> {
     let fen: Fen = fen.parse()?;
@@ -12,7 +15,17 @@ pub fn parse_fen_to_position(fen: &str) -> Result<Chess, Error> {
     }
 }
 
-pub fn apply_uci_moves(pos: &mut Chess, moves: &[String]) -> Result<(), Error> {
+pub fn parse_variant_fen(fen: &str, variant: GameVariant) -> Result<VariantPosition, Error> {
+    let fen: Fen = fen.parse()?;
+    let setup = fen.as_setup().clone();
+    let castling_mode = CastlingMode::detect(&setup);
+    match variant.position(setup, castling_mode) {
+        Ok(p) => Ok(p),
+        Err(e) => Ok(e.ignore_too_much_material()?),
+    }
+}
+
+pub fn apply_uci_moves<P: Position>(pos: &mut P, moves: &[String]) -> Result<(), Error> {
     for m in moves {
         let uci = UciMove::from_ascii(m.as_bytes())?;
         let mv = uci.to_move(pos)?;
@@ -27,14 +40,23 @@ pub fn parse_fen_and_apply_moves(fen: &str, moves: &[String]) -> Result<Chess, E
     Ok(pos)
 }
 
-pub fn normalize_uci_moves_for_fen(fen: &str, moves: &[String]) -> Result<Vec<String>, Error> {
-    let fen: Fen = fen.parse()?;
-    let setup = fen.as_setup().clone();
-    let castling_mode = CastlingMode::detect(&setup);
-    let mut pos = match setup.position(castling_mode) {
-        Ok(p) => p,
-        Err(e) => e.ignore_too_much_material()?,
-    };
+pub fn parse_variant_fen_and_apply_moves(
+    fen: &str,
+    moves: &[String],
+    variant: GameVariant,
+) -> Result<VariantPosition, Error> {
+    let mut pos = parse_variant_fen(fen, variant)?;
+    apply_uci_moves(&mut pos, moves)?;
+    Ok(pos)
+}
+
+pub fn normalize_uci_moves_for_fen(
+    fen: &str,
+    moves: &[String],
+    variant: GameVariant,
+) -> Result<Vec<String>, Error> {
+    let castling_mode = CastlingMode::detect(fen.parse::<Fen>()?.as_setup());
+    let mut pos = parse_variant_fen(fen, variant)?;
 
     let mut normalized_moves = Vec::with_capacity(moves.len());
 
@@ -47,3 +69,22 @@ pub fn normalize_uci_moves_for_fen(fen: &str, moves: &[String]) -> Result<Vec<St
 
     Ok(normalized_moves)
 }
+
+/// Passes the turn to the opponent without moving (a null move).
+///
+/// Returns `None` when the side to move is in check, since the resulting
+/// position would be illegal.
+pub fn null_move_position(pos: &Chess) -> Option<Chess> {
+    if pos.is_check() {
+        return None;
+    }
+    let mut setup = pos.clone().into_setup(EnPassantMode::Legal);
+    if setup.turn.is_black() {
+        setup.fullmoves = setup.fullmoves.saturating_add(1);
+    }
+    setup.turn = !setup.turn;
+    setup.ep_square = None;
+    setup.halfmoves = setup.halfmoves.saturating_add(1);
+    let castling_mode = CastlingMode::detect(&setup);
+    Chess::from_setup(setup, castling_mode).ok()
+}
@@ -0,0 +1,130 @@
+use shakmaty::Board;
+use vampirc_uci::uci::ScoreValue;
+
+/// Coefficients of the material-based win rate model used by Stockfish 16,
+/// fitted on counts in `[17, 78]` and anchored at a material count of 58.
+/// See <https://github.com/official-stockfish/WDL_model>.
+const MATERIAL_AS: [f64; 4] = [-150.77043883, 394.96159472, -321.73403766, 406.15850091];
+const MATERIAL_BS: [f64; 4] = [62.33245393, -91.35491995, 40.32985732, 51.77152785];
+
+/// Coefficients of the older ply-based model (Stockfish 15.1), anchored at ply 64.
+/// Only used when the material on the board is not known.
+const PLY_AS: [f64; 4] = [0.38036525, -2.82015070, 23.17882135, 307.36768407];
+const PLY_BS: [f64; 4] = [-2.29434733, 13.27689788, -14.26828904, 63.45318330];
+
+/// Stockfish's material count: pawns are worth 1, minors 3, rooks 5 and queens 9.
+pub fn wdl_material(board: &Board) -> u32 {
+    let material = board.material();
+    [material.white, material.black]
+        .iter()
+        .map(|m| {
+            m.pawn as u32
+                + 3 * m.knight as u32
+                + 3 * m.bishop as u32
+                + 5 * m.rook as u32
+                + 9 * m.queen as u32
+        })
+        .sum()
+}
+
+fn polynomial(coefficients: &[f64; 4], m: f64) -> f64 {
+    ((coefficients[0] * m + coefficients[1]) * m + coefficients[2]) * m + coefficients[3]
+}
+
+/// Returns the `(a, b)` parameters of the logistic model and the value that
+/// corresponds to one pawn (`a` at the anchor point).
+fn win_rate_params(ply: u32, material: Option<u32>) -> (f64, f64, f64) {
+    let (coefficients_a, coefficients_b, m) = match material {
+        Some(material) => (
+            &MATERIAL_AS,
+            &MATERIAL_BS,
+            material.clamp(17, 78) as f64 / 58.0,
+        ),
+        None => (&PLY_AS, &PLY_BS, ply.min(240) as f64 / 64.0),
+    };
+    let pawn_value = coefficients_a.iter().sum::<f64>();
+    (
+        polynomial(coefficients_a, m),
+        polynomial(coefficients_b, m),
+        pawn_value,
+    )
+}
+
+/// Win probability in per mille for a centipawn score from the side to move's
+/// point of view.
+fn win_rate(cp: f64, ply: u32, material: Option<u32>) -> f64 {
+    let (a, b, pawn_value) = win_rate_params(ply, material);
+    // UCI centipawns are normalized so that 100cp is a 50% win rate at the
+    // anchor, so convert them back to the model's internal units first.
+    let v = (cp * pawn_value / 100.0).clamp(-4000.0, 4000.0);
+    1000.0 / (1.0 + ((a - v) / b).exp())
+}
+
+/// Estimates win/draw/loss probabilities (in per mille, from the side to move's
+/// point of view) for engines that don't report `wdl` themselves.
+pub fn wdl_from_score(value: &ScoreValue, ply: u32, material: Option<u32>) -> (u32, u32, u32) {
+    match *value {
+        ScoreValue::Mate(m) if m > 0 => (1000, 0, 0),
+        ScoreValue::Mate(_) => (0, 0, 1000),
+        ScoreValue::Cp(cp) => {
+            let cp = f64::from(cp);
+            let win = win_rate(cp, ply, material).round() as u32;
+            let loss = win_rate(-cp, ply, material).round() as u32;
+            (win, 1000u32.saturating_sub(win + loss), loss)
+        }
+    }
+}
+
+/// Normalized expected score in `[0, 1]`: a win counts as 1 and a draw as 0.5.
+pub fn expected_score(win: f64, draw: f64, loss: f64) -> f64 {
+    let total = win + draw + loss;
+    if total <= 0.0 {
+        return 0.5;
+    }
+    (win + draw / 2.0) / total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_position_is_balanced() {
+        let (w, d, l) = wdl_from_score(&ScoreValue::Cp(0), 0, Some(78));
+        assert_eq!(w, l);
+        assert_eq!(w + d + l, 1000);
+        assert!((expected_score(w as f64, d as f64, l as f64) - 0.5).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn one_pawn_at_anchor_is_half_win() {
+        let (w, _, _) = wdl_from_score(&ScoreValue::Cp(100), 0, Some(58));
+        assert_eq!(w, 500);
+
+        let (w, _, _) = wdl_from_score(&ScoreValue::Cp(100), 64, None);
+        assert_eq!(w, 500);
+    }
+
+    #[test]
+    fn expected_score_is_monotonic() {
+        let mut last = 0.0;
+        for cp in (-1000..=1000).step_by(50) {
+            let (w, d, l) = wdl_from_score(&ScoreValue::Cp(cp), 0, Some(40));
+            let score = expected_score(w as f64, d as f64, l as f64);
+            assert!(score >= last);
+            last = score;
+        }
+    }
+
+    #[test]
+    fn mate_scores() {
+        assert_eq!(wdl_from_score(&ScoreValue::Mate(3), 0, None), (1000, 0, 0));
+        assert_eq!(wdl_from_score(&ScoreValue::Mate(-2), 0, None), (0, 0, 1000));
+        assert_eq!(wdl_from_score(&ScoreValue::Mate(0), 0, None), (0, 0, 1000));
+    }
+
+    #[test]
+    fn start_position_material() {
+        assert_eq!(wdl_material(&Board::default()), 78);
+    }
+}
@@ -0,0 +1,521 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use futures_util::future::try_join_all;
+use serde::{Deserialize, Serialize};
+use shakmaty::{
+    fen::Fen, san::San, uci::UciMove, CastlingMode, Chess, FromSetup, Move, Position, PositionError,
+};
+use specta::Type;
+use vampirc_uci::{parse_one, UciInfoAttribute, UciMessage};
+
+use crate::{
+    engine::{BaseEngine, EngineOption, EngineSpawnConfig, GoMode},
+    error::Error,
+    progress::update_progress,
+    AppState,
+};
+
+/// A move worth points in an STS-style `c0` opcode.
+#[derive(Serialize, Deserialize, Debug, Clone, Type, PartialEq, Eq)]
+pub struct EpdPoints {
+    pub san: String,
+    pub points: u32,
+}
+
+/// A test position read from an EPD file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EpdPosition {
+    pub id: Option<String>,
+    pub fen: String,
+    /// Best moves (`bm`), in SAN.
+    pub best_moves: Vec<String>,
+    /// Moves to avoid (`am`), in SAN.
+    pub avoid_moves: Vec<String>,
+    pub points: Vec<EpdPoints>,
+}
+
+/// Splits the operations part of an EPD line on `;`, ignoring the ones inside
+/// quoted strings.
+fn split_operations(ops: &str) -> Vec<&str> {
+    let mut operations = Vec::new();
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (i, c) in ops.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => {
+                operations.push(ops[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    operations.push(ops[start..].trim());
+    operations.retain(|op| !op.is_empty());
+    operations
+}
+
+/// Parses STS points like `"f5=10, Be5+=2, Bf2=3"`.
+fn parse_points(value: &str) -> Vec<EpdPoints> {
+    value
+        .split(',')
+        .filter_map(|entry| {
+            let (san, points) = entry.trim().split_once('=')?;
+            Some(EpdPoints {
+                san: san.trim().to_string(),
+                points: points.trim().parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+pub fn parse_epd_line(line: &str) -> Result<EpdPosition, Error> {
+    let invalid = || Error::InvalidEpd(line.to_string());
+    // fields can be separated by any run of whitespace, the operations are
+    // the rest of the line
+    let mut ops = line.trim_start();
+    let mut board = Vec::with_capacity(4);
+    for _ in 0..4 {
+        let end = ops.find(char::is_whitespace).unwrap_or(ops.len());
+        if end == 0 {
+            return Err(invalid());
+        }
+        board.push(&ops[..end]);
+        ops = ops[end..].trim_start();
+    }
+
+    let mut position = EpdPosition {
+        id: None,
+        fen: String::new(),
+        best_moves: Vec::new(),
+        avoid_moves: Vec::new(),
+        points: Vec::new(),
+    };
+    let mut halfmoves = "0".to_string();
+    let mut fullmoves = "1".to_string();
+    for op in split_operations(ops) {
+        let (opcode, operand) = op.split_once(char::is_whitespace).unwrap_or((op, ""));
+        let operand = operand.trim();
+        let unquoted = operand.trim_matches('"');
+        match opcode {
+            "id" => position.id = Some(unquoted.to_string()),
+            "bm" => position.best_moves = operand.split_whitespace().map(String::from).collect(),
+            "am" => position.avoid_moves = operand.split_whitespace().map(String::from).collect(),
+            "c0" => {
+                let points = parse_points(unquoted);
+                if !points.is_empty() {
+                    position.points = points;
+                }
+            }
+            "hmvc" => halfmoves = operand.to_string(),
+            "fmvn" => fullmoves = operand.to_string(),
+            _ => {}
+        }
+    }
+    position.fen = format!("{} {} {}", board.join(" "), halfmoves, fullmoves);
+    position.fen.parse::<Fen>().map_err(|_| invalid())?;
+    Ok(position)
+}
+
+pub fn parse_epd(text: &str) -> Result<Vec<EpdPosition>, Error> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(parse_epd_line)
+        .collect()
+}
+
+/// Resolves the SAN moves of a position so they can be compared with the
+/// engine's move.
+struct Solution {
+    position: Chess,
+    castling_mode: CastlingMode,
+    best: Vec<Move>,
+    avoid: Vec<Move>,
+    points: Vec<(Move, u32)>,
+}
+
+impl Solution {
+    fn new(epd: &EpdPosition) -> Result<Self, Error> {
+        let fen: Fen = epd.fen.parse()?;
+        let castling_mode = CastlingMode::detect(fen.as_setup());
+        let position = Chess::from_setup(fen.as_setup().clone(), castling_mode)
+            .or_else(PositionError::ignore_too_much_material)?;
+        let resolve = |san: &str| -> Result<Move, Error> {
+            let parsed: San = san
+                .trim_end_matches(['+', '#', '!', '?'])
+                .parse()
+                .map_err(|_| Error::InvalidEpd(format!("{}: {}", epd.fen, san)))?;
+            parsed
+                .to_move(&position)
+                .map_err(|_| Error::InvalidEpd(format!("{}: illegal move {}", epd.fen, san)))
+        };
+        let best = epd
+            .best_moves
+            .iter()
+            .map(|m| resolve(m))
+            .collect::<Result<_, _>>()?;
+        let avoid = epd
+            .avoid_moves
+            .iter()
+            .map(|m| resolve(m))
+            .collect::<Result<_, _>>()?;
+        let points = epd
+            .points
+            .iter()
+            .map(|p| Ok((resolve(&p.san)?, p.points)))
+            .collect::<Result<_, Error>>()?;
+        Ok(Self {
+            position,
+            castling_mode,
+            best,
+            avoid,
+            points,
+        })
+    }
+
+    fn max_points(&self) -> u32 {
+        if self.points.is_empty() {
+            1
+        } else {
+            self.points.iter().map(|(_, p)| *p).max().unwrap_or(0)
+        }
+    }
+
+    /// Points earned by playing `m`. Without `c0` points a position is worth
+    /// one point when the move is a `bm` and not an `am`.
+    fn points(&self, m: &Move) -> u32 {
+        if !self.points.is_empty() {
+            return self
+                .points
+                .iter()
+                .find(|(p, _)| p == m)
+                .map_or(0, |(_, points)| *points);
+        }
+        let best = self.best.is_empty() || self.best.contains(m);
+        let avoided = self.avoid.contains(m);
+        u32::from(best && !avoided)
+    }
+
+    fn parse_move(&self, uci: &str) -> Option<Move> {
+        uci.parse::<UciMove>().ok()?.to_move(&self.position).ok()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct EpdPositionResult {
+    pub id: Option<String>,
+    pub fen: String,
+    /// The engine's move in SAN, if it returned a legal one.
+    pub engine_move: Option<String>,
+    pub solved: bool,
+    pub points: u32,
+    pub max_points: u32,
+    /// Time after which the engine settled on its final move, when solved.
+    pub time_ms: Option<u64>,
+    pub depth: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct EpdSuiteResult {
+    pub positions: Vec<EpdPositionResult>,
+    pub solved: u32,
+    pub total: u32,
+    pub score: u32,
+    pub max_score: u32,
+}
+
+impl EpdSuiteResult {
+    fn new(positions: Vec<EpdPositionResult>) -> Self {
+        Self {
+            solved: positions.iter().filter(|p| p.solved).count() as u32,
+            total: positions.len() as u32,
+            score: positions.iter().map(|p| p.points).sum(),
+            max_score: positions.iter().map(|p| p.max_points).sum(),
+            positions,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct EpdRunOptions {
+    /// Number of engine instances searching in parallel.
+    pub concurrency: u32,
+    /// Suites are standard chess, other variants are rejected.
+    #[serde(flatten)]
+    pub spawn: EngineSpawnConfig,
+}
+
+/// Searches one position, tracking since when the engine's main line has
+/// started with a move worth full points.
+async fn search_position(
+    engine: &mut BaseEngine,
+    epd: &EpdPosition,
+    go_mode: &GoMode,
+    cancel_flag: &AtomicBool,
+) -> Result<EpdPositionResult, Error> {
+    let solution = Solution::new(epd)?;
+    let max_points = solution.max_points();
+
+    engine.new_game().await?;
+    engine
+        .set_chess960(solution.castling_mode.is_chess960())
+        .await?;
+    engine.set_position(&epd.fen, &[]).await?;
+    engine.go(go_mode).await?;
+    let start = Instant::now();
+
+    let mut solved_since = None;
+    let mut depth = 0;
+    let best_move = loop {
+        if cancel_flag.load(Ordering::SeqCst) {
+            engine.stop().await?;
+            return Err(Error::AnalysisCancelled);
+        }
+        let line = {
+            let reader = engine.reader_mut().ok_or(Error::EngineDisconnected)?;
+            reader.next_line().await?
+        };
+        let Some(line) = line else {
+            return Err(Error::EngineDisconnected);
+        };
+        engine.log_engine(&line);
+        match parse_one(&line) {
+            UciMessage::Info(attrs) => {
+                let mut first = None;
+                let mut multipv = 1;
+                let mut line_depth = None;
+                for attr in attrs {
+                    match attr {
+                        UciInfoAttribute::Pv(pv) => first = pv.first().map(|m| m.to_string()),
+                        UciInfoAttribute::MultiPv(n) => multipv = n,
+                        UciInfoAttribute::Depth(d) => line_depth = Some(d),
+                        _ => {}
+                    }
+                }
+                let Some(first) = first.filter(|_| multipv == 1) else {
+                    continue;
+                };
+                depth = line_depth.unwrap_or(depth);
+                let full = solution
+                    .parse_move(&first)
+                    .is_some_and(|m| solution.points(&m) == max_points);
+                if !full {
+                    solved_since = None;
+                } else if solved_since.is_none() {
+                    solved_since = Some(start.elapsed());
+                }
+            }
+            UciMessage::BestMove { best_move, .. } => break best_move.to_string(),
+            _ => {}
+        }
+    };
+    let elapsed = start.elapsed();
+
+    let engine_move = solution.parse_move(&best_move);
+    let points = engine_move.as_ref().map_or(0, |m| solution.points(m));
+    let solved = points == max_points;
+    Ok(EpdPositionResult {
+        id: epd.id.clone(),
+        fen: epd.fen.clone(),
+        engine_move: engine_move.map(|m| San::from_move(&solution.position, &m).to_string()),
+        solved,
+        points,
+        max_points,
+        time_ms: solved.then(|| solved_since.unwrap_or(elapsed).as_millis() as u64),
+        depth,
+    })
+}
+
+#[tauri::command]
+#[specta::specta]
+#[allow(clippy::too_many_arguments)]
+pub async fn run_epd_suite(
+    id: String,
+    engine: String,
+    file: PathBuf,
+    go_mode: GoMode,
+    options: EpdRunOptions,
+    uci_options: Vec<EngineOption>,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<EpdSuiteResult, Error> {
+    let positions = parse_epd(&tokio::fs::read_to_string(&file).await?)?;
+    if matches!(go_mode, GoMode::Infinite) {
+        return Err(Error::InvalidEpd(
+            "Test suites need a search limit".to_string(),
+        ));
+    }
+    if !options.spawn.variant.is_standard() {
+        return Err(Error::UnsupportedVariant(
+            options.spawn.variant.pgn_name().to_string(),
+        ));
+    }
+    options.spawn.limits.validate(&uci_options)?;
+
+    let cancel_flag = Arc::new(AtomicBool::new(false));
+    state
+        .analysis_cancel_flags
+        .insert(id.clone(), cancel_flag.clone());
+
+    let total = positions.len();
+    let next = Mutex::new(0);
+    let results: Mutex<Vec<Option<EpdPositionResult>>> = Mutex::new(vec![None; total]);
+    let workers = (options.concurrency.max(1) as usize).min(total.max(1));
+
+    let worker = |_| {
+        let (engine, positions, go_mode, options, uci_options) =
+            (&engine, &positions, &go_mode, &options, &uci_options);
+        let (next, results, cancel_flag, state, app, id) =
+            (&next, &results, &cancel_flag, &state, &app, &id);
+        async move {
+            let mut engine = BaseEngine::start(PathBuf::from(engine), &options.spawn).await?;
+            engine.check_options(uci_options)?;
+            for option in uci_options {
+                engine.set_option(&option.name, &option.value).await?;
+            }
+            loop {
+                let i = {
+                    let mut next = next.lock().unwrap();
+                    *next += 1;
+                    *next - 1
+                };
+                let Some(epd) = positions.get(i) else {
+                    break;
+                };
+                let result = search_position(&mut engine, epd, go_mode, cancel_flag).await?;
+                let done = {
+                    let mut results = results.lock().unwrap();
+                    results[i] = Some(result);
+                    results.iter().filter(|r| r.is_some()).count()
+                };
+                update_progress(
+                    &state.progress_state,
+                    app,
+                    id.clone(),
+                    (done as f32 / total as f32) * 100.0,
+                    false,
+                )?;
+            }
+            engine.quit().await?;
+            Ok::<_, Error>(())
+        }
+    };
+
+    let run = try_join_all((0..workers).map(worker)).await;
+    state.analysis_cancel_flags.remove(&id);
+    run?;
+
+    update_progress(&state.progress_state, &app, id, 100.0, true)?;
+    let positions = results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .flatten()
+        .collect();
+    Ok(EpdSuiteResult::new(positions))
+}
+
+#[tauri::command]
+#[specta::specta]
+pub fn export_epd_results(results: EpdSuiteResult, file: PathBuf) -> Result<(), Error> {
+    let mut writer = csv::Writer::from_path(file)?;
+    writer.write_record([
+        "id",
+        "fen",
+        "engine_move",
+        "solved",
+        "points",
+        "max_points",
+        "time_ms",
+        "depth",
+    ])?;
+    for p in &results.positions {
+        writer.write_record([
+            p.id.clone().unwrap_or_default(),
+            p.fen.clone(),
+            p.engine_move.clone().unwrap_or_default(),
+            p.solved.to_string(),
+            p.points.to_string(),
+            p.max_points.to_string(),
+            p.time_ms.map(|t| t.to_string()).unwrap_or_default(),
+            p.depth.to_string(),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_wac_line() {
+        let epd = parse_epd_line(
+            r#"2rr3k/pp3pp1/1nnqbN1p/3pN3/2pP4/2P3Q1/PPB4P/R4RK1 w - - bm Qg6; id "WAC.001";"#,
+        )
+        .unwrap();
+        assert_eq!(epd.id.as_deref(), Some("WAC.001"));
+        assert_eq!(epd.best_moves, vec!["Qg6"]);
+        assert_eq!(
+            epd.fen,
+            "2rr3k/pp3pp1/1nnqbN1p/3pN3/2pP4/2P3Q1/PPB4P/R4RK1 w - - 0 1"
+        );
+    }
+
+    #[test]
+    fn parses_fields_separated_by_several_spaces() {
+        let epd = parse_epd_line("4k3/8/8/8/8/8/4P3/4K3  w\t-   -  bm e4;").unwrap();
+        assert_eq!(epd.fen, "4k3/8/8/8/8/8/4P3/4K3 w - - 0 1");
+        assert_eq!(epd.best_moves, vec!["e4"]);
+    }
+
+    #[test]
+    fn parses_sts_points() {
+        let epd = parse_epd_line(
+            r#"1kr5/3n4/q3p2p/p2n2p1/PppB1P2/5BP1/1P2Q2P/3R2K1 w - - bm f5; id "STS(v1.0) Undermine.001"; c0 "f5=10, Be5+=2, Bf2=3, Bg4=2";"#,
+        )
+        .unwrap();
+        assert_eq!(epd.id.as_deref(), Some("STS(v1.0) Undermine.001"));
+        assert_eq!(epd.points.len(), 4);
+        assert_eq!(
+            epd.points[1],
+            EpdPoints {
+                san: "Be5+".to_string(),
+                points: 2
+            }
+        );
+
+        let solution = Solution::new(&epd).unwrap();
+        assert_eq!(solution.max_points(), 10);
+        let bishop = solution.parse_move("d4e5").unwrap();
+        assert_eq!(solution.points(&bishop), 2);
+        let other = solution.parse_move("g1g2").unwrap();
+        assert_eq!(solution.points(&other), 0);
+    }
+
+    #[test]
+    fn avoid_moves() {
+        let epd = parse_epd_line("4k3/8/8/8/8/8/4P3/4K3 w - - am e4; id \"avoid\";").unwrap();
+        let solution = Solution::new(&epd).unwrap();
+        assert_eq!(solution.points(&solution.parse_move("e2e4").unwrap()), 0);
+        assert_eq!(solution.points(&solution.parse_move("e2e3").unwrap()), 1);
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        assert!(parse_epd_line("not an epd").is_err());
+        assert!(parse_epd("# comment\n\n4k3/8/8/8/8/8/8/4K3 w - - id \"x\";").is_ok());
+    }
+}
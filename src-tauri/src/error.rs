@@ -1,4 +1,4 @@
-use shakmaty::Chess;
+use shakmaty::{variant::VariantPosition, Chess};
 use specta::Type;
 
 #[derive(Debug, thiserror::Error)]
@@ -21,9 +21,21 @@ pub enum Error {
     #[error(transparent)]
     Reqwest(Box<reqwest::Error>),
 
+    #[error(transparent)]
+    Csv(Box<csv::Error>),
+
+    #[error(transparent)]
+    Json(Box<serde_json::Error>),
+
+    #[error(transparent)]
+    Regex(Box<regex::Error>),
+
     #[error(transparent)]
     ChessPosition(Box<shakmaty::PositionError<Chess>>),
 
+    #[error(transparent)]
+    VariantPosition(Box<shakmaty::PositionError<VariantPosition>>),
+
     #[error(transparent)]
     IllegalUciMove(Box<shakmaty::uci::IllegalUciMoveError>),
 
@@ -72,6 +84,9 @@ pub enum Error {
     #[error("Game not found: {0}")]
     GameNotFound(String),
 
+    #[error("Engine not found: {0}")]
+    EngineNotFound(String),
+
     #[error("Game not in progress")]
     GameNotInProgress,
 
@@ -92,6 +107,36 @@ pub enum Error {
 
     #[error("Analysis cancelled")]
     AnalysisCancelled,
+
+    #[error("Search stopped")]
+    SearchStopped,
+
+    #[error("Invalid search pattern: {0}")]
+    InvalidPattern(String),
+
+    #[error("Invalid resource limits: {0}")]
+    InvalidResourceLimits(String),
+
+    #[error("Unsupported variant: {0}")]
+    UnsupportedVariant(String),
+
+    #[error("Engine protocol error: {0}")]
+    EngineProtocol(String),
+
+    #[error("Could not connect to engine at {0}")]
+    EngineConnection(String),
+
+    #[error("Invalid engine options: {}", crate::engine::format_option_errors(.0))]
+    InvalidEngineOptions(Vec<crate::engine::EngineOptionError>),
+
+    #[error("Invalid EPD: {0}")]
+    InvalidEpd(String),
+
+    #[error("Invalid benchmark: {0}")]
+    InvalidBenchmark(String),
+
+    #[error("Invalid query over several databases: {0}")]
+    InvalidMultiQuery(String),
 }
 
 impl From<std::io::Error> for Error {
@@ -130,12 +175,36 @@ impl From<reqwest::Error> for Error {
     }
 }
 
+impl From<csv::Error> for Error {
+    fn from(value: csv::Error) -> Self {
+        Self::Csv(Box::new(value))
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(value: serde_json::Error) -> Self {
+        Self::Json(Box::new(value))
+    }
+}
+
+impl From<regex::Error> for Error {
+    fn from(value: regex::Error) -> Self {
+        Self::Regex(Box::new(value))
+    }
+}
+
 impl From<shakmaty::PositionError<Chess>> for Error {
     fn from(value: shakmaty::PositionError<Chess>) -> Self {
         Self::ChessPosition(Box::new(value))
     }
 }
 
+impl From<shakmaty::PositionError<VariantPosition>> for Error {
+    fn from(value: shakmaty::PositionError<VariantPosition>) -> Self {
+        Self::VariantPosition(Box::new(value))
+    }
+}
+
 impl From<shakmaty::uci::IllegalUciMoveError> for Error {
     fn from(value: shakmaty::uci::IllegalUciMoveError) -> Self {
         Self::IllegalUciMove(Box::new(value))
@@ -0,0 +1,350 @@
+use serde::Serialize;
+use shakmaty::{attacks, Bitboard, Board, ByColor, Chess, Color, File, Position, Role, Square};
+use specta::Type;
+
+use crate::{chess::piece_value, engine::parse_fen_and_apply_moves, error::Error};
+
+/// Bonus per square a piece can move to, by role.
+fn mobility_weight(role: Role) -> i32 {
+    match role {
+        Role::Knight => 4,
+        Role::Bishop => 5,
+        Role::Rook => 3,
+        Role::Queen => 1,
+        _ => 0,
+    }
+}
+
+const SHIELD_PAWN: i32 = 12;
+const OPEN_FILE_NEAR_KING: i32 = -20;
+const ISOLATED_PAWN: i32 = -15;
+const DOUBLED_PAWN: i32 = -15;
+const BACKWARD_PAWN: i32 = -10;
+/// Bonus for a passed pawn, by rank from its own side.
+const PASSED_PAWN: [i32; 8] = [0, 5, 10, 20, 35, 60, 100, 0];
+const CENTRALIZED_MINOR: i32 = 10;
+const ROOK_OPEN_FILE: i32 = 20;
+const ROOK_SEMI_OPEN_FILE: i32 = 10;
+const ROOK_SEVENTH_RANK: i32 = 20;
+const SPACE_SQUARE: i32 = 2;
+const BISHOP_PAIR: i32 = 30;
+
+#[derive(Serialize, Debug, Default, Clone, Type, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PawnStructure {
+    pub isolated: u32,
+    /// Pawns standing behind another pawn of the same color.
+    pub doubled: u32,
+    pub passed: u32,
+    pub backward: u32,
+}
+
+/// Evaluation terms of one side, in centipawns.
+#[derive(Serialize, Debug, Default, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct SideEvaluation {
+    pub material: i32,
+    pub mobility: i32,
+    pub king_safety: i32,
+    pub pawn_structure: i32,
+    pub piece_activity: i32,
+    pub space: i32,
+    pub bishop_pair: i32,
+    pub total: i32,
+    /// Squares the pieces can move to that aren't attacked by enemy pawns.
+    pub safe_moves: u32,
+    /// Pawns right in front of the king.
+    pub pawn_shield: u32,
+    /// Files next to the king without pawns of its color.
+    pub open_files_near_king: u32,
+    pub pawns: PawnStructure,
+}
+
+#[derive(Serialize, Debug, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct EvalBreakdown {
+    pub white: SideEvaluation,
+    pub black: SideEvaluation,
+    /// Difference of the totals from White's point of view.
+    pub total: i32,
+}
+
+/// Rank of the square counted from `color`'s side, 0 to 7.
+fn relative_rank(sq: Square, color: Color) -> i32 {
+    let rank = u32::from(sq.rank()) as i32;
+    match color {
+        Color::White => rank,
+        Color::Black => 7 - rank,
+    }
+}
+
+fn adjacent_files(file: File) -> Bitboard {
+    [file.offset(-1), file.offset(1)]
+        .into_iter()
+        .flatten()
+        .map(Bitboard::from_file)
+        .fold(Bitboard::EMPTY, |acc, bb| acc | bb)
+}
+
+fn pawn_attacks(board: &Board, color: Color) -> Bitboard {
+    (board.pawns() & board.by_color(color))
+        .into_iter()
+        .fold(Bitboard::EMPTY, |acc, sq| {
+            acc | attacks::pawn_attacks(color, sq)
+        })
+}
+
+/// Whether `sq` is ahead of `from` for `color`.
+fn ahead(sq: Square, from: Square, color: Color) -> bool {
+    relative_rank(sq, color) > relative_rank(from, color)
+}
+
+/// No enemy pawn ahead of it on its file or the adjacent ones.
+fn is_passed(board: &Board, sq: Square, color: Color) -> bool {
+    let theirs = board.pawns() & board.by_color(!color);
+    !(theirs & (Bitboard::from_file(sq.file()) | adjacent_files(sq.file())))
+        .into_iter()
+        .any(|enemy| ahead(enemy, sq, color))
+}
+
+fn pawn_structure(board: &Board, color: Color) -> PawnStructure {
+    let ours = board.pawns() & board.by_color(color);
+    let their_attacks = pawn_attacks(board, !color);
+
+    let mut counts = PawnStructure::default();
+    for sq in ours {
+        let file = Bitboard::from_file(sq.file());
+        let neighbours = adjacent_files(sq.file());
+        let isolated = (ours & neighbours).is_empty();
+        let passed = is_passed(board, sq, color);
+
+        if isolated {
+            counts.isolated += 1;
+        }
+        if (ours & file).into_iter().any(|own| ahead(own, sq, color)) {
+            counts.doubled += 1;
+        }
+        if passed {
+            counts.passed += 1;
+        }
+        // no pawn beside or behind can support it and it can't advance safely
+        let stop = sq.offset(if color == Color::White { 8 } else { -8 });
+        if !isolated
+            && !passed
+            && !(ours & neighbours)
+                .into_iter()
+                .any(|own| !ahead(own, sq, color))
+            && stop.is_some_and(|stop| their_attacks.contains(stop))
+        {
+            counts.backward += 1;
+        }
+    }
+    counts
+}
+
+fn pawn_structure_score(board: &Board, color: Color, counts: &PawnStructure) -> i32 {
+    let passed_bonus: i32 = (board.pawns() & board.by_color(color))
+        .into_iter()
+        .filter(|&sq| is_passed(board, sq, color))
+        .map(|sq| PASSED_PAWN[relative_rank(sq, color) as usize])
+        .sum();
+    counts.isolated as i32 * ISOLATED_PAWN
+        + counts.doubled as i32 * DOUBLED_PAWN
+        + counts.backward as i32 * BACKWARD_PAWN
+        + passed_bonus
+}
+
+/// Pawns on the two ranks in front of the king and files around it without
+/// our pawns.
+fn king_shelter(board: &Board, color: Color) -> (u32, u32) {
+    let Some(king) = board.king_of(color) else {
+        return (0, 0);
+    };
+    let ours = board.pawns() & board.by_color(color);
+    let files = Bitboard::from_file(king.file()) | adjacent_files(king.file());
+    let shield = (ours & files)
+        .into_iter()
+        .filter(|&sq| (1..=2).contains(&(relative_rank(sq, color) - relative_rank(king, color))))
+        .count() as u32;
+    let open = [
+        king.file().offset(-1),
+        Some(king.file()),
+        king.file().offset(1),
+    ]
+    .into_iter()
+    .flatten()
+    .filter(|&file| (ours & Bitboard::from_file(file)).is_empty())
+    .count() as u32;
+    (shield, open)
+}
+
+fn is_central(sq: Square) -> bool {
+    (2..=5).contains(&u32::from(sq.file())) && (2..=5).contains(&u32::from(sq.rank()))
+}
+
+fn piece_activity(board: &Board, color: Color) -> i32 {
+    let ours = board.by_color(color);
+    let minors = (board.knights() | board.bishops()) & ours;
+    let centralized = minors.into_iter().filter(|&sq| is_central(sq)).count() as i32;
+
+    let rooks: i32 = (board.rooks() & ours)
+        .into_iter()
+        .map(|sq| {
+            let file = Bitboard::from_file(sq.file());
+            let file_bonus = if (board.pawns() & file).is_empty() {
+                ROOK_OPEN_FILE
+            } else if (board.pawns() & ours & file).is_empty() {
+                ROOK_SEMI_OPEN_FILE
+            } else {
+                0
+            };
+            let seventh = if relative_rank(sq, color) == 6 {
+                ROOK_SEVENTH_RANK
+            } else {
+                0
+            };
+            file_bonus + seventh
+        })
+        .sum();
+    centralized * CENTRALIZED_MINOR + rooks
+}
+
+/// Squares in the opponent's half that we control and their pawns don't.
+fn space(board: &Board, color: Color) -> i32 {
+    let their_pawn_attacks = pawn_attacks(board, !color);
+    let controlled = board
+        .by_color(color)
+        .into_iter()
+        .fold(Bitboard::EMPTY, |acc, sq| acc | board.attacks_from(sq));
+    (controlled & !their_pawn_attacks & !board.by_color(color))
+        .into_iter()
+        .filter(|&sq| relative_rank(sq, color) >= 4)
+        .count() as i32
+        * SPACE_SQUARE
+}
+
+fn side_evaluation(board: &Board, color: Color) -> SideEvaluation {
+    let ours = board.by_color(color);
+    let unsafe_squares = pawn_attacks(board, !color) | ours;
+
+    let material = (ours & !board.kings())
+        .into_iter()
+        .filter_map(|sq| board.role_at(sq))
+        .map(piece_value)
+        .sum();
+
+    let mut safe_moves = 0;
+    let mut mobility = 0;
+    for sq in ours & !board.pawns() & !board.kings() {
+        let Some(role) = board.role_at(sq) else {
+            continue;
+        };
+        let moves = (board.attacks_from(sq) & !unsafe_squares).count() as i32;
+        safe_moves += moves as u32;
+        mobility += moves * mobility_weight(role);
+    }
+
+    let (pawn_shield, open_files_near_king) = king_shelter(board, color);
+    let king_safety =
+        pawn_shield as i32 * SHIELD_PAWN + open_files_near_king as i32 * OPEN_FILE_NEAR_KING;
+
+    let pawns = pawn_structure(board, color);
+    let pawn_structure = pawn_structure_score(board, color, &pawns);
+
+    let bishops = board.bishops() & ours;
+    let bishop_pair =
+        if (bishops & Bitboard::LIGHT_SQUARES).any() && (bishops & Bitboard::DARK_SQUARES).any() {
+            BISHOP_PAIR
+        } else {
+            0
+        };
+
+    let piece_activity = piece_activity(board, color);
+    let space = space(board, color);
+    SideEvaluation {
+        material,
+        mobility,
+        king_safety,
+        pawn_structure,
+        piece_activity,
+        space,
+        bishop_pair,
+        total: material
+            + mobility
+            + king_safety
+            + pawn_structure
+            + piece_activity
+            + space
+            + bishop_pair,
+        safe_moves,
+        pawn_shield,
+        open_files_near_king,
+        pawns,
+    }
+}
+
+pub fn evaluate(position: &Chess) -> EvalBreakdown {
+    let sides = ByColor::new_with(|color| side_evaluation(position.board(), color));
+    let total = sides.white.total - sides.black.total;
+    EvalBreakdown {
+        white: sides.white,
+        black: sides.black,
+        total,
+    }
+}
+
+/// Explains a position with static terms for each side.
+#[tauri::command]
+#[specta::specta]
+pub fn get_eval_breakdown(fen: String, moves: Vec<String>) -> Result<EvalBreakdown, Error> {
+    let position = parse_fen_and_apply_moves(&fen, &moves)?;
+    Ok(evaluate(&position))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(fen: &str) -> EvalBreakdown {
+        evaluate(&parse_fen_and_apply_moves(fen, &[]).unwrap())
+    }
+
+    #[test]
+    fn starting_position_is_balanced() {
+        let breakdown = eval("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+        assert_eq!(breakdown.total, 0);
+        assert_eq!(breakdown.white.material, 3920);
+        assert_eq!(breakdown.white.bishop_pair, BISHOP_PAIR);
+        assert_eq!(breakdown.white.pawn_shield, 3);
+        assert_eq!(breakdown.white.open_files_near_king, 0);
+        assert_eq!(breakdown.white.pawns, PawnStructure::default());
+    }
+
+    #[test]
+    fn pawn_structure_terms() {
+        // White: doubled and isolated c-pawns, isolated passed a-pawn
+        // Black: passed e-pawn and a backward d-pawn, as c4 controls d5
+        let breakdown = eval("4k3/8/3p4/2P1p3/P1P5/8/8/4K3 w - - 0 1");
+        assert_eq!(
+            breakdown.white.pawns,
+            PawnStructure {
+                isolated: 3,
+                doubled: 1,
+                passed: 1,
+                backward: 0,
+            }
+        );
+        assert_eq!(breakdown.black.pawns.backward, 1);
+        assert_eq!(breakdown.black.pawns.passed, 1);
+    }
+
+    #[test]
+    fn king_safety_counts_shield_and_open_files() {
+        let castled = eval("6k1/8/8/8/8/8/5PPP/6K1 w - - 0 1");
+        assert_eq!(castled.white.pawn_shield, 3);
+        assert_eq!(castled.white.open_files_near_king, 0);
+        assert_eq!(castled.black.pawn_shield, 0);
+        assert_eq!(castled.black.open_files_near_king, 3);
+        assert!(castled.white.king_safety > castled.black.king_safety);
+    }
+}
@@ -2,7 +2,7 @@ use std::{
     collections::HashMap,
     fs::File,
     io::{BufRead, BufReader, Cursor, Read, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::Arc,
     time::Instant,
 };
@@ -14,7 +14,8 @@ use polyglot_book_rs::PolyglotBook;
 use rand::{seq::IteratorRandom, Rng};
 use serde::{Deserialize, Serialize};
 use shakmaty::{
-    fen::Fen, san::SanPlus, uci::UciMove, CastlingMode, Chess, Color, EnPassantMode, Position,
+    fen::Fen, san::SanPlus, uci::UciMove, variant::VariantPosition, CastlingMode, Chess, Color,
+    EnPassantMode, Outcome, Position,
 };
 use specta::Type;
 use tauri::AppHandle;
@@ -25,8 +26,12 @@ use tokio::{
 };
 
 use crate::{
-    engine::{parse_fen_to_position, BaseEngine, EngineLog, EngineOption, GoMode, PlayersTime},
+    engine::{
+        parse_fen_to_position, parse_variant_fen, BaseEngine, EngineCrashed, EngineLog,
+        EngineLogFilter, EngineOption, EngineSpawnConfig, GoMode, PlayersTime,
+    },
     error::Error,
+    variant::GameVariant,
 };
 
 pub type GameId = String;
@@ -43,6 +48,8 @@ pub enum PlayerConfig {
         #[serde(default)]
         options: Vec<EngineOption>,
         go: Option<GoMode>,
+        #[serde(flatten)]
+        spawn: EngineSpawnConfig,
     },
 }
 
@@ -63,7 +70,10 @@ pub struct GameConfig {
     pub initial_fen: Option<String>,
     #[serde(default)]
     pub initial_moves: Vec<String>,
+    /// Opening books are only used for standard games.
     pub opening_book: Option<OpeningBookConfig>,
+    #[serde(default)]
+    pub variant: GameVariant,
 }
 
 #[derive(Clone, Debug, Deserialize, Type)]
@@ -97,6 +107,8 @@ pub enum GameResult {
 #[serde(rename_all = "camelCase")]
 pub enum GameEndReason {
     Checkmate,
+    /// A win by the rules of the variant, like an exploded king or a third check.
+    Variant,
     Timeout,
     Resignation,
     Abandonment,
@@ -110,6 +122,8 @@ pub enum DrawReason {
     ThreefoldRepetition,
     FiftyMoveRule,
     Agreement,
+    /// A draw by the rules of the variant, like both kings reaching the last rank.
+    Variant,
 }
 
 #[derive(Clone, Debug, Serialize, Type)]
@@ -137,6 +151,7 @@ pub struct GameState {
     pub black_time: Option<u64>,
     pub white_player: String,
     pub black_player: String,
+    pub variant: GameVariant,
 }
 
 #[derive(Clone, Debug, Serialize, Type, Event)]
@@ -178,7 +193,7 @@ struct GameController {
     config: GameConfig,
     initial_fen: String,
     moves: Vec<GameMove>,
-    position: Chess,
+    position: VariantPosition,
     position_history: HashMap<String, u32>,
     status: GameStatus,
     clock: Option<ClockState>,
@@ -193,11 +208,12 @@ struct GameController {
 
 impl GameController {
     fn new(game_id: GameId, config: GameConfig) -> Result<Self, Error> {
-        let initial_fen = config.initial_fen.clone().unwrap_or_else(|| {
-            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1".to_string()
-        });
+        let initial_fen = config
+            .initial_fen
+            .clone()
+            .unwrap_or_else(|| config.variant.start_fen().to_string());
 
-        let position = parse_fen_to_position(&initial_fen)?;
+        let position = parse_variant_fen(&initial_fen, config.variant)?;
 
         let clock = if config.white_time_control.is_some() || config.black_time_control.is_some() {
             Some(ClockState {
@@ -282,10 +298,11 @@ impl GameController {
             black_time,
             white_player,
             black_player,
+            variant: self.config.variant,
         }
     }
 
-    fn position_key(position: &Chess) -> String {
+    fn position_key(position: &VariantPosition) -> String {
         let fen = Fen::from_position(position.clone(), EnPassantMode::Legal).to_string();
         fen.split_whitespace().take(4).collect::<Vec<_>>().join(" ")
     }
@@ -399,7 +416,7 @@ impl GameController {
     }
 
     fn rebuild_position_from_moves(&mut self) -> Result<(), Error> {
-        self.position = parse_fen_to_position(&self.initial_fen)?;
+        self.position = parse_variant_fen(&self.initial_fen, self.config.variant)?;
 
         self.position_history.clear();
         let initial_key = Self::position_key(&self.position);
@@ -439,6 +456,26 @@ impl GameController {
     }
 
     fn check_game_end(&mut self) {
+        if let Some(outcome) = self.position.variant_outcome() {
+            let result = match outcome {
+                Outcome::Decisive {
+                    winner: Color::White,
+                } => GameResult::WhiteWins {
+                    reason: GameEndReason::Variant,
+                },
+                Outcome::Decisive {
+                    winner: Color::Black,
+                } => GameResult::BlackWins {
+                    reason: GameEndReason::Variant,
+                },
+                Outcome::Draw => GameResult::Draw {
+                    reason: DrawReason::Variant,
+                },
+            };
+            self.status = GameStatus::Finished { result };
+            return;
+        }
+
         if self.position.is_checkmate() {
             let result = if self.position.turn() == Color::White {
                 GameResult::BlackWins {
@@ -594,37 +631,15 @@ impl GameManager {
         controller.polyglot_book = polyglot_book;
         controller.polyglot_max_ply = polyglot_max_ply;
 
-        if let PlayerConfig::Engine { path, options, .. } = &config.white {
-            let mut engine = BaseEngine::spawn(PathBuf::from(path)).await?;
-            engine.init_uci().await?;
-            for opt in options {
-                if opt.name == "UCI_Chess960" {
-                    continue;
-                }
-                engine.set_option(&opt.name, &opt.value).await?;
-            }
-            if castling_mode.is_chess960() {
-                engine.set_option("UCI_Chess960", "true").await?;
-            } else {
-                engine.set_option("UCI_Chess960", "false").await?;
-            }
+        if let Some(engine) =
+            spawn_player_engine(&config.white, config.variant, castling_mode).await?
+        {
             controller.white_engine = Some(Arc::new(Mutex::new(engine)));
         }
 
-        if let PlayerConfig::Engine { path, options, .. } = &config.black {
-            let mut engine = BaseEngine::spawn(PathBuf::from(path)).await?;
-            engine.init_uci().await?;
-            for opt in options {
-                if opt.name == "UCI_Chess960" {
-                    continue;
-                }
-                engine.set_option(&opt.name, &opt.value).await?;
-            }
-            if castling_mode.is_chess960() {
-                engine.set_option("UCI_Chess960", "true").await?;
-            } else {
-                engine.set_option("UCI_Chess960", "false").await?;
-            }
+        if let Some(engine) =
+            spawn_player_engine(&config.black, config.variant, castling_mode).await?
+        {
             controller.black_engine = Some(Arc::new(Mutex::new(engine)));
         }
 
@@ -817,11 +832,11 @@ impl GameManager {
         Ok(())
     }
 
-    pub async fn get_engine_logs(
+    async fn player_engine(
         &self,
         game_id: &str,
         color: &str,
-    ) -> Result<Vec<EngineLog>, Error> {
+    ) -> Result<Option<Arc<Mutex<BaseEngine>>>, Error> {
         let game = self
             .games
             .get(game_id)
@@ -834,14 +849,37 @@ impl GameManager {
             "black" => &controller.black_engine,
             _ => return Err(Error::InvalidColor(color.to_string())),
         };
+        Ok(engine.clone())
+    }
 
-        if let Some(engine_arc) = engine {
+    pub async fn get_engine_logs(
+        &self,
+        game_id: &str,
+        color: &str,
+        filter: &EngineLogFilter,
+    ) -> Result<Vec<EngineLog>, Error> {
+        if let Some(engine_arc) = self.player_engine(game_id, color).await? {
             let engine = engine_arc.lock().await;
-            Ok(engine.get_logs())
+            engine.get_logs(filter)
         } else {
             Ok(Vec::new())
         }
     }
+
+    pub async fn export_engine_logs(
+        &self,
+        game_id: &str,
+        color: &str,
+        filter: &EngineLogFilter,
+        path: &Path,
+    ) -> Result<(), Error> {
+        let engine_arc = self
+            .player_engine(game_id, color)
+            .await?
+            .ok_or_else(|| Error::EngineNotFound(color.to_string()))?;
+        let engine = engine_arc.lock().await;
+        engine.export_logs(path, filter)
+    }
 }
 
 impl Default for GameManager {
@@ -1068,7 +1106,11 @@ fn choose_weighted_index(weights: &[u16], rng: &mut impl Rng) -> usize {
 }
 
 fn apply_opening_book(config: GameConfig) -> Result<OpeningBookResult, Error> {
-    let Some(opening_book) = &config.opening_book else {
+    let opening_book = config
+        .opening_book
+        .as_ref()
+        .filter(|_| config.variant.is_standard());
+    let Some(opening_book) = opening_book else {
         return Ok(OpeningBookResult {
             config,
             polyglot_book: None,
@@ -1171,6 +1213,36 @@ fn apply_opening_book(config: GameConfig) -> Result<OpeningBookResult, Error> {
     }
 }
 
+async fn spawn_player_engine(
+    player: &PlayerConfig,
+    variant: GameVariant,
+    castling_mode: CastlingMode,
+) -> Result<Option<BaseEngine>, Error> {
+    let PlayerConfig::Engine {
+        path,
+        options,
+        spawn,
+        ..
+    } = player
+    else {
+        return Ok(None);
+    };
+    spawn.limits.validate(options)?;
+    let mut engine = BaseEngine::start(PathBuf::from(path), spawn).await?;
+    let options: Vec<_> = options
+        .iter()
+        .filter(|opt| opt.name != "UCI_Chess960" && opt.name != "UCI_Variant")
+        .cloned()
+        .collect();
+    engine.check_options(&options)?;
+    for opt in &options {
+        engine.set_option(&opt.name, &opt.value).await?;
+    }
+    engine.set_variant(variant).await?;
+    engine.set_chess960(castling_mode.is_chess960()).await?;
+    Ok(Some(engine))
+}
+
 fn spawn_engine_task(
     game_id: &GameId,
     controller: &Arc<RwLock<GameController>>,
@@ -1410,7 +1482,7 @@ async fn request_engine_move(
         }
     }
 
-    let (engine_arc, go_mode, initial_fen, moves, turn) = {
+    let (engine_arc, player_config, go_mode, initial_fen, moves, turn, variant) = {
         let ctrl = controller.read().await;
 
         if ctrl.status != GameStatus::Playing {
@@ -1429,8 +1501,8 @@ async fn request_engine_move(
             None => return Err(Error::EngineNotInitialized),
         };
 
-        let go = match player_config {
-            PlayerConfig::Engine { go, .. } => go,
+        let go = match &player_config {
+            PlayerConfig::Engine { go, .. } => go.clone(),
             _ => return Err(Error::NotEngineTurn),
         };
 
@@ -1458,14 +1530,59 @@ async fn request_engine_move(
             go.unwrap_or(GoMode::Depth(20))
         };
 
-        (engine, go_mode, initial_fen, moves, turn)
+        (
+            engine,
+            player_config,
+            go_mode,
+            initial_fen,
+            moves,
+            turn,
+            ctrl.config.variant,
+        )
     };
 
-    let best_move = {
+    let mut crashes = 0;
+    let best_move = loop {
+        let result = {
+            let mut engine = engine_arc.lock().await;
+            async {
+                engine.set_position(&initial_fen, &moves).await?;
+                engine.go(&go_mode).await?;
+                engine.wait_for_bestmove().await
+            }
+            .await
+        };
+        let e = match result {
+            Ok(best_move) => break best_move,
+            Err(e) => e,
+        };
+
+        // restart the engine and search again instead of forfeiting the game
         let mut engine = engine_arc.lock().await;
-        engine.set_position(&initial_fen, &moves).await?;
-        engine.go(&go_mode).await?;
-        engine.wait_for_bestmove().await?
+        if !matches!(e, Error::EngineDisconnected) && !engine.is_gone().await {
+            return Err(e);
+        }
+        let Some(crash) = engine.crash_report().await else {
+            return Err(e);
+        };
+        crashes += 1;
+        let event = EngineCrashed::new(
+            game_id.to_string(),
+            None,
+            engine.path().display().to_string(),
+            crash,
+            crashes,
+        );
+        let restarting = event.restarting;
+        event.emit(app)?;
+        if !restarting {
+            return Err(e);
+        }
+        error!("Engine crashed in game {}, restarting: {:?}", game_id, e);
+        let castling_mode = CastlingMode::detect(initial_fen.parse::<Fen>()?.as_setup());
+        *engine = spawn_player_engine(&player_config, variant, castling_mode)
+            .await?
+            .ok_or(Error::EngineNotInitialized)?;
     };
 
     let mut ctrl = controller.write().await;
@@ -1570,7 +1687,26 @@ pub async fn abort_game(
 pub async fn get_game_engine_logs(
     game_id: String,
     color: String,
+    filter: Option<EngineLogFilter>,
     state: tauri::State<'_, crate::AppState>,
 ) -> Result<Vec<EngineLog>, Error> {
-    state.game_manager.get_engine_logs(&game_id, &color).await
+    state
+        .game_manager
+        .get_engine_logs(&game_id, &color, &filter.unwrap_or_default())
+        .await
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn export_game_engine_logs(
+    game_id: String,
+    color: String,
+    filter: Option<EngineLogFilter>,
+    file: PathBuf,
+    state: tauri::State<'_, crate::AppState>,
+) -> Result<(), Error> {
+    state
+        .game_manager
+        .export_engine_logs(&game_id, &color, &filter.unwrap_or_default(), &file)
+        .await
 }
@@ -0,0 +1,526 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use pgn_reader::{BufferedReader, Nag, RawComment, RawHeader, SanPlus, Skip, Visitor};
+use serde::{Deserialize, Serialize};
+use shakmaty::{
+    fen::Fen, uci::UciMove, CastlingMode, Chess, EnPassantMode, FromSetup, Position, PositionError,
+};
+use specta::Type;
+use tokio::sync::Mutex;
+use vampirc_uci::uci::ScoreValue;
+
+use crate::{
+    chess::{BestMoves, EngineOptions, EngineProcess},
+    db::{load_game_tree, render_movetext, DecodedGame, DecodedGameNode},
+    engine::{EngineOption, EngineSpawnConfig, GoMode},
+    error::Error,
+    progress::update_progress,
+    AppState,
+};
+
+/// Builds a [`DecodedGame`] tree from PGN, keeping the headers so the game can
+/// be written back.
+#[derive(Default)]
+struct TreeBuilder {
+    headers: Vec<(String, String)>,
+    stack: Vec<Vec<DecodedGameNode>>,
+    outcome: Option<String>,
+}
+
+impl Visitor for TreeBuilder {
+    type Result = ParsedGame;
+
+    fn begin_game(&mut self) {
+        self.headers.clear();
+        self.stack = vec![Vec::new()];
+        self.outcome = None;
+    }
+
+    fn header(&mut self, key: &[u8], value: RawHeader<'_>) {
+        self.headers.push((
+            String::from_utf8_lossy(key).to_string(),
+            String::from_utf8_lossy(value.as_bytes()).to_string(),
+        ));
+    }
+
+    fn san(&mut self, san: SanPlus) {
+        if let Some(nodes) = self.stack.last_mut() {
+            nodes.push(DecodedGameNode::Move(san.to_string()));
+        }
+    }
+
+    fn nag(&mut self, nag: Nag) {
+        if let Some(nodes) = self.stack.last_mut() {
+            nodes.push(DecodedGameNode::Nag(nag.to_string()));
+        }
+    }
+
+    fn comment(&mut self, comment: RawComment<'_>) {
+        if let Some(nodes) = self.stack.last_mut() {
+            nodes.push(DecodedGameNode::Comment(
+                String::from_utf8_lossy(comment.as_bytes())
+                    .trim()
+                    .to_string(),
+            ));
+        }
+    }
+
+    fn begin_variation(&mut self) -> Skip {
+        self.stack.push(Vec::new());
+        Skip(false)
+    }
+
+    fn end_variation(&mut self) {
+        if self.stack.len() > 1 {
+            let nodes = self.stack.pop().unwrap_or_default();
+            if let Some(parent) = self.stack.last_mut() {
+                parent.push(DecodedGameNode::Variation(nodes));
+            }
+        }
+    }
+
+    fn outcome(&mut self, outcome: Option<shakmaty::Outcome>) {
+        self.outcome = outcome.map(|o| o.to_string());
+    }
+
+    fn end_game(&mut self) -> Self::Result {
+        let nodes = std::mem::take(&mut self.stack)
+            .into_iter()
+            .next()
+            .unwrap_or_default();
+        ParsedGame {
+            headers: std::mem::take(&mut self.headers),
+            tree: DecodedGame { nodes },
+            outcome: self.outcome.take(),
+        }
+    }
+}
+
+struct ParsedGame {
+    headers: Vec<(String, String)>,
+    tree: DecodedGame,
+    outcome: Option<String>,
+}
+
+impl ParsedGame {
+    fn initial_fen(&self) -> Result<Fen, Error> {
+        match self.headers.iter().find(|(k, _)| k == "FEN") {
+            Some((_, fen)) => Ok(Fen::from_ascii(fen.as_bytes())?),
+            None => Ok(Fen::default()),
+        }
+    }
+}
+
+fn parse_pgn_tree(pgn: &str) -> Result<ParsedGame, Error> {
+    let mut reader = BufferedReader::new(pgn.as_bytes());
+    let mut builder = TreeBuilder::default();
+    reader.read_game(&mut builder)?.ok_or(Error::NoMovesFound)
+}
+
+/// A move of the tree, identified by its path: the index of the node in the
+/// mainline, followed by the index inside each nested variation.
+struct TreeMove {
+    path: Vec<u32>,
+    san: String,
+    moves: Vec<String>,
+    position: Chess,
+}
+
+fn collect_moves(
+    nodes: &[DecodedGameNode],
+    pos: &Chess,
+    moves: &[String],
+    castling_mode: CastlingMode,
+    path: &mut Vec<u32>,
+    out: &mut Vec<TreeMove>,
+) -> Result<(), Error> {
+    let mut pos = pos.clone();
+    let mut moves = moves.to_vec();
+    let mut before_last_move: Option<(Chess, Vec<String>)> = None;
+
+    for (i, node) in nodes.iter().enumerate() {
+        path.push(i as u32);
+        match node {
+            DecodedGameNode::Move(san) => {
+                let san: SanPlus = san.parse()?;
+                let m = san.san.to_move(&pos)?;
+                before_last_move = Some((pos.clone(), moves.clone()));
+                moves.push(UciMove::from_move(&m, castling_mode).to_string());
+                pos.play_unchecked(&m);
+                out.push(TreeMove {
+                    path: path.clone(),
+                    san: san.to_string(),
+                    moves: moves.clone(),
+                    position: pos.clone(),
+                });
+            }
+            DecodedGameNode::Variation(children) => {
+                // a variation replaces the last move played in its parent line
+                let (parent_pos, parent_moves) = match &before_last_move {
+                    Some((p, m)) => (p, m.as_slice()),
+                    None => (&pos, moves.as_slice()),
+                };
+                collect_moves(children, parent_pos, parent_moves, castling_mode, path, out)?;
+            }
+            DecodedGameNode::Nag(_) | DecodedGameNode::Comment(_) => {}
+        }
+        path.pop();
+    }
+    Ok(())
+}
+
+/// Identifies a position regardless of move counters, so transpositions share
+/// the same key.
+fn position_key(pos: &Chess) -> String {
+    Fen::from_position(pos.clone(), EnPassantMode::Legal)
+        .to_string()
+        .split_whitespace()
+        .take(4)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn format_eval(best: &BestMoves) -> String {
+    match best.score.value {
+        ScoreValue::Cp(cp) => format!("{:.2}", f64::from(cp) / 100.0),
+        ScoreValue::Mate(m) => format!("#{}", m),
+    }
+}
+
+fn strip_eval(comment: &str) -> String {
+    let mut out = String::with_capacity(comment.len());
+    let mut rest = comment;
+    while let Some(start) = rest.find("[%eval") {
+        out.push_str(&rest[..start]);
+        match rest[start..].find(']') {
+            Some(end) => rest = &rest[start + end + 1..],
+            None => rest = "",
+        }
+    }
+    out.push_str(rest);
+    out.trim().to_string()
+}
+
+/// Adds `[%eval]` comments after every analyzed move, replacing existing ones.
+fn annotate_nodes(
+    nodes: &[DecodedGameNode],
+    evals: &HashMap<Vec<u32>, String>,
+    path: &mut Vec<u32>,
+) -> Vec<DecodedGameNode> {
+    let mut out = Vec::with_capacity(nodes.len());
+    let mut pending: Option<String> = None;
+
+    for (i, node) in nodes.iter().enumerate() {
+        path.push(i as u32);
+        match node {
+            DecodedGameNode::Nag(_) => out.push(node.clone()),
+            DecodedGameNode::Comment(comment) => {
+                let comment = strip_eval(comment);
+                let comment = match pending.take() {
+                    Some(eval) if comment.is_empty() => eval,
+                    Some(eval) => format!("{} {}", eval, comment),
+                    None => comment,
+                };
+                if !comment.is_empty() {
+                    out.push(DecodedGameNode::Comment(comment));
+                }
+            }
+            _ => {
+                if let Some(eval) = pending.take() {
+                    out.push(DecodedGameNode::Comment(eval));
+                }
+                match node {
+                    DecodedGameNode::Variation(children) => {
+                        out.push(DecodedGameNode::Variation(annotate_nodes(
+                            children, evals, path,
+                        )));
+                    }
+                    _ => {
+                        out.push(node.clone());
+                        pending = evals.get(path).map(|e| format!("[%eval {}]", e));
+                    }
+                }
+            }
+        }
+        path.pop();
+    }
+    if let Some(eval) = pending {
+        out.push(DecodedGameNode::Comment(eval));
+    }
+    out
+}
+
+#[derive(Deserialize, Debug, Clone, Type)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum GameTreeSource {
+    Pgn { pgn: String },
+    Database { file: PathBuf, game_id: i32 },
+}
+
+#[derive(Deserialize, Debug, Default, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct TreeAnalysisOptions {
+    /// Return the game with `[%eval]` comments on every analyzed move.
+    pub annotate_pgn: bool,
+    /// Trees are standard chess, other variants are rejected.
+    #[serde(flatten)]
+    pub spawn: EngineSpawnConfig,
+}
+
+#[derive(Serialize, Debug, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct TreeNodeAnalysis {
+    /// Index of the move in the mainline, followed by the index inside each
+    /// nested variation. The root position has an empty path.
+    pub path: Vec<u32>,
+    pub san: Option<String>,
+    pub fen: String,
+    pub best: Vec<BestMoves>,
+    /// Whether this position was already analyzed through a transposition.
+    pub transposition: bool,
+}
+
+#[derive(Serialize, Debug, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct GameTreeAnalysis {
+    pub nodes: Vec<TreeNodeAnalysis>,
+    pub pgn: Option<String>,
+}
+
+#[tauri::command]
+#[specta::specta]
+#[allow(clippy::too_many_arguments)]
+pub async fn analyze_game_tree(
+    id: String,
+    engine: String,
+    go_mode: GoMode,
+    source: GameTreeSource,
+    options: TreeAnalysisOptions,
+    uci_options: Vec<EngineOption>,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<GameTreeAnalysis, Error> {
+    let (tree, fen, headers, outcome) = match source {
+        GameTreeSource::Pgn { pgn } => {
+            let parsed = parse_pgn_tree(&pgn)?;
+            let fen = parsed.initial_fen()?;
+            (parsed.tree, fen, Some(parsed.headers), parsed.outcome)
+        }
+        GameTreeSource::Database { file, game_id } => {
+            let (tree, fen) = load_game_tree(&state, &file, game_id)?;
+            (tree, fen, None, None)
+        }
+    };
+
+    let setup = fen.as_setup().clone();
+    let castling_mode = CastlingMode::detect(&setup);
+    let root =
+        Chess::from_setup(setup, castling_mode).or_else(PositionError::ignore_too_much_material)?;
+
+    let mut tree_moves = vec![TreeMove {
+        path: Vec::new(),
+        san: String::new(),
+        moves: Vec::new(),
+        position: root.clone(),
+    }];
+    collect_moves(
+        &tree.nodes,
+        &root,
+        &[],
+        castling_mode,
+        &mut Vec::new(),
+        &mut tree_moves,
+    )?;
+
+    // analyze each position only once, at its first occurrence
+    let keys: Vec<String> = tree_moves
+        .iter()
+        .map(|m| position_key(&m.position))
+        .collect();
+    let mut first_seen: HashMap<&str, usize> = HashMap::new();
+    for (i, key) in keys.iter().enumerate() {
+        first_seen.entry(key.as_str()).or_insert(i);
+    }
+    let unique: Vec<usize> = (0..tree_moves.len())
+        .filter(|&i| first_seen[keys[i].as_str()] == i && !tree_moves[i].position.is_game_over())
+        .collect();
+
+    if !options.spawn.variant.is_standard() {
+        return Err(Error::UnsupportedVariant(
+            options.spawn.variant.pgn_name().to_string(),
+        ));
+    }
+    options.spawn.limits.validate(&uci_options)?;
+
+    let cancel_flag = Arc::new(AtomicBool::new(false));
+    state
+        .analysis_cancel_flags
+        .insert(id.clone(), cancel_flag.clone());
+
+    // the flag is removed however the search ends
+    let results = async {
+        let (proc, reader) = EngineProcess::new(PathBuf::from(&engine), &options.spawn).await?;
+        let process = Mutex::new(proc);
+        process.lock().await.attach_reader(reader);
+
+        let root_fen = Fen::from_position(root, EnPassantMode::Legal).to_string();
+        let mut results: HashMap<usize, Vec<BestMoves>> = HashMap::new();
+        let searched = async {
+            for (n, &i) in unique.iter().enumerate() {
+                if cancel_flag.load(Ordering::SeqCst) {
+                    return Err(Error::AnalysisCancelled);
+                }
+
+                update_progress(
+                    &state.progress_state,
+                    &app,
+                    id.clone(),
+                    (n as f32 / unique.len() as f32) * 100.0,
+                    false,
+                )?;
+
+                {
+                    let mut proc = process.lock().await;
+                    proc.set_options(EngineOptions {
+                        fen: root_fen.clone(),
+                        moves: tree_moves[i].moves.clone(),
+                        extra_options: uci_options.clone(),
+                        spawn: options.spawn.clone(),
+                    })
+                    .await?;
+                    proc.go(&go_mode).await?;
+                }
+                results.insert(i, EngineProcess::search_until_bestmove(&process).await?);
+            }
+            Ok(())
+        }
+        .await;
+        process.lock().await.kill().await?;
+        searched?;
+        Ok::<_, Error>(results)
+    }
+    .await;
+    state.analysis_cancel_flags.remove(&id);
+    let results = results?;
+
+    let nodes: Vec<TreeNodeAnalysis> = tree_moves
+        .iter()
+        .enumerate()
+        .map(|(i, m)| {
+            let first = first_seen[keys[i].as_str()];
+            TreeNodeAnalysis {
+                path: m.path.clone(),
+                san: (!m.path.is_empty()).then(|| m.san.clone()),
+                fen: Fen::from_position(m.position.clone(), EnPassantMode::Legal).to_string(),
+                best: results.get(&first).cloned().unwrap_or_default(),
+                transposition: first != i,
+            }
+        })
+        .collect();
+
+    let pgn = options.annotate_pgn.then(|| {
+        let evals: HashMap<Vec<u32>, String> = nodes
+            .iter()
+            .filter(|n| !n.path.is_empty())
+            .filter_map(|n| n.best.first().map(|b| (n.path.clone(), format_eval(b))))
+            .collect();
+        let annotated = DecodedGame {
+            nodes: annotate_nodes(&tree.nodes, &evals, &mut Vec::new()),
+        };
+        let mut pgn = String::new();
+        for (key, value) in headers.iter().flatten() {
+            pgn.push_str(&format!("[{} \"{}\"]\n", key, value));
+        }
+        if !pgn.is_empty() {
+            pgn.push('\n');
+        }
+        pgn.push_str(&render_movetext(&annotated, &fen));
+        if let Some(outcome) = outcome.or_else(|| {
+            headers
+                .iter()
+                .flatten()
+                .find(|(k, _)| k == "Result")
+                .map(|(_, v)| v.clone())
+        }) {
+            pgn.push(' ');
+            pgn.push_str(&outcome);
+        }
+        pgn
+    });
+
+    update_progress(&state.progress_state, &app, id.clone(), 100.0, true)?;
+    Ok(GameTreeAnalysis { nodes, pgn })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree_moves(pgn: &str) -> Vec<TreeMove> {
+        let parsed = parse_pgn_tree(pgn).unwrap();
+        let mut out = Vec::new();
+        collect_moves(
+            &parsed.tree.nodes,
+            &Chess::default(),
+            &[],
+            CastlingMode::Standard,
+            &mut Vec::new(),
+            &mut out,
+        )
+        .unwrap();
+        out
+    }
+
+    #[test]
+    fn walks_variations() {
+        let moves = tree_moves("1. e4 e5 (1... c5 2. Nf3 (2. Nc3)) 2. Nf3 *");
+        let paths: Vec<_> = moves
+            .iter()
+            .map(|m| (m.path.clone(), m.san.clone()))
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                (vec![0], "e4".to_string()),
+                (vec![1], "e5".to_string()),
+                (vec![2, 0], "c5".to_string()),
+                (vec![2, 1], "Nf3".to_string()),
+                (vec![2, 2, 0], "Nc3".to_string()),
+                (vec![3], "Nf3".to_string()),
+            ]
+        );
+        assert_eq!(moves[4].moves, vec!["e2e4", "c7c5", "b1c3"]);
+    }
+
+    #[test]
+    fn transpositions_share_key() {
+        let moves = tree_moves("1. Nf3 Nf6 2. Nc3 (2. g3) 2... Nc6 (1. Nc3 Nc6 2. Nf3 Nf6) *");
+        let first = moves.iter().find(|m| m.san == "Nc6").unwrap();
+        let last = moves.last().unwrap();
+        assert_eq!(position_key(&first.position), position_key(&last.position));
+    }
+
+    #[test]
+    fn annotates_after_nags_and_merges_comments() {
+        let parsed = parse_pgn_tree("1. e4 $1 {[%eval 0.1] best} e5 (1... c5) *").unwrap();
+        let evals = HashMap::from([
+            (vec![0], "0.30".to_string()),
+            (vec![3], "0.25".to_string()),
+            (vec![4, 0], "0.35".to_string()),
+        ]);
+        let annotated = DecodedGame {
+            nodes: annotate_nodes(&parsed.tree.nodes, &evals, &mut Vec::new()),
+        };
+        assert_eq!(
+            render_movetext(&annotated, &Fen::default()),
+            "1. e4! {[%eval 0.30] best} e5 {[%eval 0.25]} (1... c5 {[%eval 0.35]})"
+        );
+    }
+}
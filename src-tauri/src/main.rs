@@ -3,28 +3,36 @@
     windows_subsystem = "windows"
 )]
 
+mod benchmark;
 mod chess;
+mod critical;
 mod db;
 mod engine;
+mod epd;
 mod error;
+mod eval;
 mod game;
+mod game_tree;
 
 mod fs;
 mod lexer;
+mod motifs;
 mod oauth;
 mod opening;
 mod pgn;
 mod progress;
 mod puzzle;
 mod sound;
+mod variant;
 
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
-use chess::{BestMovesPayload, EngineProcess};
+use chess::{BestMovesPayload, EngineConfig, EngineProcess};
 use dashmap::DashMap;
-use db::{DatabaseProgress, GameQuery, NormalizedGame, PositionStats};
+use db::{DatabaseProgress, GameQuery, NormalizedGame, PositionStats, SearchResults};
 use derivative::Derivative;
+use engine::{validate_resource_limits, EngineCommand, EngineCrashed};
 use game::GameManager;
 use progress::{clear_progress, get_progress, ProgressEvent, ProgressStore};
 
@@ -32,26 +40,33 @@ use log::LevelFilter;
 use oauth::AuthState;
 #[cfg(debug_assertions)]
 use specta_typescript::{BigIntExportBehavior, Typescript};
-use sysinfo::SystemExt;
 use tauri::{Manager, Window};
 use tauri_plugin_log::{Target, TargetKind};
 
+use crate::benchmark::{get_benchmarks, run_benchmark};
 use crate::chess::{
-    analyze_game, cancel_analysis, get_engine_config, get_engine_logs, kill_engine, kill_engines,
-    stop_engine,
+    analyze_game, cancel_analysis, export_engine_logs, get_engine_config, get_engine_logs,
+    get_threat, kill_engine, kill_engines, stop_engine, validate_engine_options,
 };
+use crate::critical::find_critical_moments;
 use crate::db::{
-    clear_games, convert_pgn, create_indexes, delete_database, delete_db_game, delete_empty_games,
-    delete_indexes, export_to_pgn, get_player, get_players_game_info, get_tournaments,
-    preload_reference_db, search_position, MmapSearchIndex,
+    build_opening_tree, cancel_search, clear_games, convert_pgn, create_indexes, delete_database,
+    delete_db_game, delete_empty_games, delete_indexes, export_to_pgn, get_opening_tree,
+    get_player, get_players_game_info, get_search_matches, get_search_totals, get_tournaments,
+    preload_reference_db, search_position, search_position_multi, stream_search_position,
+    MmapPositionIndex, MmapSearchIndex,
 };
 use crate::game::{
-    abort_game, get_game_engine_logs, get_game_state, make_game_move, resign_game, start_game,
-    take_back_game_move, ClockUpdateEvent, GameMoveEvent, GameOverEvent,
+    abort_game, export_game_engine_logs, get_game_engine_logs, get_game_state, make_game_move,
+    resign_game, start_game, take_back_game_move, ClockUpdateEvent, GameMoveEvent, GameOverEvent,
 };
+use crate::game_tree::analyze_game_tree;
 
+use crate::epd::{export_epd_results, run_epd_suite};
+use crate::eval::get_eval_breakdown;
 use crate::fs::set_file_as_executable;
 use crate::lexer::lex_pgn;
+use crate::motifs::get_line_motifs;
 use crate::oauth::authenticate;
 use crate::pgn::{count_pgn_games, delete_game, read_games, write_game};
 use crate::puzzle::{
@@ -62,8 +77,8 @@ use crate::sound::get_sound_server_port;
 use crate::{
     chess::get_best_moves,
     db::{
-        delete_duplicated_games, edit_db_info, get_db_info, get_games, get_players, merge_players,
-        write_db_game,
+        delete_duplicated_games, edit_db_info, get_db_info, get_games, get_games_multi,
+        get_players, merge_players, write_db_game,
     },
     fs::{download_file, file_exists, get_file_metadata},
     opening::{
@@ -71,6 +86,7 @@ use crate::{
     },
 };
 use std::sync::atomic::AtomicBool;
+use std::time::SystemTime;
 use tokio::sync::Semaphore;
 
 #[derive(Derivative)]
@@ -81,14 +97,18 @@ pub struct AppState {
         diesel::r2d2::Pool<diesel::r2d2::ConnectionManager<diesel::SqliteConnection>>,
     >,
     line_cache: DashMap<(GameQuery, PathBuf), (Vec<PositionStats>, Vec<NormalizedGame>)>,
-    db_cache: Mutex<Option<MmapSearchIndex>>,
+    db_cache: DashMap<PathBuf, MmapSearchIndex>,
+    position_indexes: DashMap<PathBuf, MmapPositionIndex>,
     #[derivative(Default(value = "Arc::new(Semaphore::new(2))"))]
     new_request: Arc<Semaphore>,
     #[derivative(Default(value = "DashMap::new()"))]
     search_collisions: DashMap<(GameQuery, PathBuf), Arc<tokio::sync::Mutex<()>>>,
+    search_cancel_flags: DashMap<String, Vec<Arc<AtomicBool>>>,
+    search_results: SearchResults,
     pgn_offsets: DashMap<String, Vec<u64>>,
 
     engine_processes: DashMap<(String, String), Arc<tokio::sync::Mutex<EngineProcess>>>,
+    engine_configs: DashMap<PathBuf, (SystemTime, EngineCommand, EngineConfig)>,
     analysis_cancel_flags: DashMap<String, Arc<AtomicBool>>,
     auth: AuthState,
     game_manager: GameManager,
@@ -117,7 +137,18 @@ fn main() {
             kill_engine,
             kill_engines,
             get_engine_logs,
+            export_engine_logs,
+            get_threat,
+            analyze_game_tree,
+            get_line_motifs,
+            get_eval_breakdown,
+            find_critical_moments,
+            run_epd_suite,
+            run_benchmark,
+            get_benchmarks,
+            export_epd_results,
             memory_size,
+            validate_resource_limits,
             get_puzzle,
             search_opening_name,
             get_opening_from_fen,
@@ -125,6 +156,7 @@ fn main() {
             get_opening_from_name,
             get_players_game_info,
             get_engine_config,
+            validate_engine_options,
             file_exists,
             get_file_metadata,
             merge_players,
@@ -152,7 +184,15 @@ fn main() {
             get_tournaments,
             get_db_info,
             get_games,
+            get_games_multi,
             search_position,
+            search_position_multi,
+            stream_search_position,
+            cancel_search,
+            get_search_matches,
+            get_search_totals,
+            get_opening_tree,
+            build_opening_tree,
             get_players,
             get_puzzle_db_info,
             get_puzzle_themes,
@@ -165,6 +205,7 @@ fn main() {
             resign_game,
             abort_game,
             get_game_engine_logs,
+            export_game_engine_logs,
             preload_reference_db,
             get_progress,
             clear_progress,
@@ -176,7 +217,8 @@ fn main() {
             ProgressEvent,
             GameMoveEvent,
             ClockUpdateEvent,
-            GameOverEvent
+            GameOverEvent,
+            EngineCrashed
         ));
 
     #[cfg(debug_assertions)]
@@ -272,6 +314,5 @@ fn is_bmi2_compatible() -> bool {
 #[tauri::command]
 #[specta::specta]
 fn memory_size() -> u32 {
-    let total_bytes = sysinfo::System::new_all().total_memory();
-    (total_bytes / 1024 / 1024) as u32
+    engine::total_memory_mb()
 }
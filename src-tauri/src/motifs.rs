@@ -0,0 +1,380 @@
+use serde::{Deserialize, Serialize};
+use shakmaty::{
+    attacks, fen::Fen, uci::UciMove, Bitboard, Board, CastlingMode, Chess, Color, FromSetup, Move,
+    Piece, Position, PositionError, Rank, Role, Square,
+};
+use specta::Type;
+
+use crate::{
+    chess::{count_material, naive_eval, piece_value, qsearch},
+    engine::null_move_position,
+    error::Error,
+};
+
+/// Value used for the king when comparing attacked pieces.
+const KING_VALUE: i32 = 10_000;
+
+/// How many plies of the engine line are followed to see if sacrificed
+/// material is won back.
+const SACRIFICE_HORIZON: usize = 10;
+
+/// Material (in `piece_value` units) that may still be missing for a
+/// sacrifice to count as won back.
+const REGAIN_MARGIN: i32 = 50;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Type, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub enum Motif {
+    Fork,
+    Pin,
+    Skewer,
+    DiscoveredAttack,
+    BackRankThreat,
+    HangingPiece,
+    RemovalOfDefender,
+    /// Material is given up and not won back within the engine line.
+    Sacrifice,
+    /// Material is given up but won back (or the line mates) soon after.
+    PseudoSacrifice,
+}
+
+fn value(role: Role) -> i32 {
+    match role {
+        Role::King => KING_VALUE,
+        role => piece_value(role),
+    }
+}
+
+fn is_slider(role: Role) -> bool {
+    matches!(role, Role::Bishop | Role::Rook | Role::Queen)
+}
+
+fn defended(board: &Board, sq: Square, by: Color) -> bool {
+    board.attacks_to(sq, by, board.occupied()).any()
+}
+
+/// Whether attacking the piece on `target` with a piece worth `attacker`
+/// is a real threat: kings, more valuable pieces and undefended pieces.
+fn is_threatened(board: &Board, target: Square, attacker: i32) -> bool {
+    board.role_at(target).is_some_and(|role| {
+        role == Role::King
+            || value(role) > attacker
+            || board
+                .color_at(target)
+                .is_some_and(|color| !defended(board, target, color))
+    })
+}
+
+fn fork(board: &Board, sq: Square, us: Color) -> bool {
+    let Some(piece) = board.piece_at(sq) else {
+        return false;
+    };
+    let attacker = value(piece.role);
+    // a forking piece that can simply be taken doesn't fork anything
+    if piece.role != Role::King && defended(board, sq, !us) && !defended(board, sq, us) {
+        return false;
+    }
+    let targets = board.attacks_from(sq) & board.by_color(!us);
+    targets
+        .into_iter()
+        .filter(|&target| is_threatened(board, target, attacker))
+        .count()
+        >= 2
+}
+
+/// Looks through the enemy pieces attacked by the slider on `sq` for a pin
+/// (less valuable piece in front) or a skewer (more valuable piece in front).
+fn pins_and_skewers(board: &Board, sq: Square, us: Color) -> (bool, bool) {
+    let Some(piece) = board.piece_at(sq).filter(|p| is_slider(p.role)) else {
+        return (false, false);
+    };
+    let occupied = board.occupied();
+    let direct = attacks::attacks(sq, piece, occupied);
+    let (mut pin, mut skewer) = (false, false);
+    for front in direct & board.by_color(!us) {
+        let xray = attacks::attacks(sq, piece, occupied.without(front))
+            & attacks::ray(sq, front)
+            & !direct
+            & board.by_color(!us);
+        for behind in xray {
+            let (Some(front_role), Some(behind_role)) =
+                (board.role_at(front), board.role_at(behind))
+            else {
+                continue;
+            };
+            if front_role != Role::King && value(behind_role) > value(front_role) {
+                pin = true;
+            } else if (front_role == Role::King || value(front_role) > value(behind_role))
+                && is_threatened(board, behind, value(piece.role))
+            {
+                skewer = true;
+            }
+        }
+    }
+    (pin, skewer)
+}
+
+/// A piece that didn't move now attacks something it didn't attack before.
+fn discovered_attack(before: &Board, after: &Board, moved_to: Square, us: Color) -> bool {
+    let sliders = after.by_color(us) & (after.bishops() | after.rooks() | after.queens());
+    sliders.into_iter().filter(|&sq| sq != moved_to).any(|sq| {
+        if before.piece_at(sq) != after.piece_at(sq) {
+            return false;
+        }
+        let attacker = after.role_at(sq).map_or(0, value);
+        let uncovered = after.attacks_from(sq) & !before.attacks_from(sq) & after.by_color(!us);
+        uncovered
+            .into_iter()
+            .any(|target| is_threatened(after, target, attacker))
+    })
+}
+
+fn back_rank(color: Color) -> Rank {
+    match color {
+        Color::White => Rank::First,
+        Color::Black => Rank::Eighth,
+    }
+}
+
+/// Whether the side to move mates with a rook or queen landing on `rank`.
+fn mates_on_rank(pos: &Chess, rank: Rank) -> bool {
+    pos.legal_moves().iter().any(|m| {
+        matches!(m.role(), Role::Rook | Role::Queen) && m.to().rank() == rank && {
+            let mut pos = pos.clone();
+            pos.play_unchecked(m);
+            pos.is_checkmate()
+        }
+    })
+}
+
+/// The opponent's king is stuck on its back rank and a rook or queen mates
+/// there now, or would if the opponent passed and couldn't before the move.
+fn back_rank_threat(before: &Chess, after: &Chess, us: Color) -> bool {
+    let board = after.board();
+    let Some(king) = board.king_of(!us) else {
+        return false;
+    };
+    let rank = back_rank(!us);
+    if king.rank() != rank {
+        return false;
+    }
+    let heavy = board.rooks() | board.queens();
+    if after.is_checkmate() {
+        return (after.checkers() & heavy)
+            .into_iter()
+            .any(|sq| sq.rank() == rank);
+    }
+    let Some(passed) = null_move_position(after) else {
+        return false;
+    };
+    mates_on_rank(&passed, rank) && !mates_on_rank(before, rank)
+}
+
+/// The captured piece was the last defender of another piece we attack.
+fn removal_of_defender(before: &Board, after: &Board, m: &Move, us: Color) -> bool {
+    let Some(captured) = m.capture().filter(|_| !m.is_en_passant()) else {
+        return false;
+    };
+    let defender = Piece {
+        color: !us,
+        role: captured,
+    };
+    let guarded = attacks::attacks(m.to(), defender, before.occupied())
+        & before.by_color(!us)
+        & !before.kings();
+    guarded.into_iter().any(|sq| {
+        defended(before, sq, !us)
+            && !defended(after, sq, !us)
+            && after.attacks_to(sq, us, after.occupied()).any()
+    })
+}
+
+/// Whether the material given up by the move is won back in the line.
+fn material_regained(before: &Chess, after: &Chess, pv: &[Move]) -> bool {
+    let start = count_material(before);
+    let mut pos = after.clone();
+    // our point of view: after our moves the opponent is to move
+    if -qsearch(&pos, -KING_VALUE, KING_VALUE) >= start - REGAIN_MARGIN {
+        return true;
+    }
+    for (i, m) in pv.iter().take(SACRIFICE_HORIZON).enumerate() {
+        pos.play_unchecked(m);
+        if i % 2 == 1 && -qsearch(&pos, -KING_VALUE, KING_VALUE) >= start - REGAIN_MARGIN {
+            return true;
+        }
+        if pos.is_checkmate() {
+            return i % 2 == 1;
+        }
+    }
+    false
+}
+
+/// Finds the motifs of playing `m` in `before`, using `pv` (the engine's
+/// line after the move, starting with the opponent's reply) to tell real
+/// sacrifices from material that is won back.
+pub fn detect_motifs(before: &Chess, m: &Move, pv: &[Move]) -> Vec<Motif> {
+    let us = before.turn();
+    let mut after = before.clone();
+    after.play_unchecked(m);
+    let (board_before, board_after) = (before.board(), after.board());
+
+    let mut motifs = Vec::new();
+    if !m.is_castle() {
+        let to = m.to();
+        if fork(board_after, to, us) {
+            motifs.push(Motif::Fork);
+        }
+        let (pin, skewer) = pins_and_skewers(board_after, to, us);
+        if pin {
+            motifs.push(Motif::Pin);
+        }
+        if skewer {
+            motifs.push(Motif::Skewer);
+        }
+        if discovered_attack(board_before, board_after, to, us) {
+            motifs.push(Motif::DiscoveredAttack);
+        }
+        if m.is_capture() && !m.is_en_passant() && !defended(board_before, to, !us) {
+            motifs.push(Motif::HangingPiece);
+        }
+        if removal_of_defender(board_before, board_after, m, us) {
+            motifs.push(Motif::RemovalOfDefender);
+        }
+    }
+    if back_rank_threat(before, &after, us) {
+        motifs.push(Motif::BackRankThreat);
+    }
+
+    if !after.is_game_over() && naive_eval(before) > -naive_eval(&after) + 100 {
+        if material_regained(before, &after, pv) {
+            motifs.push(Motif::PseudoSacrifice);
+        } else {
+            motifs.push(Motif::Sacrifice);
+        }
+    }
+    motifs
+}
+
+/// Parses UCI moves played one after the other from `position`, stopping at
+/// the first illegal one.
+pub fn parse_line(position: &Chess, moves: &[String]) -> Vec<Move> {
+    let mut pos = position.clone();
+    let mut line = Vec::new();
+    for uci in moves {
+        let Some(m) = UciMove::from_ascii(uci.as_bytes())
+            .ok()
+            .and_then(|uci| uci.to_move(&pos).ok())
+        else {
+            break;
+        };
+        pos.play_unchecked(&m);
+        line.push(m);
+    }
+    line
+}
+
+/// Motifs of the moves played by the side to move in a line, like a puzzle
+/// solution.
+#[tauri::command]
+#[specta::specta]
+pub fn get_line_motifs(fen: String, moves: Vec<String>) -> Result<Vec<Motif>, Error> {
+    let fen: Fen = fen.parse()?;
+    let castling_mode = CastlingMode::detect(fen.as_setup());
+    let mut pos = Chess::from_setup(fen.as_setup().clone(), castling_mode)
+        .or_else(PositionError::ignore_too_much_material)?;
+    let line = parse_line(&pos, &moves);
+
+    let mut motifs = Vec::new();
+    for (i, m) in line.iter().enumerate() {
+        if i % 2 == 0 {
+            motifs.extend(detect_motifs(&pos, m, &line[i + 1..]));
+        }
+        pos.play_unchecked(m);
+    }
+    motifs.sort();
+    motifs.dedup();
+    Ok(motifs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn motifs(fen: &str, uci: &str, pv: &[&str]) -> Vec<Motif> {
+        let fen: Fen = fen.parse().unwrap();
+        let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
+        let m = UciMove::from_ascii(uci.as_bytes())
+            .unwrap()
+            .to_move(&pos)
+            .unwrap();
+        let mut after = pos.clone();
+        after.play_unchecked(&m);
+        let pv: Vec<String> = pv.iter().map(|s| s.to_string()).collect();
+        detect_motifs(&pos, &m, &parse_line(&after, &pv))
+    }
+
+    #[test]
+    fn knight_fork() {
+        // Nc7+ forks king and rook
+        let found = motifs("r3k3/8/8/3N4/8/8/8/4K3 w - - 0 1", "d5c7", &[]);
+        assert!(found.contains(&Motif::Fork));
+    }
+
+    #[test]
+    fn pin_and_skewer() {
+        let found = motifs("4k3/8/2n5/8/8/8/8/4KB2 w - - 0 1", "f1b5", &[]);
+        assert!(found.contains(&Motif::Pin));
+
+        // Ra6+ and the queen behind the king falls
+        let found = motifs("8/8/2k4q/8/8/8/8/R3K3 w - - 0 1", "a1a6", &[]);
+        assert!(found.contains(&Motif::Skewer));
+        assert!(!found.contains(&Motif::Pin));
+    }
+
+    #[test]
+    fn discovered_attack() {
+        // the bishop moves away and the rook attacks the queen
+        let found = motifs("4q1k1/8/8/8/8/4B3/8/4R1K1 w - - 0 1", "e3c5", &[]);
+        assert!(found.contains(&Motif::DiscoveredAttack));
+    }
+
+    #[test]
+    fn back_rank() {
+        let found = motifs("6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1", "a1a8", &[]);
+        assert!(found.contains(&Motif::BackRankThreat));
+
+        // moving the bishop away threatens Rd8#
+        let found = motifs("6k1/5ppp/8/8/8/8/3B1PPP/3R2K1 w - - 0 1", "d2e3", &[]);
+        assert!(found.contains(&Motif::BackRankThreat));
+
+        // the threat was already there
+        let found = motifs("6k1/5ppp/8/8/8/8/5PPP/3R2K1 w - - 0 1", "g1f1", &[]);
+        assert!(!found.contains(&Motif::BackRankThreat));
+    }
+
+    #[test]
+    fn hanging_piece_and_removal_of_defender() {
+        let found = motifs("4k3/8/8/3n4/8/8/8/3RK3 w - - 0 1", "d1d5", &[]);
+        assert!(found.contains(&Motif::HangingPiece));
+
+        // the knight on f6 is the only defender of the rook on d7
+        let found = motifs("6k1/3r4/5n2/8/8/8/5Q2/3R2K1 w - - 0 1", "f2f6", &[]);
+        assert!(found.contains(&Motif::RemovalOfDefender));
+    }
+
+    #[test]
+    fn real_and_pseudo_sacrifices() {
+        // Bxf7+ Kxf7 Ng5+ forks king and queen
+        let found = motifs(
+            "6k1/5ppp/4q3/7B/8/5N2/5PPP/6K1 w - - 0 1",
+            "h5f7",
+            &["g8f7", "f3g5", "f7g8", "g5e6"],
+        );
+        assert!(found.contains(&Motif::PseudoSacrifice));
+        assert!(!found.contains(&Motif::Sacrifice));
+
+        // the queen is simply lost
+        let found = motifs("4k3/8/8/2p5/8/8/8/3QK3 w - - 0 1", "d1d4", &["c5d4"]);
+        assert!(found.contains(&Motif::Sacrifice));
+    }
+}
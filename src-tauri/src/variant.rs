@@ -0,0 +1,159 @@
+use serde::{Deserialize, Serialize};
+use shakmaty::{
+    fen::Fen,
+    variant::{Variant, VariantPosition},
+    CastlingMode, EnPassantMode, PositionError, Setup,
+};
+use specta::Type;
+
+/// Rule set of a game. Chess960 and games from a position use the standard
+/// rules, with the castling mode detected from the FEN.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum GameVariant {
+    #[default]
+    Standard,
+    Crazyhouse,
+    Atomic,
+    Antichess,
+    KingOfTheHill,
+    ThreeCheck,
+    Horde,
+    RacingKings,
+}
+
+impl GameVariant {
+    /// Parses the value of a PGN `Variant` header, as exported by lichess.
+    pub fn from_pgn(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "" | "standard" | "chess" | "chess960" | "fischerandom" | "from position" => {
+                Some(GameVariant::Standard)
+            }
+            "crazyhouse" => Some(GameVariant::Crazyhouse),
+            "atomic" => Some(GameVariant::Atomic),
+            "antichess" | "giveaway" | "suicide" => Some(GameVariant::Antichess),
+            "king of the hill" | "kingofthehill" => Some(GameVariant::KingOfTheHill),
+            "three-check" | "threecheck" | "3check" => Some(GameVariant::ThreeCheck),
+            "horde" => Some(GameVariant::Horde),
+            "racing kings" | "racingkings" => Some(GameVariant::RacingKings),
+            _ => None,
+        }
+    }
+
+    pub fn pgn_name(self) -> &'static str {
+        match self {
+            GameVariant::Standard => "Standard",
+            GameVariant::Crazyhouse => "Crazyhouse",
+            GameVariant::Atomic => "Atomic",
+            GameVariant::Antichess => "Antichess",
+            GameVariant::KingOfTheHill => "King of the Hill",
+            GameVariant::ThreeCheck => "Three-check",
+            GameVariant::Horde => "Horde",
+            GameVariant::RacingKings => "Racing Kings",
+        }
+    }
+
+    /// Name stored in the database, standard games have none.
+    pub fn db_name(self) -> Option<&'static str> {
+        match self {
+            GameVariant::Standard => None,
+            variant => Some(variant.pgn_name()),
+        }
+    }
+
+    pub fn from_db_name(name: Option<&str>) -> Self {
+        name.and_then(Self::from_pgn).unwrap_or_default()
+    }
+
+    /// Values of `UCI_Variant` engines use for this variant, most common first.
+    pub fn uci_names(self) -> &'static [&'static str] {
+        match self {
+            GameVariant::Standard => &["chess"],
+            GameVariant::Crazyhouse => &["crazyhouse"],
+            GameVariant::Atomic => &["atomic"],
+            GameVariant::Antichess => &["antichess", "giveaway"],
+            GameVariant::KingOfTheHill => &["kingofthehill"],
+            GameVariant::ThreeCheck => &["3check"],
+            GameVariant::Horde => &["horde"],
+            GameVariant::RacingKings => &["racingkings"],
+        }
+    }
+
+    pub fn is_standard(self) -> bool {
+        self == GameVariant::Standard
+    }
+
+    /// Whether material and pawns on their home squares can only decrease,
+    /// which the position search relies on to skip games early. Crazyhouse
+    /// has drops and horde pawns can move up to the second rank.
+    pub fn has_monotonic_material(self) -> bool {
+        !matches!(self, GameVariant::Crazyhouse | GameVariant::Horde)
+    }
+
+    pub fn position(
+        self,
+        setup: Setup,
+        mode: CastlingMode,
+    ) -> Result<VariantPosition, PositionError<VariantPosition>> {
+        VariantPosition::from_setup(self.into(), setup, mode)
+    }
+
+    pub fn start_position(self) -> VariantPosition {
+        VariantPosition::new(self.into())
+    }
+
+    pub fn start_fen(self) -> Fen {
+        Fen::from_position(self.start_position(), EnPassantMode::Legal)
+    }
+}
+
+impl From<GameVariant> for Variant {
+    fn from(variant: GameVariant) -> Self {
+        match variant {
+            GameVariant::Standard => Variant::Chess,
+            GameVariant::Crazyhouse => Variant::Crazyhouse,
+            GameVariant::Atomic => Variant::Atomic,
+            GameVariant::Antichess => Variant::Antichess,
+            GameVariant::KingOfTheHill => Variant::KingOfTheHill,
+            GameVariant::ThreeCheck => Variant::ThreeCheck,
+            GameVariant::Horde => Variant::Horde,
+            GameVariant::RacingKings => Variant::RacingKings,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_lichess_headers() {
+        assert_eq!(
+            GameVariant::from_pgn("King of the Hill"),
+            Some(GameVariant::KingOfTheHill)
+        );
+        assert_eq!(
+            GameVariant::from_pgn("Three-check"),
+            Some(GameVariant::ThreeCheck)
+        );
+        assert_eq!(
+            GameVariant::from_pgn("Chess960"),
+            Some(GameVariant::Standard)
+        );
+        assert_eq!(GameVariant::from_pgn("Shogi"), None);
+        assert_eq!(
+            GameVariant::from_db_name(GameVariant::RacingKings.db_name()),
+            GameVariant::RacingKings
+        );
+        assert_eq!(GameVariant::from_db_name(None), GameVariant::Standard);
+    }
+
+    #[test]
+    fn variants_have_their_own_start_positions() {
+        assert_eq!(GameVariant::Standard.start_fen(), Fen::default());
+        assert_eq!(
+            GameVariant::RacingKings.start_fen().to_string(),
+            "8/8/8/8/8/8/krbnNBRK/qrbnNBRQ w - - 0 1"
+        );
+    }
+}